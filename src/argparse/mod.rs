@@ -67,19 +67,19 @@ impl ArgParse {
             // Formats and prints the argument with indentation padding
             let mut arg_str = String::from("  ");
             arg_str.push_str(&arg.name);
-            if arg.alternative.is_some() {
+            if let Some(alternative) = &arg.alternative {
                 arg_str.push_str(" (--");
-                arg_str.push_str(arg.alternative.as_ref().unwrap());
+                arg_str.push_str(alternative);
                 arg_str.push(')');
             }
-            if arg.default.is_some() {
+            if let Some(default) = &arg.default {
                 arg_str.push_str(" (default: ");
-                arg_str.push_str(arg.default.as_ref().unwrap());
+                arg_str.push_str(default);
                 arg_str.push(')');
             }
-            if arg.help.is_some() {
+            if let Some(help) = &arg.help {
                 arg_str.push_str("\n    ");
-                arg_str.push_str(arg.help.as_ref().unwrap());
+                arg_str.push_str(help);
             }
             // Print the argument text
             println!("{}", arg_str);
@@ -98,22 +98,19 @@ impl ArgParse {
                 // We are either 'out of sync' (which I don't handle) or this is a non-bound argument
                 self.non_bound.push(name.clone());
             }
-            match self.args.get_mut(&name) {
-                Some(arg) => {
-                    // It was found
-                    arg.found = true;
-                    if i + 1 < arguments.len() {
-                        // There is a value
-                        let next = arguments[i + 1].clone();
-                        if !next.starts_with('-') {
-                            arg.value = Some(next);
-
-                            // Skip the value
-                            i += 1
-                        }
+            if let Some(arg) = self.args.get_mut(&name) {
+                // It was found
+                arg.found = true;
+                if i + 1 < arguments.len() {
+                    // There is a value
+                    let next = arguments[i + 1].clone();
+                    if !next.starts_with('-') {
+                        arg.value = Some(next);
+
+                        // Skip the value
+                        i += 1
                     }
                 }
-                None => {}
             }
             i += 1;
         }
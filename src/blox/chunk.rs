@@ -6,8 +6,7 @@ use super::{
 #[derive(PartialEq, Debug, Clone)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub constants: ValueArray,
-    line_data: Vec<u8>, // Lets assume that a line can't have more than 255 bytes
+    line_data: Vec<(usize, usize)>, // Runs of bytes belonging to the same line, as (line, byte count)
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -19,11 +18,11 @@ fn simple_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
 
 #[cfg(not(tarpaulin_include))]
 // Prints the instruction and returns the offset to the next instruction.
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+fn constant_instruction(name: &str, chunk: &Chunk, constants: &ValueArray, offset: usize) -> usize {
     let slot = chunk.read_chunk(offset + 1);
     //print!("{}: {} {}, ", chunk.get_line(offset), name, constant_index);
     print!("{}: {}, slot {}, ", chunk.get_line(offset), name, slot);
-    chunk.get_value(slot as usize).print();
+    constants.get_value(slot as usize).print();
     println!();
     offset + 2
 }
@@ -36,6 +35,73 @@ fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
     offset + 2
 }
 
+#[cfg(not(tarpaulin_include))]
+// Prints the instruction and returns the offset to the next instruction.
+fn invoke_instruction(name: &str, chunk: &Chunk, constants: &ValueArray, offset: usize) -> usize {
+    let slot = chunk.read_chunk(offset + 1);
+    let arg_count = chunk.read_chunk(offset + 2);
+    print!(
+        "{}: {}, ({} args), slot {}, ",
+        chunk.get_line(offset),
+        name,
+        arg_count,
+        slot
+    );
+    constants.get_value(slot as usize).print();
+    println!();
+    offset + 3
+}
+
+#[cfg(not(tarpaulin_include))]
+// Prints the closure instruction with its captured upvalues and returns the offset to the next instruction.
+fn closure_instruction(name: &str, chunk: &Chunk, constants: &ValueArray, offset: usize) -> usize {
+    let slot = chunk.read_chunk(offset + 1);
+    let function = constants.get_value(slot as usize);
+    println!(
+        "{}: {}, slot {}, {}",
+        chunk.get_line(offset),
+        name,
+        slot,
+        function
+    );
+
+    let upvalue_count = match function {
+        Value::Function(function) => function.upvalue_count(),
+        _ => 0,
+    };
+
+    // Each upvalue is encoded as an (is_local, index) pair
+    let mut offset = offset + 2;
+    for _ in 0..upvalue_count {
+        let is_local = chunk.read_chunk(offset);
+        let index = chunk.read_chunk(offset + 1);
+        println!(
+            "{:04}      |   {} {}",
+            offset,
+            if is_local == 1 { "local" } else { "upvalue" },
+            index
+        );
+        offset += 2;
+    }
+    offset
+}
+
+#[cfg(not(tarpaulin_include))]
+// Prints the iterate instruction and returns the offset to the next instruction.
+fn iterate_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let slot = chunk.read_chunk(offset + 1);
+    let offset_jump: u16 =
+        ((chunk.read_chunk(offset + 2) as u16) << 8) | chunk.read_chunk(offset + 3) as u16;
+    println!(
+        "{}: {}, slot {}, {}",
+        chunk.get_line(offset),
+        name,
+        slot,
+        offset + 4 + offset_jump as usize
+    );
+    offset + 4
+}
+
 #[cfg(not(tarpaulin_include))]
 // Prints the instruction and returns the offset to the next instruction.
 fn jump_instruction(name: &str, positive: bool, chunk: &Chunk, offset: usize) -> usize {
@@ -58,7 +124,6 @@ impl Chunk {
         // TODO: Preallocate the code and line data arrays (?)
         Self {
             code: Vec::new(),
-            constants: ValueArray::new(),
             line_data: Vec::new(),
         }
     }
@@ -70,60 +135,57 @@ impl Chunk {
     // Adds byte to the chunk
     pub fn write_byte(&mut self, byte: u8, line: usize) {
         // RLE compression of line data
-        if self.line_data.len() == line + 1 {
-            self.line_data[line] += 1;
-        } else {
-            self.line_data.push(1);
+        match self.line_data.last_mut() {
+            Some((last_line, count)) if *last_line == line => *count += 1,
+            _ => self.line_data.push((line, 1)),
         }
         self.code.push(byte);
     }
 
+    // Removes the bytes from the given offset onwards
+    pub fn truncate(&mut self, offset: usize) {
+        let mut excess = self.code.len().saturating_sub(offset);
+        self.code.truncate(offset);
+        while excess > 0 {
+            let Some((_, count)) = self.line_data.last_mut() else {
+                break;
+            };
+            if *count > excess {
+                *count -= excess;
+                break;
+            }
+            excess -= *count;
+            self.line_data.pop();
+        }
+    }
+
     // https://www.csfieldguide.org.nz/en/chapters/coding-compression/run-length-encoding/
     pub fn get_line(&self, offset: usize) -> usize {
         let mut total: usize = 0;
-        for (line, length) in self.line_data.iter().enumerate() {
-            total += *length as usize;
+        for (line, length) in self.line_data.iter() {
+            total += *length;
             if total > offset {
-                return line;
+                return *line;
             }
         }
 
         unreachable!("Line should always be found");
     }
 
-    pub fn get_value(&self, index: usize) -> Value {
-        self.constants.get_value(index)
-    }
-
-    // Adds constant into our chunk and returns the index of the constant
-    pub fn add_constant(&mut self, value: Value) -> usize {
-        self.constants.add_value(value);
-
-        self.constants.len() - 1
-    }
-
-    pub fn patch_constant(&mut self, index: usize, value: Value) {
-        self.constants.set_value(index, value);
-    }
-
-    pub fn get_constant(&mut self, index: usize) -> Value {
-        self.constants.get_value(index)
-    }
-
-    // Disassembles the chunk
+    // Disassembles the chunk, the constants are those of the module the code belongs to
     #[cfg(not(tarpaulin_include))]
-    pub fn disassemble_chunk_from(&self, name: &str, start: usize) {
+    pub fn disassemble_chunk_from(&self, name: &str, start: usize, constants: &ValueArray) {
         println!("== {} ==", name);
 
         let mut offset = start;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction(offset, constants);
         }
     }
 
     // Disassembles the instruction at the given offset
     #[cfg(not(tarpaulin_include))]
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
+    pub fn disassemble_instruction(&self, offset: usize, constants: &ValueArray) -> usize {
         // Print out the instruction offset
         print!("{:04} ", offset);
 
@@ -141,26 +203,64 @@ impl Chunk {
             opcode::OP_SUBTRACT => simple_instruction(name, self, offset),
             opcode::OP_MULTIPLY => simple_instruction(name, self, offset),
             opcode::OP_DIVIDE => simple_instruction(name, self, offset),
+            opcode::OP_RANGE => simple_instruction(name, self, offset),
+            opcode::OP_RANGE_STEP => simple_instruction(name, self, offset),
+            opcode::OP_GET_ITERATOR => simple_instruction(name, self, offset),
+            opcode::OP_ITERATE => iterate_instruction(name, self, offset),
+            opcode::OP_YIELD => simple_instruction(name, self, offset),
+            opcode::OP_INT_DIVIDE => simple_instruction(name, self, offset),
+            opcode::OP_POWER => simple_instruction(name, self, offset),
+            opcode::OP_BIT_AND => simple_instruction(name, self, offset),
+            opcode::OP_BIT_OR => simple_instruction(name, self, offset),
+            opcode::OP_BIT_XOR => simple_instruction(name, self, offset),
+            opcode::OP_SHIFT_LEFT => simple_instruction(name, self, offset),
+            opcode::OP_SHIFT_RIGHT => simple_instruction(name, self, offset),
             opcode::OP_NOT => simple_instruction(name, self, offset),
             opcode::OP_NEGATE => simple_instruction(name, self, offset),
+            opcode::OP_BIT_NOT => simple_instruction(name, self, offset),
             opcode::OP_PRINT => simple_instruction(name, self, offset),
+            opcode::OP_SLEEP => simple_instruction(name, self, offset),
             opcode::OP_JUMP_BACK => jump_instruction(name, false, self, offset),
             opcode::OP_JUMP => jump_instruction(name, true, self, offset),
             opcode::OP_JUMP_IF_FALSE => jump_instruction(name, true, self, offset),
+            opcode::OP_JUMP_IF_NOT_NIL => jump_instruction(name, true, self, offset),
             opcode::OP_CALL => byte_instruction(name, self, offset),
+            opcode::OP_CLOSURE => closure_instruction(name, self, constants, offset),
+            opcode::OP_CLOSE_UPVALUE => simple_instruction(name, self, offset),
+            opcode::OP_INVOKE => invoke_instruction(name, self, constants, offset),
+            opcode::OP_SUPER_INVOKE => invoke_instruction(name, self, constants, offset),
+            opcode::OP_GET_SUPER => constant_instruction(name, self, constants, offset),
+            opcode::OP_INHERIT => simple_instruction(name, self, offset),
+            opcode::OP_BUILD_LIST => byte_instruction(name, self, offset),
+            opcode::OP_BUILD_MAP => byte_instruction(name, self, offset),
+            opcode::OP_GET_INDEX => simple_instruction(name, self, offset),
+            opcode::OP_SET_INDEX => simple_instruction(name, self, offset),
+            opcode::OP_INTERPOLATE => byte_instruction(name, self, offset),
+            opcode::OP_DUP | opcode::OP_BURY => byte_instruction(name, self, offset),
+            opcode::OP_TRY => jump_instruction(name, true, self, offset),
+            opcode::OP_END_TRY => simple_instruction(name, self, offset),
+            opcode::OP_THROW => simple_instruction(name, self, offset),
+            opcode::OP_IMPORT => constant_instruction(name, self, constants, offset),
+            opcode::OP_END_IMPORT => simple_instruction(name, self, offset),
+            opcode::OP_CLASS => constant_instruction(name, self, constants, offset),
+            opcode::OP_METHOD => constant_instruction(name, self, constants, offset),
+            opcode::OP_GET_PROPERTY => constant_instruction(name, self, constants, offset),
+            opcode::OP_SET_PROPERTY => constant_instruction(name, self, constants, offset),
             opcode::OP_RETURN => simple_instruction(name, self, offset),
-            opcode::OP_CONSTANT => constant_instruction(name, self, offset),
+            opcode::OP_CONSTANT => constant_instruction(name, self, constants, offset),
             opcode::OP_NIL => simple_instruction(name, self, offset),
             opcode::OP_TRUE => simple_instruction(name, self, offset),
             opcode::OP_FALSE => simple_instruction(name, self, offset),
             opcode::OP_POP => simple_instruction(name, self, offset),
             opcode::OP_GET_LOCAL => byte_instruction(name, self, offset),
             opcode::OP_SET_LOCAL => byte_instruction(name, self, offset),
-            opcode::OP_GET_GLOBAL => constant_instruction(name, self, offset),
-            opcode::OP_DEFINE_GLOBAL => constant_instruction(name, self, offset),
-            opcode::OP_SET_GLOBAL => constant_instruction(name, self, offset),
+            opcode::OP_GET_GLOBAL => constant_instruction(name, self, constants, offset),
+            opcode::OP_DEFINE_GLOBAL => constant_instruction(name, self, constants, offset),
+            opcode::OP_SET_GLOBAL => constant_instruction(name, self, constants, offset),
+            opcode::OP_GET_UPVALUE => byte_instruction(name, self, offset),
+            opcode::OP_SET_UPVALUE => byte_instruction(name, self, offset),
             _ => {
-                println!("Invalid opcode {}", self.code[offset] as u8);
+                println!("Invalid opcode {}", self.code[offset]);
                 offset + 1
             }
         }
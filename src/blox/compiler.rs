@@ -1,85 +1,75 @@
+use std::collections::HashSet;
+use std::path::Path;
 use std::rc::Rc;
 
 use super::lexer::Token;
 use super::opcode;
 use super::value::{
+    enumeration::Enum,
     function::{Function, FunctionType},
+    module::Module,
     Value,
 };
-use super::{chunk::Chunk, lexer::Lexer, lexer::TokenKind, locals::Locals, parser::Parser};
+use super::{
+    chunk::Chunk,
+    lexer::{is_min_integer_magnitude, parse_number, unescape, Lexer, Number, TokenKind},
+    locals::{Binding, Locals},
+    parser::Parser,
+    types::{ClassType, EnumType, Signature, Type, Typed},
+};
 
 pub struct Compiler {
     parser: Parser,
     lexer: Lexer,
-    locals: Locals,                                     // All locals
-    current_function: Function,                         // Active function being built
-    function_start_states: Vec<(Lexer, Parser, usize)>, // Lexer and parser state for all function declaration starts as well as the function constant index
+    locals: Locals,                // All locals
+    enclosing_locals: Vec<Locals>, // Locals of the enclosing functions, innermost last
+    current_function: Function,    // Active function being built
     function_type: FunctionType,
+    classes: Vec<ClassState>, // Class declarations we are currently inside of, innermost last
+    loops: Vec<LoopState>, // Loops of the current function we are currently inside of, innermost last
+    tries: Vec<TryState>, // Try statements of the current function we are currently inside of, innermost last
+    module: Rc<Module>,   // Module the compiled functions belong to
+    last_target: Option<(AssignTarget, usize)>, // The last variable, property or index read and its address
+    last_type: Typed,                           // The static type of the last compiled expression
+    signature: Signature,                       // The types of the function being compiled
+    returns: bool, // Whether the last statement always returns or throws, so code after it isn't reached
+    reassigned: HashSet<String>, // Names assigned anywhere in the source, globals with these names aren't inferred
+    function_doc: Option<Rc<str>>, // Doc comment of the variable being declared, for a function starting its initializer
     output: bool,
-
-    // VERY HACKY: If true the compiler will commit the changes to the chunk
-    // In order to keep functions in the same chunk and be able to declare functions anywhere I have to defer function compilation to the end of the script
-    // But since this is a single pass compiler I can't do this in the parser when the function declaration is first encountered,
-    //  and I need to use this commit flag to skip over function declarations and bodies and not commit them
-    // If I had more time I would have loved to convert this to a proper AST and use a visitor pattern to handle this
-    commit: bool,
+    file: Option<String>, // Path of the imported module being compiled, shown in errors
 }
 
 impl Compiler {
-    // Create a new compiler
-    pub fn new() -> Self {
+    // Create a new compiler for code in the given module
+    pub fn new(module: Rc<Module>) -> Self {
         let mut compiler = Self {
             parser: Parser::new(),
             lexer: Lexer::new(),
             locals: Locals::new(),
-            current_function: Function::new(),
-            function_start_states: Vec::new(),
+            enclosing_locals: Vec::new(),
+            current_function: Function::new(module.clone()),
             function_type: FunctionType::Script,
+            classes: Vec::new(),
+            loops: Vec::new(),
+            tries: Vec::new(),
+            module,
+            last_target: None,
+            last_type: Typed::any(),
+            signature: Signature::new(String::new()),
+            returns: false,
+            reassigned: HashSet::new(),
+            function_doc: None,
             output: false,
-            commit: true,
+            file: None,
         };
 
         compiler.locals.declare(String::from("")); // Reserve slot 0 for the vm
         compiler
     }
 
-    // Compiles all functions in the source
-    fn compile_functions(&mut self, chunk: &mut Chunk) {
-        // Iterate all the function start states (a snapshot taken at every function declaration)
-        while !self.function_start_states.is_empty() {
-            // Pop the last function start state
-            let function_start = self.function_start_states.pop().unwrap();
-
-            // Save the start address of this function
-            let start_address = chunk.code.len();
-
-            // Compile the function using the stored state
-            self.compile_function(chunk, (function_start.0, function_start.1));
-
-            // Look up the function constant index
-            let function_constant_index = function_start.2;
-            // Get the actual function through the constant index
-            let function = chunk.get_constant(function_constant_index as usize);
-
-            // The function constant was emitted on the original compiler pass, but we didn't know where its start address would be
-            // so replace the constant now that we know its actual start address
-            let mut new_function = Function::new();
-            if let Value::Function(f) = function {
-                // My rust skills are really showing here, I made an early choice to make it Rc
-                // so I can't just mutate it, I need to replace it
-
-                // A poor man's Rc clone :)
-                new_function.set_arity(f.arity());
-                new_function.set_name(f.name().to_string());
-                new_function.set_start_address(start_address);
-            }
-
-            // Patch it with the new function data (only really new start address)
-            chunk.patch_constant(
-                function_constant_index as usize,
-                Value::Function(Rc::from(new_function)),
-            );
-        }
+    // Sets the path of the imported module being compiled, errors name it
+    pub fn set_file(&mut self, file: &str) {
+        self.file = Some(file.to_string());
     }
 
     // Compile the given source code
@@ -94,6 +84,7 @@ impl Compiler {
 
         // Set the sourcecode for lexer
         self.lexer.set_source(source);
+        self.reassigned = self.find_assignments();
 
         // Start of current function, used to later set the address for the function object
         // this will change as the chunk grows through the REPL
@@ -114,9 +105,6 @@ impl Compiler {
         // Get the compiled function
         let function = self.end_compiler(chunk, start_address);
 
-        // Compile functions found in the script and append them to the end of the chunk
-        self.compile_functions(chunk);
-
         if output {
             // Print a newline after final disassembly output
             println!();
@@ -129,6 +117,49 @@ impl Compiler {
         }
     }
 
+    // Scans the whole source for the names of assigned variables and rewinds the lexer
+    // Functions can run after any of these assignments, so the type of a global is only inferred if it never changes
+    fn find_assignments(&mut self) -> HashSet<String> {
+        let state = self.lexer.state();
+        let mut names = HashSet::new();
+        let mut previous = Token::new(TokenKind::Eof);
+        let mut before = Token::new(TokenKind::Eof);
+        while let Ok(token) = self.lexer.scan_token() {
+            let target = match token.kind {
+                TokenKind::Equal
+                | TokenKind::PlusEqual
+                | TokenKind::MinusEqual
+                | TokenKind::StarEqual
+                | TokenKind::SlashEqual
+                | TokenKind::PercentEqual
+                | TokenKind::PlusPlus
+                | TokenKind::MinusMinus
+                    if previous.kind == TokenKind::Identifier
+                        && !matches!(
+                            before.kind,
+                            TokenKind::Var | TokenKind::Const | TokenKind::Dot
+                        ) =>
+                {
+                    Some(previous)
+                }
+                TokenKind::Identifier
+                    if matches!(previous.kind, TokenKind::PlusPlus | TokenKind::MinusMinus) =>
+                {
+                    Some(token)
+                }
+                TokenKind::Eof => break,
+                _ => None,
+            };
+            if let Some(target) = target {
+                names.insert(self.lexer.get_lexeme(&target).to_string());
+            }
+            before = previous;
+            previous = token;
+        }
+        self.lexer.restore(state);
+        names
+    }
+
     // Outputs error message at the previous token and sets the had_error flag
     fn error(&mut self, message: &str) {
         self.error_at(self.parser.previous, self.parser.previous.line, message);
@@ -146,7 +177,13 @@ impl Compiler {
         }
         self.parser.panic_mode = true;
         let lexeme = self.lexer.get_lexeme(&token);
-        println!("[line {}] Error: at '{}' {}", line, lexeme, message);
+        match &self.file {
+            Some(file) => println!(
+                "[{} line {}] Error: at '{}' {}",
+                file, line, lexeme, message
+            ),
+            None => println!("[line {}] Error: at '{}' {}", line, lexeme, message),
+        }
         self.parser.had_error = true;
     }
 
@@ -160,7 +197,8 @@ impl Compiler {
                     break;
                 }
                 Err(err) => {
-                    self.error_at(self.parser.current, err.line, err.message);
+                    let token = err.token.unwrap_or(self.parser.current);
+                    self.error_at(token, err.line, err.message);
                 }
             }
         }
@@ -175,6 +213,14 @@ impl Compiler {
         self.error_at_current(message);
     }
 
+    // Returns the kind of the token after the current one without consuming anything
+    fn peek_kind(&mut self) -> TokenKind {
+        match self.lexer.peek_token() {
+            Ok(token) => token.kind,
+            Err(_) => TokenKind::Eof,
+        }
+    }
+
     // Checks if the current token matches the given kind
     fn check(&mut self, kind: TokenKind) -> bool {
         self.parser.current.kind == kind
@@ -192,10 +238,6 @@ impl Compiler {
 
     // Writes a single byte into the chunk
     fn emit_byte(&mut self, chunk: &mut Chunk, byte: u8) {
-        if !self.commit {
-            return;
-        }
-
         let line_num = self.parser.previous.line;
         chunk.write_byte(byte, line_num);
     }
@@ -223,9 +265,6 @@ impl Compiler {
 
     // Writes a given jump instruction into the chunk
     fn emit_jump(&mut self, chunk: &mut Chunk, instruction: u8) -> usize {
-        if !self.commit {
-            return 0;
-        }
         self.emit_byte(chunk, instruction);
 
         // Encode offset into the 16-bit jump instruction
@@ -238,42 +277,41 @@ impl Compiler {
 
     // Writes nil and a return instruction into the chunk
     fn emit_return(&mut self, chunk: &mut Chunk) {
-        if !self.commit {
-            return;
-        }
-        // Default return value is nil
-        self.emit_byte(chunk, opcode::OP_NIL);
+        self.emit_default_return_value(chunk);
         self.emit_byte(chunk, opcode::OP_RETURN);
     }
 
-    // Adds a constant to the chunk and returns its index
-    fn make_constant(&mut self, chunk: &mut Chunk, value: Value) -> u8 {
-        if !self.commit {
-            return 0;
+    // Writes the value returned by a function without a return value
+    fn emit_default_return_value(&mut self, chunk: &mut Chunk) {
+        if self.function_type == FunctionType::Initializer {
+            // Initializers always return the instance, which lives in slot 0
+            self.emit_bytes(chunk, opcode::OP_GET_LOCAL, 0);
+        } else {
+            // Default return value is nil
+            self.emit_byte(chunk, opcode::OP_NIL);
         }
-        let constant_index = chunk.add_constant(value);
+    }
+
+    // Adds a constant to the module and returns its index
+    // Code shares one chunk, but every module has its own constants
+    fn make_constant(&mut self, value: Value) -> u8 {
+        let constant_index = self.module.add_constant(value);
 
         if constant_index > u8::MAX as usize {
-            self.error("Too many constants in one chunk.");
+            self.error("Too many constants in one module.");
         }
         constant_index as u8
     }
 
-    // Adds a constant to the chunk and writes it to the chunk code
+    // Adds a constant to the module and writes it to the chunk code
     fn emit_constant(&mut self, chunk: &mut Chunk, constant: Value) -> u8 {
-        if !self.commit {
-            return 0;
-        }
-        let constant_index = self.make_constant(chunk, constant);
+        let constant_index = self.make_constant(constant);
         self.emit_bytes(chunk, opcode::OP_CONSTANT, constant_index);
         constant_index
     }
 
     // Patches a jump instruction address to the current code position
     fn patch_jump(&mut self, chunk: &mut Chunk, offset: usize) {
-        if !self.commit {
-            return;
-        }
         let jump_offset = chunk.code.len() - offset - 2;
 
         if jump_offset > u16::MAX as usize {
@@ -299,7 +337,7 @@ impl Compiler {
 
             // Disassemble the chunk if we have code to disassemble
             if self.output && chunk.code.len() - start_address > 0 {
-                chunk.disassemble_chunk_from(chunk_name, start_address);
+                chunk.disassemble_chunk_from(chunk_name, start_address, &self.module.constants());
             }
 
             // Update the start address of the function (this is mainly used for the 'main' function aka global script function)
@@ -314,10 +352,14 @@ impl Compiler {
         self.locals.begin_scope();
     }
 
-    // Ends a scope. Pops all the locals used in the scope
+    // Ends a scope. Pops all the locals used in the scope, captured locals are closed instead
     fn end_scope(&mut self, chunk: &mut Chunk) {
-        for _ in 0..self.locals.end_scope() {
-            self.emit_byte(chunk, opcode::OP_POP);
+        for captured in self.locals.end_scope() {
+            if captured {
+                self.emit_byte(chunk, opcode::OP_CLOSE_UPVALUE);
+            } else {
+                self.emit_byte(chunk, opcode::OP_POP);
+            }
         }
     }
 
@@ -328,18 +370,71 @@ impl Compiler {
 
     // Parses and emits a string constant
     fn string(&mut self, chunk: &mut Chunk) {
+        self.last_type = Typed::literal(Type::String);
         let token = &self.parser.previous;
-        let lexeme = self.lexer.get_lexeme(token).to_string();
+        let lexeme = self.lexer.get_lexeme(token);
         // Remove the quotes
+        let text = unescape(&lexeme[1..lexeme.len() - 1]);
 
-        self.emit_constant(chunk, Value::String(Rc::from(&lexeme[1..lexeme.len() - 1])));
+        self.emit_constant(chunk, Value::String(Rc::from(text)));
     }
 
-    // Resolves a local variable in the current scope
-    fn resolve_local(&mut self, name: Token) -> Option<usize> {
-        let lexeme = self.lexer.get_lexeme(&name);
+    // Parses and compiles a raw string, only the delimiters are removed
+    fn raw_string(&mut self, chunk: &mut Chunk) {
+        self.last_type = Typed::literal(Type::String);
+        let token = &self.parser.previous;
+        let lexeme = self.lexer.get_lexeme(token);
+        let hashes = lexeme[1..].chars().take_while(|&c| c == '#').count();
+        let text = lexeme[hashes + 2..lexeme.len() - hashes - 1].to_string();
+
+        self.emit_constant(chunk, Value::String(Rc::from(text)));
+    }
+
+    // Parses and compiles an interpolated string
+    // The string parts and the embedded expressions are pushed in order and joined by OP_INTERPOLATE
+    fn interpolation(&mut self, chunk: &mut Chunk) {
+        let mut part_count = 0;
+
+        loop {
+            // Strip the opening quote or closing brace and the trailing '${'
+            let token = self.parser.previous;
+            let lexeme = self.lexer.get_lexeme(&token);
+            let text = unescape(&lexeme[1..lexeme.len() - 2]);
+            if !text.is_empty() {
+                self.emit_constant(chunk, Value::String(Rc::from(text)));
+                part_count += 1;
+            }
+
+            self.expression(chunk);
+            part_count += 1;
+
+            if !self.match_token(TokenKind::Interpolation) {
+                break;
+            }
+        }
+
+        if !self.match_token(TokenKind::String) {
+            self.error_at_current("Expect '}' after interpolated expression.");
+            return;
+        }
+        let token = self.parser.previous;
+        let lexeme = self.lexer.get_lexeme(&token);
+        let text = unescape(&lexeme[1..lexeme.len() - 1]);
+        if !text.is_empty() {
+            self.emit_constant(chunk, Value::String(Rc::from(text)));
+            part_count += 1;
+        }
 
-        match self.locals.index_of(lexeme) {
+        if part_count > u8::MAX as usize {
+            self.error("Can't have more than 255 parts in an interpolated string.");
+        }
+        self.emit_bytes(chunk, opcode::OP_INTERPOLATE, part_count as u8);
+        self.last_type = Typed::literal(Type::String);
+    }
+
+    // Resolves a local variable in the current scope
+    fn resolve_local(&mut self, name: &str) -> Option<usize> {
+        match self.locals.index_of(name) {
             Some((index, initialized)) => {
                 if !initialized {
                     self.error("Can't read local variable in its own initializer");
@@ -350,50 +445,347 @@ impl Compiler {
         }
     }
 
+    // Returns the locals of the function at the given level, where 0 is the outermost script
+    fn locals_at(&mut self, level: usize) -> &mut Locals {
+        if level == self.enclosing_locals.len() {
+            &mut self.locals
+        } else {
+            &mut self.enclosing_locals[level]
+        }
+    }
+
+    // Resolves a variable captured from an enclosing function of the function at the given level
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Option<usize> {
+        // The script has no enclosing function
+        if level == 0 {
+            return None;
+        }
+
+        // Look for a local in the directly enclosing function
+        if let Some((index, _)) = self.locals_at(level - 1).index_of(name) {
+            self.locals_at(level - 1).capture(index);
+            return Some(self.add_upvalue(level, index as u8, true));
+        }
+
+        // Otherwise it has to be captured by the enclosing function first
+        let upvalue = self.resolve_upvalue(level - 1, name)?;
+        Some(self.add_upvalue(level, upvalue as u8, false))
+    }
+
+    // Adds an upvalue to the function at the given level and returns its index
+    fn add_upvalue(&mut self, level: usize, index: u8, is_local: bool) -> usize {
+        let upvalue = self.locals_at(level).add_upvalue(index, is_local);
+        if upvalue > u8::MAX as usize {
+            self.error("Too many closure variables in function.");
+        }
+        upvalue
+    }
+
     // Parses and compiles a variable declaration or assignment
-    fn named_variable(&mut self, chunk: &mut Chunk, name: Token, can_assign: bool) {
+    fn named_variable(&mut self, chunk: &mut Chunk, name: &str, can_assign: bool) {
+        let level = self.enclosing_locals.len();
+
+        // Constants with a literal value are read straight from the constants of the module
+        let binding = self.resolve_binding(name);
+        let variable_type = self.variable_type(name);
+        if let Binding::Literal(index) = binding {
+            let target = AssignTarget::Constant(opcode::OP_CONSTANT, index);
+            if self
+                .assignment(chunk, target, can_assign, &variable_type)
+                .is_none()
+            {
+                self.read_target(chunk, target);
+                self.last_type = variable_type;
+            }
+            return;
+        }
+
         // See if we can find a local variable with this name
-        let (var_index, get_op, set_op) = match self.resolve_local(name) {
-            Some(local_index) => (
+        let (var_index, get_op, set_op) = if let Some(local_index) = self.resolve_local(name) {
+            (
                 local_index as u8,
                 opcode::OP_GET_LOCAL,
                 opcode::OP_SET_LOCAL,
-            ),
+            )
+        } else if let Some(upvalue_index) = self.resolve_upvalue(level, name) {
+            // Captured from an enclosing function
+            (
+                upvalue_index as u8,
+                opcode::OP_GET_UPVALUE,
+                opcode::OP_SET_UPVALUE,
+            )
+        } else {
             // Assume it's global
-            None => (
-                self.identifier_constant(chunk, name),
+            (
+                self.name_constant(name),
                 opcode::OP_GET_GLOBAL,
                 opcode::OP_SET_GLOBAL,
-            ),
+            )
         };
 
+        let target = if binding == Binding::Constant {
+            AssignTarget::Constant(get_op, var_index)
+        } else {
+            AssignTarget::Variable(get_op, set_op, var_index)
+        };
+        if let Some(value) = self.assignment(chunk, target, can_assign, &variable_type) {
+            self.assign_variable_type(name, &value);
+        } else {
+            // If not it's a variable access
+            self.read_target(chunk, target);
+            self.last_type = variable_type;
+        }
+    }
+
+    // Finds the innermost local variable with the given name and the level of the function it belongs to
+    fn find_local(&mut self, name: &str) -> Option<(usize, usize)> {
+        (0..=self.enclosing_locals.len())
+            .rev()
+            .find_map(|level| Some((level, self.locals_at(level).index_of(name)?.0)))
+    }
+
+    // Finds how the variable with the given name can be used, the innermost declaration decides
+    fn resolve_binding(&mut self, name: &str) -> Binding {
+        if let Some((level, index)) = self.find_local(name) {
+            return self.locals_at(level).binding(index);
+        }
+        match self.module.literal(name) {
+            Some(index) => Binding::Literal(index),
+            None if self.module.is_constant(name) => Binding::Constant,
+            None => Binding::Variable,
+        }
+    }
+
+    // Gets the static type of the variable with the given name
+    fn variable_type(&mut self, name: &str) -> Typed {
+        match self.find_local(name) {
+            Some((level, index)) => self.locals_at(level).local_type(index),
+            None => self.module.global_type(name).unwrap_or_else(Typed::any),
+        }
+    }
+
+    // Sets the static type of the variable that was just declared
+    fn declare_type(&mut self, name: &str, ty: Typed) {
+        if self.is_scoped() {
+            if let Some((index, _)) = self.locals.index_of(name) {
+                self.locals.set_type(index, ty);
+            }
+        } else {
+            let known = ty.ty != Type::Any;
+            self.module.set_global_type(name, known.then_some(ty));
+        }
+    }
+
+    // Checks a value assigned to a variable against its annotation
+    // Variables without one can hold anything, their inferred type is dropped if the value doesn't match it
+    fn assign_variable_type(&mut self, name: &str, value: &Typed) {
+        let variable = self.variable_type(name);
+        if variable.annotated {
+            self.check_type(&value.ty, &variable.ty, &format!("variable '{}'", name));
+        } else if variable.ty != value.ty {
+            match self.find_local(name) {
+                Some((level, index)) => self.locals_at(level).set_type(index, Typed::any()),
+                None => self.module.forget_global_type(name),
+            }
+        }
+    }
+
+    // Reports an error if the target is a constant
+    fn check_assignable(&mut self, target: AssignTarget) {
+        if let AssignTarget::Constant(..) = target {
+            self.error("Can't assign to a constant.");
+        }
+    }
+
+    // Compiles an assignment to the target if one follows and returns the type of the assigned value
+    // The parts of the target, like the instance of a property, are already on the stack
+    fn assignment(
+        &mut self,
+        chunk: &mut Chunk,
+        target: AssignTarget,
+        can_assign: bool,
+        target_type: &Typed,
+    ) -> Option<Typed> {
         if can_assign && self.match_token(TokenKind::Equal) {
-            // If we match with an equals sign, we know it's a variable assignment
+            self.check_assignable(target);
             self.expression(chunk);
-            self.emit_bytes(chunk, set_op, var_index);
+            self.emit_set(chunk, target);
+        } else if let Some(operator) = self.compound_operator(can_assign) {
+            // The target is evaluated once, its parts are duplicated to both read and write it
+            self.check_assignable(target);
+            self.emit_duplicate_target(chunk, target);
+            self.emit_get(chunk, target);
+            self.expression(chunk);
+            self.emit_byte(chunk, operator);
+            self.emit_set(chunk, target);
+            let value = self.last_type.clone();
+            self.last_type = self.operation_type(operator, target_type, &value);
+        } else if let Some(operator) = self.increment_operator() {
+            // Postfix increment, the old value is kept below the parts of the target as the result
+            self.check_assignable(target);
+            self.emit_duplicate_target(chunk, target);
+            self.emit_get(chunk, target);
+            let depth = target.depth();
+            if depth > 0 {
+                self.emit_bytes(chunk, opcode::OP_BURY, depth);
+            }
+            self.emit_bytes(chunk, opcode::OP_DUP, depth);
+            self.emit_constant(chunk, Value::Integer(1));
+            self.emit_byte(chunk, operator);
+            self.emit_set(chunk, target);
+            self.emit_byte(chunk, opcode::OP_POP);
+            self.last_type = self.operation_type(operator, target_type, &Typed::literal(Type::Int));
         } else {
-            // If not it's a variable access
-            self.emit_bytes(chunk, get_op, var_index);
+            return None;
+        }
+        Some(self.last_type.clone())
+    }
+
+    // Compiles a prefix increment or decrement
+    fn prefix_increment(&mut self, chunk: &mut Chunk) {
+        let operator = if self.parser.previous.kind == TokenKind::PlusPlus {
+            opcode::OP_ADD
+        } else {
+            opcode::OP_SUBTRACT
+        };
+
+        // The operand is compiled as a read, which is replaced if it is the last thing compiled
+        self.last_target = None;
+        self.parse_expression(chunk, Precedence::Call);
+        match self.last_target.take() {
+            Some((target, address)) if address + target.get_size() == chunk.code.len() => {
+                self.check_assignable(target);
+                chunk.truncate(address);
+                self.emit_duplicate_target(chunk, target);
+                self.emit_get(chunk, target);
+                self.emit_constant(chunk, Value::Integer(1));
+                self.emit_byte(chunk, operator);
+                self.emit_set(chunk, target);
+                let operand = self.last_type.clone();
+                self.last_type =
+                    self.operation_type(operator, &operand, &Typed::literal(Type::Int));
+            }
+            _ => self.error("Invalid increment target."),
+        }
+    }
+
+    // Matches a compound assignment operator and returns the opcode of its arithmetic
+    fn compound_operator(&mut self, can_assign: bool) -> Option<u8> {
+        if !can_assign {
+            return None;
+        }
+        let operator = match self.parser.current.kind {
+            TokenKind::PlusEqual => opcode::OP_ADD,
+            TokenKind::MinusEqual => opcode::OP_SUBTRACT,
+            TokenKind::StarEqual => opcode::OP_MULTIPLY,
+            TokenKind::SlashEqual => opcode::OP_DIVIDE,
+            TokenKind::PercentEqual => opcode::OP_MODULO,
+            _ => return None,
+        };
+        self.advance();
+        Some(operator)
+    }
+
+    // Matches a postfix increment or decrement and returns the opcode of its arithmetic
+    fn increment_operator(&mut self) -> Option<u8> {
+        if self.match_token(TokenKind::PlusPlus) {
+            Some(opcode::OP_ADD)
+        } else if self.match_token(TokenKind::MinusMinus) {
+            Some(opcode::OP_SUBTRACT)
+        } else {
+            None
+        }
+    }
+
+    // Emits a plain read of the target and remembers it, so a prefix increment can turn it into a write
+    fn read_target(&mut self, chunk: &mut Chunk, target: AssignTarget) {
+        self.last_target = Some((target, chunk.code.len()));
+        self.emit_get(chunk, target);
+    }
+
+    // Duplicates the parts of the target on the stack
+    fn emit_duplicate_target(&mut self, chunk: &mut Chunk, target: AssignTarget) {
+        let depth = target.depth();
+        for _ in 0..depth {
+            self.emit_bytes(chunk, opcode::OP_DUP, depth - 1);
+        }
+    }
+
+    // Emits the instruction reading the target
+    fn emit_get(&mut self, chunk: &mut Chunk, target: AssignTarget) {
+        match target {
+            AssignTarget::Variable(get_op, _, index) | AssignTarget::Constant(get_op, index) => {
+                self.emit_bytes(chunk, get_op, index)
+            }
+            AssignTarget::Property(name) => self.emit_bytes(chunk, opcode::OP_GET_PROPERTY, name),
+            AssignTarget::Index => self.emit_byte(chunk, opcode::OP_GET_INDEX),
+        }
+    }
+
+    // Emits the instruction writing the value on top of the stack to the target
+    fn emit_set(&mut self, chunk: &mut Chunk, target: AssignTarget) {
+        match target {
+            AssignTarget::Variable(_, set_op, index) => self.emit_bytes(chunk, set_op, index),
+            // Assigning to a constant was already reported by check_assignable
+            AssignTarget::Constant(..) => {}
+            AssignTarget::Property(name) => self.emit_bytes(chunk, opcode::OP_SET_PROPERTY, name),
+            AssignTarget::Index => self.emit_byte(chunk, opcode::OP_SET_INDEX),
         }
     }
 
     // Parses and compiles a variable declaration
     fn variable(&mut self, chunk: &mut Chunk, can_assign: bool) {
-        self.named_variable(chunk, self.parser.previous, can_assign);
+        let name = self.lexer.get_lexeme(&self.parser.previous).to_string();
+        self.named_variable(chunk, &name, can_assign);
     }
 
-    // Parses and compiles a number constant
+    // Parses and compiles a number constant, it is a float if it has a fraction
     fn number(&mut self, chunk: &mut Chunk) {
         let token = &self.parser.previous;
         let lexeme = self.lexer.get_lexeme(token);
-        let value = lexeme.parse::<Value>().expect("Failed to parse lexeme");
+        let value = match parse_number(lexeme) {
+            Ok(Number::Integer(n)) => {
+                self.last_type = Typed::literal(Type::Int);
+                Value::Integer(n)
+            }
+            Ok(Number::Float(n)) => {
+                self.last_type = Typed::literal(Type::Float);
+                Value::Number(n)
+            }
+            // Only the smallest integer gets past the lexer, and it isn't negated here
+            Err(message) => {
+                self.error(message);
+                return;
+            }
+        };
         self.emit_constant(chunk, value);
     }
 
+    // Compiles the smallest integer if the current token is its literal, the minus is already consumed
+    // Its digits alone are out of range, so it can't be compiled as a number that is negated
+    fn min_integer(&mut self, chunk: &mut Chunk) -> bool {
+        let literal = self.check(TokenKind::Number)
+            && is_min_integer_magnitude(self.lexer.get_lexeme(&self.parser.current))
+            && self.peek_kind() != TokenKind::StarStar;
+        if literal {
+            self.advance();
+            self.emit_constant(chunk, Value::Integer(i64::MIN));
+            self.last_type = Typed::literal(Type::Int);
+        }
+        literal
+    }
+
     // Parses and compiles a grouping expression
-    fn grouping(&mut self, chunk: &mut Chunk) {
+    fn grouping(&mut self, chunk: &mut Chunk, doc: Option<Rc<str>>) {
+        if self.is_arrow_function() {
+            self.arrow_function(chunk, doc);
+            return;
+        }
         self.expression(chunk);
         self.consume(TokenKind::RightParen, "Expect ')' after expression.");
+
+        // A grouping can't be incremented, even if it ends with a variable
+        self.last_target = None;
     }
 
     // Parses and compiles an expression
@@ -404,122 +796,641 @@ impl Compiler {
     // Parses and compiles a block (scope)
     fn block(&mut self, chunk: &mut Chunk) {
         // Iterate all declarations in the block and compile them
+        let mut returns = false;
         while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
             self.declaration(chunk);
+            returns |= self.returns;
         }
+        self.returns = returns;
         self.consume(TokenKind::RightBrace, "Expect '}' after block.");
     }
 
     // Parses and compiles a function
-    fn function(&mut self, chunk: &mut Chunk, function_type: FunctionType) -> Rc<Function> {
-        let old_function_type = self.function_type;
-        let old_function = self.current_function.clone();
-        self.function_type = function_type;
-        let start_addr = chunk.code.len();
-        let old_locals = self.locals.clone();
+    // The body is compiled in place and jumped over, the function itself is left on the stack as a closure
+    fn function(
+        &mut self,
+        chunk: &mut Chunk,
+        function_type: FunctionType,
+        name: String,
+        doc: Option<Rc<str>>,
+    ) {
+        let enclosing = self.begin_function(chunk, function_type, name);
+        self.current_function.set_doc(doc);
+
+        self.consume(TokenKind::LeftParen, "Expect '(' after function name.");
+        self.parameters(chunk);
+        self.consume(TokenKind::LeftBrace, "Expect '{' before function body.");
+
+        // Parse in the body
+        self.block(chunk);
+        self.check_missing_return();
+
+        self.end_function(chunk, enclosing);
+    }
+
+    // Parses and compiles an arrow function, the opening parenthesis is already consumed
+    // The body is either a block or a single expression whose value is returned
+    fn arrow_function(&mut self, chunk: &mut Chunk, doc: Option<Rc<str>>) {
+        let name = String::from("anonymous");
+        let enclosing = self.begin_function(chunk, FunctionType::Function, name);
+        self.current_function.set_doc(doc);
+
+        self.parameters(chunk);
+        self.consume(
+            TokenKind::Arrow,
+            "Expect '=>' after arrow function parameters.",
+        );
+
+        if self.match_token(TokenKind::LeftBrace) {
+            self.block(chunk);
+            self.check_missing_return();
+        } else {
+            self.expression(chunk);
+            self.check_return_type();
+            self.emit_byte(chunk, opcode::OP_RETURN);
+        }
+
+        self.end_function(chunk, enclosing);
+    }
+
+    // Starts compiling a new function into the chunk
+    // Returns the state of the enclosing function, which end_function restores
+    fn begin_function(
+        &mut self,
+        chunk: &mut Chunk,
+        function_type: FunctionType,
+        name: String,
+    ) -> EnclosingFunction {
+        // Skip over the function body when executing the enclosing code
+        let body_jump = self.emit_jump(chunk, opcode::OP_JUMP);
+
+        let enclosing = EnclosingFunction {
+            body_jump,
+            start_addr: chunk.code.len(),
+            function_type: std::mem::replace(&mut self.function_type, function_type),
+            function: std::mem::replace(
+                &mut self.current_function,
+                Function::new(self.module.clone()),
+            ),
+            // Loops outside of the function can't be broken out of from inside it
+            loops: std::mem::take(&mut self.loops),
+            tries: std::mem::take(&mut self.tries),
+            signature: std::mem::replace(&mut self.signature, Signature::new(name.clone())),
+        };
 
-        self.locals = Locals::new();
-        self.locals.declare(String::from("")); // Reserve slot 0 for the vm
+        let old_locals = std::mem::replace(&mut self.locals, Locals::new());
+        self.enclosing_locals.push(old_locals);
+        if function_type == FunctionType::Function {
+            self.locals.declare(String::from("")); // Reserve slot 0 for the vm
+        } else {
+            self.locals.declare(String::from("this")); // Methods keep the instance in slot 0
+            self.locals.define();
+        }
 
-        let function_name = self.lexer.get_lexeme(&self.parser.previous).to_string();
-        self.current_function.set_name(function_name);
+        self.current_function.set_name(name);
 
         self.begin_scope();
 
-        self.consume(TokenKind::LeftParen, "Expect '(' after function name.");
+        enclosing
+    }
 
+    // Parses the parameter list up to and including the closing parenthesis
+    fn parameters(&mut self, chunk: &mut Chunk) {
         // If we have parameters, add them
         while !self.check(TokenKind::RightParen) {
+            if self.match_token(TokenKind::DotDotDot) {
+                // The rest parameter gets a list of the extra arguments
+                let param_index = self.parse_variable("Expect parameter name");
+                self.define_variable(chunk, param_index);
+                self.current_function.set_variadic();
+                self.signature.variadic = true;
+                let name = self.lexer.get_lexeme(&self.parser.previous).to_string();
+                self.declare_type(&name, Typed::literal(Type::List));
+                if self.check(TokenKind::Colon) {
+                    self.error_at_current("Rest parameter can't have a type annotation.");
+                }
+                if !self.check(TokenKind::RightParen) {
+                    self.error_at_current("Rest parameter must be the last parameter.");
+                }
+                break;
+            }
+
             self.current_function.inc_arity();
             if self.current_function.arity() > 255 {
                 self.error_at_current("Can't have more than 255 parameters");
             }
-            let param_index = self.parse_variable(chunk, "Expect parameter name");
+            let param_index = self.parse_variable("Expect parameter name");
+            let name = self.lexer.get_lexeme(&self.parser.previous).to_string();
             self.define_variable(chunk, param_index);
-            if self.check(TokenKind::RightParen) {
+            let param_type = self.type_annotation();
+            self.signature.annotated |= param_type.annotated;
+            self.declare_type(&name, param_type.clone());
+
+            let mut signature_type = param_type.ty.clone();
+            if self.match_token(TokenKind::Equal) {
+                self.default_value(chunk);
+                let default = self.last_type.ty.clone();
+                self.check_type(&default, &param_type.ty, &format!("parameter '{}'", name));
+                // Nil can be passed to get the default value
+                signature_type = Type::either(&Type::Nil, &signature_type);
+            } else if self.current_function.optional() > 0 {
+                self.error(
+                    "Parameter without a default value can't follow one with a default value.",
+                );
+            }
+            self.signature.parameters.push(signature_type);
+
+            if !self.match_token(TokenKind::Comma) {
                 break;
             }
-            self.consume(TokenKind::Comma, "Expect ',' after parameter.");
         }
 
         self.consume(
             TokenKind::RightParen,
             "Expect ')' after function parameters.",
         );
-        self.consume(TokenKind::LeftBrace, "Expect '{' before function body.");
 
-        // Parse in the body
-        self.block(chunk);
+        if self.match_token(TokenKind::Colon) {
+            self.signature.result = self.parse_type();
+            self.signature.annotated = true;
+        }
+    }
 
-        self.end_scope(chunk);
+    // Parses the type annotation of a declared variable if there is one
+    fn type_annotation(&mut self) -> Typed {
+        if self.match_token(TokenKind::Colon) {
+            Typed::new(self.parse_type(), true)
+        } else {
+            Typed::any()
+        }
+    }
 
-        let function = self.end_compiler(chunk, start_addr);
+    // Parses a type name, optionally followed by '?' to allow nil
+    fn parse_type(&mut self) -> Type {
+        self.advance();
+        let token = self.parser.previous;
+        let name = self.lexer.get_lexeme(&token).to_string();
+        let ty = match token.kind {
+            TokenKind::Nil => Type::Nil,
+            TokenKind::Fun => Type::Function(None),
+            // Classes are types for their instances and enums for their variants
+            TokenKind::Identifier => match Type::from_name(&name) {
+                Some(ty) => ty,
+                None => match self.variable_type(&name).ty {
+                    Type::Class(class) => Type::Instance(class),
+                    Type::Enum(enumeration) => Type::Variant(enumeration),
+                    _ => {
+                        self.error(&format!("Unknown type '{}'.", name));
+                        Type::Any
+                    }
+                },
+            },
+            _ => {
+                self.error("Expect type name.");
+                Type::Any
+            }
+        };
+        if self.match_token(TokenKind::Question) {
+            Type::either(&Type::Nil, &ty)
+        } else {
+            ty
+        }
+    }
 
-        self.function_type = old_function_type;
-        self.current_function = old_function;
-        self.locals = old_locals;
+    // Checks the value just compiled against the declared return type of the function
+    fn check_return_type(&mut self) {
+        let value = self.last_type.ty.clone();
+        let result = self.signature.result.clone();
+        let what = format!("the return value of '{}'", self.signature.name);
+        self.check_type(&value, &result, &what);
+    }
 
-        function
+    // Reports a function body that can end without returning a value of the declared return type
+    // Falling off the end returns nil, which is fine if the function may return nil
+    fn check_missing_return(&mut self) {
+        let result = self.signature.result.clone();
+        if !self.returns && !self.current_function.generator() && !Type::Nil.fits(&result) {
+            self.error(&format!(
+                "Function '{}' can end without returning {}.",
+                self.signature.name, result
+            ));
+        }
     }
 
-    // Parses and compiles a function declaration
-    fn function_declaration(&mut self, chunk: &mut Chunk) {
-        // Get the name of the function
-        let global = self.parse_variable(chunk, "Expect function name.");
+    // Compiles the default value of the parameter just declared
+    // A missing argument is nil, so the default replaces a nil argument when the function starts
+    fn default_value(&mut self, chunk: &mut Chunk) {
+        self.current_function.inc_optional();
+        let slot = self.current_function.arity() as u8;
+
+        self.emit_bytes(chunk, opcode::OP_GET_LOCAL, slot);
+        let end_jump = self.emit_jump(chunk, opcode::OP_JUMP_IF_NOT_NIL);
+        self.emit_byte(chunk, opcode::OP_POP);
+        self.expression(chunk);
+        self.emit_bytes(chunk, opcode::OP_SET_LOCAL, slot);
+        self.patch_jump(chunk, end_jump);
+        self.emit_byte(chunk, opcode::OP_POP);
+    }
 
-        // Defer function compilation, so store lexer state
-        let lexer_state = self.lexer.clone();
-        let parser_state = self.parser.clone();
+    // Finishes the function started by begin_function and emits the closure for it
+    fn end_function(&mut self, chunk: &mut Chunk, enclosing: EnclosingFunction) {
+        self.end_scope(chunk);
 
-        // We don't want to commit the function code to the chunk yet, we just want to parse past it
-        self.commit = false;
-        let function = self.compile_function(chunk, (self.lexer.clone(), self.parser.clone()));
-        self.commit = true;
+        let upvalues = self.locals.upvalues().to_vec();
+        self.current_function.set_upvalue_count(upvalues.len());
 
-        // Emit the function constant immediately, don't defer this
-        // We need to be able to access it when executing
-        let constant = self.make_constant(chunk, Value::Function(function));
-        self.emit_bytes(chunk, opcode::OP_CONSTANT, constant);
+        let function = self.end_compiler(chunk, enclosing.start_addr);
 
-        // Store the lexer state, parser state and function constant so we can actually compile it at the end of compilation
-        self.function_start_states
-            .push((lexer_state, parser_state, constant as usize));
+        // Returns inside the function don't return from the code around it
+        self.returns = false;
+        let mut signature = std::mem::replace(&mut self.signature, enclosing.signature);
+        signature.required = function.arity() - function.optional();
+        if function.generator() {
+            // Calling a generator function creates the generator
+            signature.result = Type::Any;
+        }
 
-        // Define global variable for the function
-        self.define_variable(chunk, global);
+        self.function_type = enclosing.function_type;
+        self.current_function = enclosing.function;
+        self.loops = enclosing.loops;
+        self.tries = enclosing.tries;
+        self.locals = self
+            .enclosing_locals
+            .pop()
+            .expect("Enclosing locals should exist");
+
+        self.patch_jump(chunk, enclosing.body_jump);
+
+        // Wrap the function in a closure, capturing the upvalues it uses
+        let constant = self.make_constant(Value::Function(function));
+        self.emit_bytes(chunk, opcode::OP_CLOSURE, constant);
+        for upvalue in upvalues {
+            self.emit_bytes(chunk, upvalue.is_local as u8, upvalue.index);
+        }
+        self.last_type = Typed::literal(Type::Function(Some(Rc::new(signature))));
     }
 
-    // This does the actual function compilation at the end of the compilation process
-    fn compile_function(&mut self, chunk: &mut Chunk, state: (Lexer, Parser)) -> Rc<Function> {
-        // Set state to a state where the function name has been parsed and the global has been defined
-        self.lexer = state.0;
-        self.parser = state.1;
+    // Checks if the parenthesis just consumed starts the parameter list of an arrow function
+    // This needs to look past the closing parenthesis, so the lexer is rewound afterwards
+    fn is_arrow_function(&mut self) -> bool {
+        let state = self.lexer.state();
+        let mut kind = self.parser.current.kind;
+        let mut is_arrow = false;
 
-        // Define it, aka mark it as initialized
-        if self.is_scoped() {
-            self.mark_initialized();
+        // Parameters are identifiers separated by commas, with optional default values and a rest marker
+        loop {
+            if kind == TokenKind::DotDotDot {
+                kind = self.scan_kind();
+            }
+            if kind != TokenKind::Identifier {
+                break;
+            }
+            kind = self.scan_kind();
+            kind = self.skip_type(kind);
+            if kind == TokenKind::Equal {
+                kind = self.skip_default_value();
+            }
+            if kind != TokenKind::Comma {
+                break;
+            }
+            kind = self.scan_kind();
+            if kind != TokenKind::Identifier && kind != TokenKind::DotDotDot {
+                // A comma has to be followed by another parameter
+                kind = TokenKind::Eof;
+                break;
+            }
+        }
+        if kind == TokenKind::RightParen {
+            let kind = self.scan_kind();
+            is_arrow = self.skip_type(kind) == TokenKind::Arrow;
         }
 
-        self.function(chunk, FunctionType::Function)
-
-        // TODO: Restore lexer and parser state? Realistically it won't be used again since we are in a state of only compiling functions
+        self.lexer.restore(state);
+        is_arrow
     }
 
-    // Parses and compiles a variable declaration
-    fn var_declaration(&mut self, chunk: &mut Chunk) {
-        let global = self.parse_variable(chunk, "Expect variable name.");
-        if self.match_token(TokenKind::Equal) {
-            // Consume the expression
-            self.expression(chunk);
-        } else {
-            // If no explicit assignment is made, use the default value nil
-            self.emit_byte(chunk, opcode::OP_NIL);
+    // Scans past a type annotation if the given token kind starts one and returns the kind after it
+    fn skip_type(&mut self, kind: TokenKind) -> TokenKind {
+        if kind != TokenKind::Colon {
+            return kind;
         }
-        self.consume(
-            TokenKind::Semicolon,
-            "Expect ';' after variable declaration.",
-        );
-        self.define_variable(chunk, global);
+        self.scan_kind();
+        match self.scan_kind() {
+            TokenKind::Question => self.scan_kind(),
+            kind => kind,
+        }
+    }
+
+    // Scans past a default parameter value and returns the comma or parenthesis ending it
+    fn skip_default_value(&mut self) -> TokenKind {
+        let mut depth = 0;
+        loop {
+            let kind = self.scan_kind();
+            match kind {
+                TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::LeftBrace => depth += 1,
+                TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace
+                    if depth > 0 =>
+                {
+                    depth -= 1
+                }
+                TokenKind::Comma | TokenKind::RightParen if depth == 0 => return kind,
+                TokenKind::Eof => return kind,
+                _ => {}
+            }
+        }
+    }
+
+    // Scans the next token kind straight from the lexer, without updating the parser
+    fn scan_kind(&mut self) -> TokenKind {
+        self.scan_ahead().kind
+    }
+
+    // Scans the next token straight from the lexer, without updating the parser
+    // Tokens with errors are skipped, they are reported once the parser gets to them
+    fn scan_ahead(&mut self) -> Token {
+        loop {
+            if let Ok(token) = self.lexer.scan_token() {
+                return token;
+            }
+        }
+    }
+
+    // Parses and compiles a function declaration
+    fn function_declaration(&mut self, chunk: &mut Chunk) {
+        let doc = self.lexer.doc_comment(&self.parser.previous);
+
+        // Get the name of the function
+        let global = self.parse_variable("Expect function name.");
+
+        // Mark it as initialized right away so the function can refer to itself
+        self.mark_initialized();
+
+        let name = self.lexer.get_lexeme(&self.parser.previous).to_string();
+        self.document(&name, doc.clone());
+        self.function(chunk, FunctionType::Function, name.clone(), doc);
+        self.declare_type(&name, self.last_type.clone());
+
+        // Define global variable for the function
+        self.define_variable(chunk, global);
+    }
+
+    // Parses and compiles a method inside a class body
+    fn method(&mut self, chunk: &mut Chunk) {
+        self.consume(TokenKind::Identifier, "Expect method name.");
+        let constant = self.identifier_constant(self.parser.previous);
+        let name = self.lexer.get_lexeme(&self.parser.previous).to_string();
+        let doc = self.lexer.doc_comment(&self.parser.previous);
+
+        let function_type = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(chunk, function_type, name, doc);
+
+        // Attach the method to the class below it on the stack
+        self.emit_bytes(chunk, opcode::OP_METHOD, constant);
+    }
+
+    // Parses and compiles a class declaration
+    fn class_declaration(&mut self, chunk: &mut Chunk) {
+        self.consume(TokenKind::Identifier, "Expect class name.");
+        let class_token = self.parser.previous;
+        let class_name = self.lexer.get_lexeme(&class_token).to_string();
+        let name_constant = self.identifier_constant(class_token);
+        self.declare_variable();
+
+        self.emit_bytes(chunk, opcode::OP_CLASS, name_constant);
+        self.define_variable(chunk, name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        let mut class_type = ClassType {
+            name: class_name.clone(),
+            superclass: None,
+        };
+        if self.match_token(TokenKind::Less) {
+            self.consume(TokenKind::Identifier, "Expect superclass name.");
+            self.variable(chunk, false);
+            class_type.superclass = match &self.last_type.ty {
+                Type::Class(_) => Some(self.last_type.ty.clone()),
+                _ => Some(Type::Any),
+            };
+
+            if class_name == self.lexer.get_lexeme(&self.parser.previous) {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // The superclass is stored in a local called 'super' that methods capture
+            self.begin_scope();
+            self.add_local(String::from("super"));
+            self.define_variable(chunk, 0);
+
+            self.named_variable(chunk, &class_name, false);
+            self.emit_byte(chunk, opcode::OP_INHERIT);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // The class can be used as a type from here on, also in its own methods
+        self.declare_class_type(&class_name, class_type);
+
+        // Load the class back onto the stack so methods can be bound to it
+        self.named_variable(chunk, &class_name, false);
+
+        self.consume(TokenKind::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            self.method(chunk);
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after class body.");
+
+        // Pop the class
+        self.emit_byte(chunk, opcode::OP_POP);
+
+        // Pop the 'super' local
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope(chunk);
+        }
+    }
+
+    // Sets the type of a declared class, which is declared outside of the scope of its 'super' local
+    fn declare_class_type(&mut self, name: &str, class_type: ClassType) {
+        let ty = Typed::literal(Type::Class(Rc::new(class_type)));
+        match self.find_local(name) {
+            Some((level, index)) if level == self.enclosing_locals.len() => {
+                self.locals.set_type(index, ty)
+            }
+            _ => self.module.set_global_type(name, Some(ty)),
+        }
+    }
+
+    // Parses and compiles an enum declaration
+    // The enum and its variants are made while compiling and stored as a constant
+    fn enum_declaration(&mut self, chunk: &mut Chunk) {
+        let doc = self.lexer.doc_comment(&self.parser.previous);
+        let global = self.parse_variable("Expect enum name.");
+        let name = self.lexer.get_lexeme(&self.parser.previous).to_string();
+        self.document(&name, doc);
+
+        self.consume(TokenKind::LeftBrace, "Expect '{' before enum variants.");
+        let mut variants: Vec<String> = Vec::new();
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            self.consume(TokenKind::Identifier, "Expect variant name.");
+            let variant = self.lexer.get_lexeme(&self.parser.previous).to_string();
+            if variants.contains(&variant) {
+                self.error(&format!("Duplicate variant '{}' in enum.", variant));
+            }
+            variants.push(variant);
+            // Allow a trailing comma
+            if !self.match_token(TokenKind::Comma) {
+                break;
+            }
+        }
+        // A match on an enum without variants could never be exhaustive
+        if variants.is_empty() {
+            self.error_at_current("Expect at least one variant in enum.");
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after enum variants.");
+
+        let value = Value::Enum(Rc::new(Enum::new(&name, &variants)));
+        self.emit_constant(chunk, value);
+
+        // Enums can't be reassigned, their variants are checked while compiling
+        if self.is_scoped() {
+            self.locals.set_binding(Binding::Constant);
+        } else {
+            self.module.define_constant(Rc::from(name.as_str()), None);
+        }
+        let enum_type = EnumType {
+            name: name.clone(),
+            variants,
+        };
+        self.declare_type(&name, Typed::literal(Type::Enum(Rc::new(enum_type))));
+        self.define_variable(chunk, global);
+    }
+
+    // Keeps the doc comment of a declared global in the module, for tooling
+    // Locals can't be looked up from outside, so their documentation is only in the source
+    fn document(&mut self, name: &str, doc: Option<Rc<str>>) {
+        if !self.is_scoped() {
+            self.module.set_doc(name, doc);
+        }
+    }
+
+    // Parses and compiles a variable or constant declaration
+    fn var_declaration(&mut self, chunk: &mut Chunk, constant: bool) {
+        let doc = self.lexer.doc_comment(&self.parser.previous);
+        let global = self.parse_variable("Expect variable name.");
+        let name = self.lexer.get_lexeme(&self.parser.previous).to_string();
+        self.document(&name, doc.clone());
+        let annotation = self.type_annotation();
+        if constant {
+            self.consume(TokenKind::Equal, "Expect '=' after constant name.");
+            let start = chunk.code.len();
+            self.function_doc = doc;
+            self.expression(chunk);
+
+            let binding = match self.literal_constant(chunk, start) {
+                Some(index) => Binding::Literal(index),
+                None => Binding::Constant,
+            };
+            if self.is_scoped() {
+                self.locals.set_binding(binding);
+            } else if let Binding::Literal(index) = binding {
+                self.module
+                    .define_constant(Rc::from(name.as_str()), Some(index));
+            } else {
+                self.module.define_constant(Rc::from(name.as_str()), None);
+            }
+        } else if self.match_token(TokenKind::Equal) {
+            // Consume the expression
+            self.function_doc = doc;
+            self.expression(chunk);
+        } else {
+            // If no explicit assignment is made, use the default value nil
+            self.emit_byte(chunk, opcode::OP_NIL);
+            self.last_type = Typed::literal(Type::Nil);
+        }
+        self.consume(
+            TokenKind::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        // Annotated variables keep their type, others are inferred from the initializer
+        // Globals assigned anywhere else can hold another type by the time a function reads them
+        if annotation.annotated {
+            let value = self.last_type.ty.clone();
+            self.check_type(&value, &annotation.ty, &format!("variable '{}'", name));
+            self.declare_type(&name, annotation);
+        } else if constant
+            || self.is_scoped()
+            || !(self.module.is_untyped(&name) || self.reassigned.contains(&name))
+        {
+            let value = Typed::literal(self.last_type.ty.clone());
+            self.declare_type(&name, value);
+        } else {
+            self.declare_type(&name, Typed::any());
+        }
+        self.define_variable(chunk, global);
+    }
+
+    // Returns the constant index of the value compiled from start if it is a literal
+    fn literal_constant(&mut self, chunk: &mut Chunk, start: usize) -> Option<u8> {
+        let value = match chunk.code[start..] {
+            [opcode::OP_CONSTANT, index] => return Some(index),
+            [opcode::OP_NIL] => Value::Nil,
+            [opcode::OP_TRUE] => Value::Boolean(true),
+            [opcode::OP_FALSE] => Value::Boolean(false),
+            // Negative numbers are a negated literal
+            [opcode::OP_CONSTANT, index, opcode::OP_NEGATE] => {
+                match self.module.constant(index as usize) {
+                    Value::Integer(n) => Value::Integer(n.checked_neg()?),
+                    Value::Number(n) => Value::Number(-n),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        Some(self.make_constant(value))
+    }
+
+    // Parses and compiles an import declaration
+    // 'import name from "path";' binds the module to name, 'import "path";' names it after the file
+    fn import_declaration(&mut self, chunk: &mut Chunk) {
+        let mut name = None;
+        if self.match_token(TokenKind::Identifier) {
+            name = Some(self.lexer.get_lexeme(&self.parser.previous).to_string());
+            if !self.check(TokenKind::Identifier)
+                || self.lexer.get_lexeme(&self.parser.current) != "from"
+            {
+                self.error_at_current("Expect 'from' after module name.");
+                return;
+            }
+            self.advance();
+        }
+
+        self.consume(TokenKind::String, "Expect module path.");
+        let lexeme = self.lexer.get_lexeme(&self.parser.previous);
+        let path = unescape(&lexeme[1..lexeme.len() - 1]);
+
+        let name = name.unwrap_or_else(|| {
+            let stem = Path::new(&path).file_stem().and_then(|stem| stem.to_str());
+            match stem {
+                Some(stem) if self.lexer.is_identifier(stem) => stem.to_string(),
+                _ => {
+                    self.error("Module file name is not a valid name, use 'import name from'.");
+                    String::new()
+                }
+            }
+        });
+        self.consume(TokenKind::Semicolon, "Expect ';' after import.");
+
+        let global = self.declare_named_variable(&name);
+        let path = self.name_constant(&path);
+        self.emit_bytes(chunk, opcode::OP_IMPORT, path);
+        self.emit_byte(chunk, opcode::OP_END_IMPORT);
+        self.define_variable(chunk, global);
     }
 
     // Parses and compiles an expression statement
@@ -543,6 +1454,7 @@ impl Compiler {
 
         // Compile statement for if branch
         self.statement(chunk);
+        let then_returns = self.returns;
 
         // This is to jump over potential else branch after finishing execution of the then statement
         let else_jump = self.emit_jump(chunk, opcode::OP_JUMP);
@@ -557,21 +1469,208 @@ impl Compiler {
         if self.match_token(TokenKind::Else) {
             // Compile statement for else branch
             self.statement(chunk);
+            self.returns &= then_returns;
+        } else {
+            self.returns = false;
         }
         // Patch the jump to the end of the else statement
         self.patch_jump(chunk, else_jump);
     }
 
+    // Parses and compiles a match statement or expression
+    // The arms of a statement are statements, the arms of an expression are expressions separated by commas
+    //
+    //     <value>
+    //     OP_DUP 0               for every arm with a pattern
+    //     <pattern>
+    //     OP_EQUAL
+    //     OP_JUMP_IF_FALSE next
+    //     OP_POP
+    //     <guard>                only with a guard
+    //     OP_JUMP_IF_FALSE next
+    //     OP_POP
+    //     OP_POP                 the value isn't needed in the arm
+    //     <arm>
+    //     OP_JUMP end
+    //   next:
+    //     OP_POP
+    //     ...
+    //     OP_POP                 the default arm
+    //     <arm>
+    //   end:
+    fn match_(&mut self, chunk: &mut Chunk, statement: bool) {
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'match'.");
+        self.expression(chunk);
+        self.consume(TokenKind::RightParen, "Expect ')' after match value.");
+        self.consume(TokenKind::LeftBrace, "Expect '{' before match arms.");
+
+        // A match on a known enum doesn't need a default arm if it covers every variant
+        let subject = self.last_type.ty.clone();
+        let mut covered: Vec<String> = Vec::new();
+        let mut exhaustive = false;
+
+        let mut end_jumps = Vec::new();
+        let mut result_type: Option<Typed> = None;
+        let mut returns = statement;
+        loop {
+            if self.check(TokenKind::RightBrace) || self.check(TokenKind::Eof) {
+                match &subject {
+                    Type::Variant(enumeration) => {
+                        let missing: Vec<&str> = enumeration
+                            .variants
+                            .iter()
+                            .filter(|variant| !covered.contains(variant))
+                            .map(String::as_str)
+                            .collect();
+                        if missing.is_empty() {
+                            exhaustive = true;
+                        } else {
+                            self.error_at_current(&format!(
+                                "Match on '{}' is missing {}.",
+                                enumeration.name,
+                                missing.join(", ")
+                            ));
+                        }
+                    }
+                    _ => self.error_at_current("Expect a default '_' arm at the end of the match."),
+                }
+                break;
+            }
+
+            let default = self.check(TokenKind::Identifier)
+                && self.lexer.get_lexeme(&self.parser.current) == "_";
+            let mut next_jumps = Vec::new();
+            if default {
+                self.advance();
+                if self.check(TokenKind::If) {
+                    self.error_at_current("The default match arm can't have a guard.");
+                }
+            } else {
+                self.emit_bytes(chunk, opcode::OP_DUP, 0);
+                let variant = self.match_pattern(chunk);
+                if let Type::Variant(_) = subject {
+                    let pattern = self.last_type.ty.clone();
+                    self.check_type(&pattern, &subject, "the match pattern");
+                }
+                self.emit_byte(chunk, opcode::OP_EQUAL);
+                next_jumps.push(self.emit_jump(chunk, opcode::OP_JUMP_IF_FALSE));
+                self.emit_byte(chunk, opcode::OP_POP);
+
+                if self.match_token(TokenKind::If) {
+                    self.expression(chunk);
+                    next_jumps.push(self.emit_jump(chunk, opcode::OP_JUMP_IF_FALSE));
+                    self.emit_byte(chunk, opcode::OP_POP);
+                } else if let Some(variant) = variant {
+                    // Arms with a guard might not match, so they don't count
+                    covered.push(variant);
+                }
+            }
+            self.consume(TokenKind::Arrow, "Expect '=>' after match pattern.");
+            self.emit_byte(chunk, opcode::OP_POP);
+
+            if statement {
+                self.statement(chunk);
+                returns &= self.returns;
+                self.match_token(TokenKind::Comma);
+            } else {
+                self.expression(chunk);
+                result_type = Some(match result_type {
+                    Some(ty) => Typed::new(
+                        Type::either(&ty.ty, &self.last_type.ty),
+                        ty.annotated && self.last_type.annotated,
+                    ),
+                    None => self.last_type.clone(),
+                });
+                if !self.check(TokenKind::RightBrace) {
+                    self.consume(TokenKind::Comma, "Expect ',' after match arm.");
+                }
+            }
+
+            // The default arm matches anything, so it has to be the last one
+            if default {
+                break;
+            }
+            end_jumps.push(self.emit_jump(chunk, opcode::OP_JUMP));
+            for jump in next_jumps {
+                self.patch_jump(chunk, jump);
+            }
+            self.emit_byte(chunk, opcode::OP_POP);
+        }
+        self.consume(
+            TokenKind::RightBrace,
+            "Expect '}' after the default match arm.",
+        );
+
+        // The value can still be something else, an argument of unknown type isn't rejected
+        if exhaustive {
+            self.emit_constant(
+                chunk,
+                Value::String(Rc::from("No match arm for the value.")),
+            );
+            self.emit_byte(chunk, opcode::OP_THROW);
+        }
+
+        for jump in end_jumps {
+            self.patch_jump(chunk, jump);
+        }
+        self.last_type = result_type.unwrap_or_else(Typed::any);
+        self.returns = returns;
+    }
+
+    // Parses and compiles the literal or enum variant a match arm compares the value with
+    // Returns the name of the variant if the pattern is a known one
+    fn match_pattern(&mut self, chunk: &mut Chunk) -> Option<String> {
+        self.advance();
+        match self.parser.previous.kind {
+            // A variant is a property of a variable, like Color.Red or module.Color.Red
+            TokenKind::Identifier if self.parser.current.kind == TokenKind::Dot => {
+                self.variable(chunk, false);
+                let mut property = String::new();
+                while self.match_token(TokenKind::Dot) {
+                    let object = self.last_type.ty.clone();
+                    self.consume(TokenKind::Identifier, "Expect property name after '.'.");
+                    property = self.lexer.get_lexeme(&self.parser.previous).to_string();
+                    let name = self.identifier_constant(self.parser.previous);
+                    self.emit_bytes(chunk, opcode::OP_GET_PROPERTY, name);
+                    self.last_type = self.property_type(&object, &property);
+                }
+                if let Type::Variant(_) = self.last_type.ty {
+                    return Some(property);
+                }
+            }
+            TokenKind::Number => self.number(chunk),
+            TokenKind::String => self.string(chunk),
+            TokenKind::RawString => self.raw_string(chunk),
+            TokenKind::True | TokenKind::False | TokenKind::Nil => self.literal(chunk),
+            TokenKind::Minus if self.parser.current.kind == TokenKind::Number => {
+                if !self.min_integer(chunk) {
+                    self.advance();
+                    self.number(chunk);
+                    self.emit_byte(chunk, opcode::OP_NEGATE);
+                }
+            }
+            _ => self.error("Expect a literal or enum variant pattern."),
+        }
+        None
+    }
+
     // Parses and compiles a for loop statement
-    fn for_statement(&mut self, chunk: &mut Chunk) {
+    fn for_statement(&mut self, chunk: &mut Chunk, label: Option<String>) {
         // Start loop scope
         self.begin_scope();
 
         self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.");
-        if self.match_token(TokenKind::Semicolon) {
+        let declares = self.match_token(TokenKind::Var);
+        if self.check(TokenKind::Identifier) && self.peek_kind() == TokenKind::In {
+            self.for_in_loop(chunk, label);
+            self.end_scope(chunk);
+            return;
+        }
+
+        if declares {
+            self.var_declaration(chunk, false);
+        } else if self.match_token(TokenKind::Semicolon) {
             // No initializer
-        } else if self.match_token(TokenKind::Var) {
-            self.var_declaration(chunk);
         } else {
             // Initialize is an expression
             self.expression_statement(chunk);
@@ -609,7 +1708,8 @@ impl Compiler {
             // Patch the jump to the start of the body
             self.patch_jump(chunk, body_jump);
         }
-        // Compile the loop body
+        // Compile the loop body, 'continue' jumps to the increment
+        self.begin_loop(label, loop_start);
         self.statement(chunk);
 
         // Jump back to top
@@ -622,16 +1722,59 @@ impl Compiler {
             self.emit_byte(chunk, opcode::OP_POP);
         }
 
+        // 'break' jumps past the condition, but the loop variables still have to be popped
+        // A loop without a condition only ends by breaking out of it
+        let broken = self.end_loop(chunk);
+        self.returns = loop_end.is_none() && !broken;
+
         // End loop scope
         self.end_scope(chunk);
     }
-    fn while_statement(&mut self, chunk: &mut Chunk) {
+
+    // Compiles the rest of a 'for (x in iterable)' loop, inside the scope of the for statement
+    fn for_in_loop(&mut self, chunk: &mut Chunk, label: Option<String>) {
+        self.consume(TokenKind::Identifier, "Expect loop variable name.");
+        let name = self.lexer.get_lexeme(&self.parser.previous).to_string();
+        self.consume(TokenKind::In, "Expect 'in' after loop variable.");
+
+        // The iterable and the position in it are kept in hidden locals
+        self.expression(chunk);
+        self.consume(TokenKind::RightParen, "Expect ')' after for clauses.");
+        self.emit_byte(chunk, opcode::OP_GET_ITERATOR);
+        self.declare_hidden_local();
+        let slot = (self.locals.len() - 1) as u8;
+        self.emit_constant(chunk, Value::Integer(0));
+        self.declare_hidden_local();
+
+        // Each iteration pushes the next value, or jumps to the end when there are none left
+        let loop_start = chunk.code.len();
+        self.emit_bytes(chunk, opcode::OP_ITERATE, slot);
+        self.emit_bytes(chunk, 0xff, 0xff);
+        let exit_jump = chunk.code.len() - 2;
+
+        // The loop variable is scoped to one iteration, so closures capture a fresh one each time
+        self.begin_loop(label, loop_start);
+        self.begin_scope();
+        self.add_local(name);
+        self.mark_initialized();
+        self.statement(chunk);
+        self.end_scope(chunk);
+
+        self.emit_jump_back(chunk, loop_start);
+        self.patch_jump(chunk, exit_jump);
+        self.end_loop(chunk);
+        self.returns = false;
+    }
+
+    // Parses and compiles a while loop statement
+    fn while_statement(&mut self, chunk: &mut Chunk, label: Option<String>) {
         // Start address of loop
         let loop_start = chunk.code.len();
 
         self.consume(TokenKind::LeftParen, "Expect '(' after 'while'.");
 
         // Compile the condition expression
+        let forever = self.check(TokenKind::True) && self.peek_kind() == TokenKind::RightParen;
         self.expression(chunk);
 
         self.consume(TokenKind::RightParen, "Expect ')' after condition.");
@@ -642,6 +1785,7 @@ impl Compiler {
         self.emit_byte(chunk, opcode::OP_POP);
 
         // Compile the body statement
+        self.begin_loop(label, loop_start);
         self.statement(chunk);
 
         // Jump back to start of loop
@@ -649,6 +1793,101 @@ impl Compiler {
 
         // Patch the jump to the end of the loop now that we know how long the loop body is
         self.patch_jump(chunk, jump_to_end);
+
+        // Pop the condition value from stack
+        self.emit_byte(chunk, opcode::OP_POP);
+
+        let broken = self.end_loop(chunk);
+        self.returns = forever && !broken;
+    }
+
+    // Starts tracking a loop whose body is about to be compiled
+    fn begin_loop(&mut self, label: Option<String>, continue_target: usize) {
+        self.loops.push(LoopState {
+            label,
+            continue_target,
+            scope_depth: self.locals.scope_depth(),
+            try_depth: self.tries.len(),
+            break_jumps: Vec::new(),
+        });
+    }
+
+    // Stops tracking the innermost loop and patches its breaks to jump here
+    // Returns whether the loop has any breaks
+    fn end_loop(&mut self, chunk: &mut Chunk) -> bool {
+        let state = self.loops.pop().expect("Loop state should exist");
+        let broken = !state.break_jumps.is_empty();
+        for jump in state.break_jumps {
+            self.patch_jump(chunk, jump);
+        }
+        broken
+    }
+
+    // Finds the loop targeted by a 'break' or 'continue', either the innermost one or the one with the given label
+    fn find_loop(&mut self, keyword: &str) -> Option<usize> {
+        if self.loops.is_empty() {
+            self.error(&format!("Can't use '{}' outside of a loop.", keyword));
+            return None;
+        }
+
+        if !self.match_token(TokenKind::Identifier) {
+            return Some(self.loops.len() - 1);
+        }
+
+        let label = self.lexer.get_lexeme(&self.parser.previous);
+        let index = self
+            .loops
+            .iter()
+            .rposition(|state| state.label.as_deref() == Some(label));
+        if index.is_none() {
+            self.error(&format!("No loop labeled '{}'.", label));
+        }
+        index
+    }
+
+    // Pops all locals declared deeper than the given scope depth, without ending their scopes
+    fn discard_locals(&mut self, chunk: &mut Chunk, depth: usize) {
+        for captured in self.locals.captured_above(depth) {
+            if captured {
+                self.emit_byte(chunk, opcode::OP_CLOSE_UPVALUE);
+            } else {
+                self.emit_byte(chunk, opcode::OP_POP);
+            }
+        }
+    }
+
+    // Parses and compiles a break statement
+    fn break_statement(&mut self, chunk: &mut Chunk) {
+        let target = self.find_loop("break");
+        self.consume(TokenKind::Semicolon, "Expect ';' after 'break'.");
+
+        if let Some(target) = target {
+            self.exit(chunk, Exit::Break(target));
+        }
+    }
+
+    // Parses and compiles a continue statement
+    fn continue_statement(&mut self, chunk: &mut Chunk) {
+        let target = self.find_loop("continue");
+        self.consume(TokenKind::Semicolon, "Expect ';' after 'continue'.");
+
+        if let Some(target) = target {
+            self.exit(chunk, Exit::Continue(target));
+        }
+    }
+
+    // Parses and compiles a loop prefixed by a label, 'name: while (...)'
+    fn labeled_statement(&mut self, chunk: &mut Chunk) {
+        let label = self.lexer.get_lexeme(&self.parser.previous).to_string();
+        self.consume(TokenKind::Colon, "Expect ':' after label.");
+
+        if self.match_token(TokenKind::While) {
+            self.while_statement(chunk, Some(label));
+        } else if self.match_token(TokenKind::For) {
+            self.for_statement(chunk, Some(label));
+        } else {
+            self.error_at_current("Expect loop after label.");
+        }
     }
 
     // Parses and compiles a print statement
@@ -658,6 +1897,14 @@ impl Compiler {
         self.emit_byte(chunk, opcode::OP_PRINT);
     }
 
+    // Parses and compiles a sleep statement
+    fn sleep_statement(&mut self, chunk: &mut Chunk) {
+        // Duration in seconds
+        self.expression(chunk);
+        self.consume(TokenKind::Semicolon, "Expect ';' after sleep duration.");
+        self.emit_byte(chunk, opcode::OP_SLEEP);
+    }
+
     // Parses and compiles a return statement
     fn return_statement(&mut self, chunk: &mut Chunk) {
         if self.function_type == FunctionType::Script {
@@ -665,12 +1912,299 @@ impl Compiler {
         }
         if self.match_token(TokenKind::Semicolon) {
             // Just return nil
-            self.emit_return(chunk);
+            self.emit_default_return_value(chunk);
+            if self.function_type != FunctionType::Initializer {
+                self.last_type = Typed::literal(Type::Nil);
+                self.check_return_type();
+            }
         } else {
+            if self.function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
             self.expression(chunk);
+            self.check_return_type();
             self.consume(TokenKind::Semicolon, "Expect ';' after return value.");
-            self.emit_byte(chunk, opcode::OP_RETURN);
         }
+
+        self.exit(chunk, Exit::Return);
+        self.returns = true;
+    }
+
+    // Parses and compiles a throw statement
+    fn throw_statement(&mut self, chunk: &mut Chunk) {
+        self.expression(chunk);
+        self.consume(TokenKind::Semicolon, "Expect ';' after thrown value.");
+        self.emit_byte(chunk, opcode::OP_THROW);
+        self.returns = true;
+    }
+
+    // Parses and compiles a try statement with a catch block, a finally block or both
+    // The finally block is compiled once, every way out of the statement goes through it
+    //
+    //     OP_NIL, OP_NIL         only with a finally block, the action and value slots
+    //     OP_TRY catch           handler for the try block
+    //     <try block>
+    //     OP_END_TRY
+    //     OP_JUMP finally        skip to the end without a finally block
+    //   catch:                   the exception is on top of the stack
+    //     OP_TRY catch_throw     only with a finally block
+    //     <catch block>
+    //     OP_END_TRY
+    //     OP_JUMP finally
+    //   catch_throw:             move the new exception into the slot of the caught one
+    //     OP_SET_LOCAL, OP_POP
+    //   finally_throw:           without a catch block the try handler jumps here
+    //     OP_SET_LOCAL, OP_POP   the exception goes into the value slot
+    //     OP_SET_LOCAL, OP_POP   the action to rethrow it
+    //   finally:                 returns, breaks and continues jump here with their action set
+    //     <finally block>
+    //     OP_GET_LOCAL           for each action taken after the block
+    //     OP_CONSTANT, OP_EQUAL
+    //     OP_JUMP_IF_FALSE next
+    //     OP_POP
+    //     <rethrow or continue the exit>
+    //   next:
+    //     OP_POP
+    //     ...
+    //     OP_POP, OP_POP         the slots, when the statement ends normally
+    fn try_statement(&mut self, chunk: &mut Chunk) {
+        let has_finally = self.find_finally();
+        let finally = has_finally.then(|| {
+            self.begin_scope();
+            self.emit_byte(chunk, opcode::OP_NIL);
+            self.declare_hidden_local();
+            self.emit_byte(chunk, opcode::OP_NIL);
+            self.declare_hidden_local();
+            FinallyState {
+                scope_depth: self.locals.scope_depth(),
+                action_slot: self.locals.len() - 2,
+                value_slot: self.locals.len() - 1,
+                exits: Vec::new(),
+                entry_jumps: Vec::new(),
+            }
+        });
+
+        let try_jump = self.emit_jump(chunk, opcode::OP_TRY);
+        self.tries.push(TryState {
+            handler_active: true,
+            finally,
+        });
+
+        self.consume(TokenKind::LeftBrace, "Expect '{' after 'try'.");
+        self.begin_scope();
+        self.block(chunk);
+        self.end_scope(chunk);
+        let mut returns = self.returns;
+
+        self.emit_byte(chunk, opcode::OP_END_TRY);
+        let mut exit_jumps = vec![self.emit_jump(chunk, opcode::OP_JUMP)];
+        self.patch_jump(chunk, try_jump);
+
+        let has_catch = self.match_token(TokenKind::Catch);
+        if has_catch {
+            self.begin_scope();
+            if self.match_token(TokenKind::LeftParen) {
+                self.consume(TokenKind::Identifier, "Expect exception variable name.");
+                let name = self.lexer.get_lexeme(&self.parser.previous).to_string();
+                self.locals.declare(name);
+                self.locals.define();
+                self.consume(
+                    TokenKind::RightParen,
+                    "Expect ')' after exception variable.",
+                );
+            } else {
+                self.declare_hidden_local();
+            }
+            let slot = self.locals.len() - 1;
+
+            // Exceptions thrown in the catch block still run the finally block
+            let catch_jump = has_finally.then(|| self.emit_jump(chunk, opcode::OP_TRY));
+            if let Some(state) = self.tries.last_mut() {
+                state.handler_active = has_finally;
+            }
+
+            self.consume(TokenKind::LeftBrace, "Expect '{' after catch.");
+            self.block(chunk);
+            returns &= self.returns;
+
+            if has_finally {
+                self.emit_byte(chunk, opcode::OP_END_TRY);
+            }
+            self.end_scope(chunk);
+
+            if let Some(catch_jump) = catch_jump {
+                exit_jumps.push(self.emit_jump(chunk, opcode::OP_JUMP));
+                self.patch_jump(chunk, catch_jump);
+                self.emit_bytes(chunk, opcode::OP_SET_LOCAL, slot as u8);
+                self.emit_byte(chunk, opcode::OP_POP);
+            }
+        }
+
+        let state = self.tries.pop().expect("Try state should exist");
+
+        match state.finally {
+            Some(finally) => {
+                // Keep the exception and rethrow it after the finally block
+                self.emit_bytes(chunk, opcode::OP_SET_LOCAL, finally.value_slot as u8);
+                self.emit_byte(chunk, opcode::OP_POP);
+                self.emit_constant(chunk, Value::Integer(0));
+                self.emit_bytes(chunk, opcode::OP_SET_LOCAL, finally.action_slot as u8);
+                self.emit_byte(chunk, opcode::OP_POP);
+
+                for jump in exit_jumps.into_iter().chain(finally.entry_jumps) {
+                    self.patch_jump(chunk, jump);
+                }
+                self.consume(TokenKind::Finally, "Expect 'finally' after catch block.");
+                self.finally_block(chunk);
+                returns |= self.returns;
+
+                // Carry on with whatever left the statement, the surrounding try statements are active again
+                let exits = std::iter::once(None).chain(finally.exits.into_iter().map(Some));
+                for (action, exit) in exits.enumerate() {
+                    self.emit_bytes(chunk, opcode::OP_GET_LOCAL, finally.action_slot as u8);
+                    self.emit_constant(chunk, Value::Integer(action as i64));
+                    self.emit_byte(chunk, opcode::OP_EQUAL);
+                    let next = self.emit_jump(chunk, opcode::OP_JUMP_IF_FALSE);
+                    self.emit_byte(chunk, opcode::OP_POP);
+                    match exit {
+                        Some(Exit::Break(target)) => self.exit(chunk, Exit::Break(target)),
+                        Some(Exit::Continue(target)) => self.exit(chunk, Exit::Continue(target)),
+                        Some(Exit::Return) => {
+                            self.emit_bytes(chunk, opcode::OP_GET_LOCAL, finally.value_slot as u8);
+                            self.exit(chunk, Exit::Return);
+                        }
+                        None => {
+                            self.emit_bytes(chunk, opcode::OP_GET_LOCAL, finally.value_slot as u8);
+                            self.emit_byte(chunk, opcode::OP_THROW);
+                        }
+                    }
+                    self.patch_jump(chunk, next);
+                    self.emit_byte(chunk, opcode::OP_POP);
+                }
+                self.end_scope(chunk);
+            }
+            None => {
+                if !has_catch {
+                    self.error_at_current("Expect 'catch' or 'finally' after try block.");
+                }
+                for jump in exit_jumps {
+                    self.patch_jump(chunk, jump);
+                }
+            }
+        }
+        self.returns = returns;
+    }
+
+    // Parses and compiles the block after 'finally'
+    fn finally_block(&mut self, chunk: &mut Chunk) {
+        self.consume(TokenKind::LeftBrace, "Expect '{' after 'finally'.");
+        self.begin_scope();
+        self.block(chunk);
+        self.end_scope(chunk);
+    }
+
+    // Leaves the try statements an exit jumps out of, and then returns or jumps to the target loop
+    // If one of them has a finally block the exit jumps into it instead, and continues after the block
+    fn exit(&mut self, chunk: &mut Chunk, exit: Exit) {
+        let depth = match exit {
+            Exit::Return => 0,
+            Exit::Break(target) | Exit::Continue(target) => self.loops[target].try_depth,
+        };
+        for index in (depth..self.tries.len()).rev() {
+            if self.tries[index].handler_active {
+                self.emit_byte(chunk, opcode::OP_END_TRY);
+            }
+            let Some(finally) = &mut self.tries[index].finally else {
+                continue;
+            };
+            let action = match finally.exits.iter().position(|other| *other == exit) {
+                Some(position) => position + 1,
+                None => {
+                    finally.exits.push(exit);
+                    finally.exits.len()
+                }
+            };
+            let (scope_depth, action_slot, value_slot) =
+                (finally.scope_depth, finally.action_slot, finally.value_slot);
+
+            if exit == Exit::Return {
+                self.emit_bytes(chunk, opcode::OP_SET_LOCAL, value_slot as u8);
+                self.emit_byte(chunk, opcode::OP_POP);
+            }
+            self.emit_constant(chunk, Value::Integer(action as i64));
+            self.emit_bytes(chunk, opcode::OP_SET_LOCAL, action_slot as u8);
+            self.emit_byte(chunk, opcode::OP_POP);
+            self.discard_locals(chunk, scope_depth);
+            let jump = self.emit_jump(chunk, opcode::OP_JUMP);
+            if let Some(finally) = &mut self.tries[index].finally {
+                finally.entry_jumps.push(jump);
+            }
+            return;
+        }
+
+        match exit {
+            Exit::Return => self.emit_byte(chunk, opcode::OP_RETURN),
+            Exit::Break(target) => {
+                self.discard_locals(chunk, self.loops[target].scope_depth);
+                let jump = self.emit_jump(chunk, opcode::OP_JUMP);
+                self.loops[target].break_jumps.push(jump);
+            }
+            Exit::Continue(target) => {
+                self.discard_locals(chunk, self.loops[target].scope_depth);
+                self.emit_jump_back(chunk, self.loops[target].continue_target);
+            }
+        }
+    }
+
+    // Looks ahead for a finally block after the try statement starting at the current token
+    fn find_finally(&mut self) -> bool {
+        let state = self.lexer.state();
+        let mut token = self.parser.current;
+        let mut found = false;
+        if self.skip_block(token) {
+            token = self.scan_ahead();
+            if token.kind == TokenKind::Catch {
+                token = self.scan_ahead();
+                if token.kind == TokenKind::LeftParen {
+                    while !matches!(token.kind, TokenKind::RightParen | TokenKind::Eof) {
+                        token = self.scan_ahead();
+                    }
+                    token = self.scan_ahead();
+                }
+                token = if self.skip_block(token) {
+                    self.scan_ahead()
+                } else {
+                    Token::new(TokenKind::Eof)
+                };
+            }
+            found = token.kind == TokenKind::Finally;
+        }
+
+        self.lexer.restore(state);
+        found
+    }
+
+    // Scans past the block opened by the given token, returns false if it isn't a complete block
+    fn skip_block(&mut self, open: Token) -> bool {
+        if open.kind != TokenKind::LeftBrace {
+            return false;
+        }
+        let mut depth = 1;
+        while depth > 0 {
+            match self.scan_ahead().kind {
+                TokenKind::LeftBrace => depth += 1,
+                TokenKind::RightBrace => depth -= 1,
+                TokenKind::Eof => return false,
+                _ => (),
+            }
+        }
+        true
+    }
+
+    // Declares a local without a name for a value the compiler keeps on the stack
+    fn declare_hidden_local(&mut self) {
+        self.locals.declare(String::from(""));
+        self.locals.define();
     }
 
     // Synchronizes the lexer and parser to a valid state, after the erronous declaration
@@ -684,10 +2218,19 @@ impl Compiler {
                 TokenKind::Class
                 | TokenKind::Fun
                 | TokenKind::Var
+                | TokenKind::Const
+                | TokenKind::Enum
                 | TokenKind::For
                 | TokenKind::If
                 | TokenKind::While
                 | TokenKind::Print
+                | TokenKind::Sleep
+                | TokenKind::Break
+                | TokenKind::Continue
+                | TokenKind::Try
+                | TokenKind::Throw
+                | TokenKind::Match
+                | TokenKind::Import
                 | TokenKind::Return => return,
                 _ => {}
             }
@@ -697,16 +2240,32 @@ impl Compiler {
 
     // Parses and compiles a statement
     fn statement(&mut self, chunk: &mut Chunk) {
+        self.returns = false;
         if self.match_token(TokenKind::Print) {
             self.print_statement(chunk);
+        } else if self.match_token(TokenKind::Sleep) {
+            self.sleep_statement(chunk);
         } else if self.match_token(TokenKind::If) {
             self.if_statement(chunk);
         } else if self.match_token(TokenKind::Return) {
             self.return_statement(chunk);
         } else if self.match_token(TokenKind::For) {
-            self.for_statement(chunk);
+            self.for_statement(chunk, None);
         } else if self.match_token(TokenKind::While) {
-            self.while_statement(chunk);
+            self.while_statement(chunk, None);
+        } else if self.match_token(TokenKind::Break) {
+            self.break_statement(chunk);
+        } else if self.match_token(TokenKind::Continue) {
+            self.continue_statement(chunk);
+        } else if self.match_token(TokenKind::Try) {
+            self.try_statement(chunk);
+        } else if self.match_token(TokenKind::Throw) {
+            self.throw_statement(chunk);
+        } else if self.match_token(TokenKind::Match) {
+            self.match_(chunk, true);
+        } else if self.check(TokenKind::Identifier) && self.peek_kind() == TokenKind::Colon {
+            self.advance();
+            self.labeled_statement(chunk);
         } else if self.match_token(TokenKind::LeftBrace) {
             self.begin_scope();
             self.block(chunk);
@@ -718,10 +2277,20 @@ impl Compiler {
 
     // Parses and compiles a declaration
     fn declaration(&mut self, chunk: &mut Chunk) {
-        if self.match_token(TokenKind::Fun) {
+        if self.match_token(TokenKind::Class) {
+            self.class_declaration(chunk);
+        } else if self.check(TokenKind::Fun) && self.peek_kind() != TokenKind::LeftParen {
+            // 'fun' followed by '(' is an anonymous function used as an expression statement
+            self.advance();
             self.function_declaration(chunk);
         } else if self.match_token(TokenKind::Var) {
-            self.var_declaration(chunk);
+            self.var_declaration(chunk, false);
+        } else if self.match_token(TokenKind::Const) {
+            self.var_declaration(chunk, true);
+        } else if self.match_token(TokenKind::Enum) {
+            self.enum_declaration(chunk);
+        } else if self.match_token(TokenKind::Import) {
+            self.import_declaration(chunk);
         } else {
             self.statement(chunk);
         }
@@ -735,14 +2304,34 @@ impl Compiler {
     // Parses and compiles a unary expression
     fn unary(&mut self, chunk: &mut Chunk) {
         let operator_kind = self.parser.previous.kind;
+        if operator_kind == TokenKind::Minus && self.min_integer(chunk) {
+            return;
+        }
 
         // Compile the operand
         self.parse_expression(chunk, Precedence::Unary);
 
         // Emit the operator instruction
         match operator_kind {
-            TokenKind::Bang => self.emit_byte(chunk, opcode::OP_NOT),
-            TokenKind::Minus => self.emit_byte(chunk, opcode::OP_NEGATE),
+            TokenKind::Bang => {
+                self.emit_byte(chunk, opcode::OP_NOT);
+                self.last_type = Typed::literal(Type::Bool);
+            }
+            TokenKind::Minus => {
+                self.emit_byte(chunk, opcode::OP_NEGATE);
+                let operand = self.last_type.clone();
+                if operand.annotated && !operand.ty.is_numeric() {
+                    self.error(&format!("Operand must be a number, got {}.", operand.ty));
+                }
+            }
+            TokenKind::Tilde => {
+                self.emit_byte(chunk, opcode::OP_BIT_NOT);
+                let operand = self.last_type.clone();
+                if operand.annotated && !operand.ty.fits(&Type::Int) {
+                    self.error(&format!("Operand must be an integer, got {}.", operand.ty));
+                }
+                self.last_type = Typed::new(Type::Int, operand.annotated);
+            }
             _ => (),
         }
     }
@@ -751,32 +2340,365 @@ impl Compiler {
     fn binary(&mut self, chunk: &mut Chunk) {
         let operator_kind = self.parser.previous.kind;
 
+        // Operators are left associative, except for ** which groups to the right
         let precedence = Precedence::from(operator_kind);
+        let operand_precedence = if precedence == Precedence::Exponent {
+            precedence
+        } else {
+            precedence.next()
+        };
 
-        self.parse_expression(chunk, precedence);
+        let left = self.last_type.clone();
+        self.parse_expression(chunk, operand_precedence);
+        let right = self.last_type.clone();
 
         // TODO: make operations such as != >= and <= a single instruction
-        match operator_kind {
-            TokenKind::BangEqual => self.emit_bytes(chunk, opcode::OP_EQUAL, opcode::OP_NOT),
-            TokenKind::EqualEqual => self.emit_byte(chunk, opcode::OP_EQUAL),
-            TokenKind::Greater => self.emit_byte(chunk, opcode::OP_GREATER),
-            TokenKind::GreaterEqual => self.emit_bytes(chunk, opcode::OP_LESS, opcode::OP_NOT),
-            TokenKind::Less => self.emit_byte(chunk, opcode::OP_LESS),
-            TokenKind::LessEqual => self.emit_bytes(chunk, opcode::OP_GREATER, opcode::OP_NOT),
-            TokenKind::Percent => self.emit_byte(chunk, opcode::OP_MODULO),
-            TokenKind::Plus => self.emit_byte(chunk, opcode::OP_ADD),
-            TokenKind::Minus => self.emit_byte(chunk, opcode::OP_SUBTRACT),
-            TokenKind::Star => self.emit_byte(chunk, opcode::OP_MULTIPLY),
-            TokenKind::Slash => self.emit_byte(chunk, opcode::OP_DIVIDE),
-            _ => (),
+        let operator = match operator_kind {
+            TokenKind::BangEqual | TokenKind::EqualEqual => opcode::OP_EQUAL,
+            TokenKind::Greater | TokenKind::LessEqual => opcode::OP_GREATER,
+            TokenKind::Less | TokenKind::GreaterEqual => opcode::OP_LESS,
+            TokenKind::Percent => opcode::OP_MODULO,
+            TokenKind::Plus => opcode::OP_ADD,
+            TokenKind::Minus => opcode::OP_SUBTRACT,
+            TokenKind::Star => opcode::OP_MULTIPLY,
+            TokenKind::Slash => opcode::OP_DIVIDE,
+            TokenKind::TildeSlash => opcode::OP_INT_DIVIDE,
+            TokenKind::StarStar => opcode::OP_POWER,
+            TokenKind::Ampersand => opcode::OP_BIT_AND,
+            TokenKind::Pipe => opcode::OP_BIT_OR,
+            TokenKind::Caret => opcode::OP_BIT_XOR,
+            TokenKind::LessLess => opcode::OP_SHIFT_LEFT,
+            TokenKind::GreaterGreater => opcode::OP_SHIFT_RIGHT,
+            TokenKind::DotDot => opcode::OP_RANGE,
+            _ => return,
+        };
+        self.last_type = self.operation_type(operator, &left, &right);
+
+        // 'step' after a range is only a keyword there, it can still name a variable
+        if operator == opcode::OP_RANGE
+            && self.check(TokenKind::Identifier)
+            && self.lexer.get_lexeme(&self.parser.current) == "step"
+        {
+            let range = self.last_type.clone();
+            self.advance();
+            self.parse_expression(chunk, Precedence::Range.next());
+            let step = self.last_type.clone();
+            if step.annotated && !step.ty.fits(&Type::Int) {
+                self.error(&format!("Range step must be an integer, got {}.", step.ty));
+            }
+            self.emit_byte(chunk, opcode::OP_RANGE_STEP);
+            self.last_type = range;
+            return;
+        }
+
+        self.emit_byte(chunk, operator);
+        if matches!(
+            operator_kind,
+            TokenKind::BangEqual | TokenKind::GreaterEqual | TokenKind::LessEqual
+        ) {
+            self.emit_byte(chunk, opcode::OP_NOT);
         }
     }
 
+    // Gets the type of the result of an operator and reports operands that can't be used with it
+    // Only operands with annotated types are reported, the rest is left to the runtime checks
+    fn operation_type(&mut self, operator: u8, left: &Typed, right: &Typed) -> Typed {
+        let annotated = left.annotated || right.annotated;
+        let numeric = left.ty.is_numeric() && right.ty.is_numeric();
+        let ty = match operator {
+            // Anything but collections, functions and objects can be added to a string
+            opcode::OP_ADD if *left.ty.base() == Type::String => {
+                let addable = matches!(
+                    right.ty.base(),
+                    Type::Any | Type::Nil | Type::Bool | Type::String
+                ) || right.ty.is_numeric();
+                if annotated && !addable {
+                    self.error(&format!("Can't add {} to a string.", right.ty));
+                }
+                Type::String
+            }
+            opcode::OP_ADD
+            | opcode::OP_SUBTRACT
+            | opcode::OP_MULTIPLY
+            | opcode::OP_MODULO
+            | opcode::OP_INT_DIVIDE
+            | opcode::OP_DIVIDE
+            | opcode::OP_POWER
+            | opcode::OP_GREATER
+            | opcode::OP_LESS => {
+                if annotated && !numeric {
+                    self.error(&format!(
+                        "Operands must be numbers, got {} and {}.",
+                        left.ty, right.ty
+                    ));
+                }
+                match operator {
+                    opcode::OP_DIVIDE => Type::Float,
+                    // A negative integer exponent gives a float
+                    opcode::OP_POWER => match Type::arithmetic(&left.ty, &right.ty) {
+                        Type::Int => Type::Number,
+                        ty => ty,
+                    },
+                    opcode::OP_GREATER | opcode::OP_LESS => Type::Bool,
+                    // A string on the right could still be added to an unknown left operand
+                    opcode::OP_ADD if *left.ty.base() == Type::Any => Type::Any,
+                    _ => Type::arithmetic(&left.ty, &right.ty),
+                }
+            }
+            opcode::OP_BIT_AND
+            | opcode::OP_BIT_OR
+            | opcode::OP_BIT_XOR
+            | opcode::OP_SHIFT_LEFT
+            | opcode::OP_SHIFT_RIGHT => {
+                if annotated && !(left.ty.fits(&Type::Int) && right.ty.fits(&Type::Int)) {
+                    self.error(&format!(
+                        "Operands must be integers, got {} and {}.",
+                        left.ty, right.ty
+                    ));
+                }
+                Type::Int
+            }
+            opcode::OP_RANGE => {
+                if annotated && !(left.ty.fits(&Type::Int) && right.ty.fits(&Type::Int)) {
+                    self.error(&format!(
+                        "Range bounds must be integers, got {} and {}.",
+                        left.ty, right.ty
+                    ));
+                }
+                Type::Range
+            }
+            _ => Type::Bool,
+        };
+        Typed::new(ty, annotated)
+    }
+
     // Parses and compiles a call instruction
     fn call(&mut self, chunk: &mut Chunk) {
-        // Emit the return address constant
-        let argument_count = self.argument_list(chunk);
-        self.emit_bytes(chunk, opcode::OP_CALL, argument_count);
+        let callee = self.last_type.clone();
+        let arguments = self.argument_list(chunk);
+        self.emit_bytes(chunk, opcode::OP_CALL, arguments.len() as u8);
+        self.last_type = self.call_type(&callee, &arguments);
+    }
+
+    // Checks the arguments of a call against the signature of the callee and gets the type of the result
+    fn call_type(&mut self, callee: &Typed, arguments: &[Typed]) -> Typed {
+        match &callee.ty {
+            // Calls of functions without annotations are left to the runtime checks
+            Type::Function(Some(signature)) if signature.annotated => {
+                if !signature.accepts(arguments.len()) {
+                    self.error(&format!(
+                        "Expected {} arguments, but got {}.",
+                        signature.arity_range(),
+                        arguments.len()
+                    ));
+                }
+                for (index, (argument, parameter)) in
+                    arguments.iter().zip(&signature.parameters).enumerate()
+                {
+                    let what = format!("argument {} of '{}'", index + 1, signature.name);
+                    self.check_type(&argument.ty, parameter, &what);
+                }
+                Typed::new(signature.result.clone(), true)
+            }
+            Type::Class(class) => Typed::literal(Type::Instance(class.clone())),
+            _ => Typed::any(),
+        }
+    }
+
+    // Reports an error if a value of the given type can't be used where the expected type is
+    fn check_type(&mut self, value: &Type, expected: &Type, what: &str) {
+        if !value.fits(expected) {
+            self.error(&format!(
+                "Expected {} for {}, got {}.",
+                expected, what, value
+            ));
+        }
+    }
+
+    // Parses and compiles a property access, assignment or method invocation
+    fn dot(&mut self, chunk: &mut Chunk, can_assign: bool) {
+        let object = self.last_type.ty.clone();
+        self.consume(TokenKind::Identifier, "Expect property name after '.'.");
+        let property = self.lexer.get_lexeme(&self.parser.previous).to_string();
+        let name = self.identifier_constant(self.parser.previous);
+
+        let target = AssignTarget::Property(name);
+        if self
+            .assignment(chunk, target, can_assign, &Typed::any())
+            .is_some()
+        {
+            // Assigned to the property
+        } else if self.match_token(TokenKind::LeftParen) {
+            // Invoke the method directly instead of creating a bound method first
+            let arguments = self.argument_list(chunk);
+            self.emit_bytes(chunk, opcode::OP_INVOKE, name);
+            self.emit_byte(chunk, arguments.len() as u8);
+        } else {
+            self.read_target(chunk, target);
+            self.last_type = self.property_type(&object, &property);
+            return;
+        }
+        // Fields and methods aren't typed
+        self.last_type = Typed::any();
+    }
+
+    // Gets the type of a property read from a value of the given type
+    // Only enums and their variants have known properties, so misspelled variants are caught here
+    fn property_type(&mut self, object: &Type, property: &str) -> Typed {
+        match object {
+            Type::Enum(enumeration) => {
+                if !enumeration
+                    .variants
+                    .iter()
+                    .any(|variant| variant == property)
+                {
+                    self.error(&format!(
+                        "Enum '{}' has no variant '{}'.",
+                        enumeration.name, property
+                    ));
+                }
+                Typed::literal(Type::Variant(enumeration.clone()))
+            }
+            Type::Variant(_) => match property {
+                "name" => Typed::literal(Type::String),
+                "index" => Typed::literal(Type::Int),
+                _ => Typed::any(),
+            },
+            _ => Typed::any(),
+        }
+    }
+
+    // Compiles a 'super' method access or invocation
+    fn super_(&mut self, chunk: &mut Chunk) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => {}
+        }
+
+        self.consume(TokenKind::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenKind::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(self.parser.previous);
+
+        // Load the instance and then the superclass to look the method up in
+        self.named_variable(chunk, "this", false);
+        if self.match_token(TokenKind::LeftParen) {
+            let arguments = self.argument_list(chunk);
+            self.named_variable(chunk, "super", false);
+            self.emit_bytes(chunk, opcode::OP_SUPER_INVOKE, name);
+            self.emit_byte(chunk, arguments.len() as u8);
+        } else {
+            self.named_variable(chunk, "super", false);
+            self.emit_bytes(chunk, opcode::OP_GET_SUPER, name);
+        }
+        self.last_type = Typed::any();
+    }
+
+    // Parses and compiles a list literal
+    fn list(&mut self, chunk: &mut Chunk) {
+        let mut element_count = 0;
+
+        if !self.check(TokenKind::RightBracket) {
+            loop {
+                // Allow a trailing comma
+                if self.check(TokenKind::RightBracket) {
+                    break;
+                }
+                self.expression(chunk);
+                if element_count == 255 {
+                    self.error("Can't have more than 255 elements in a list literal.");
+                }
+                element_count += 1;
+                if !self.match_token(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenKind::RightBracket, "Expect ']' after list elements.");
+        self.emit_bytes(chunk, opcode::OP_BUILD_LIST, element_count as u8);
+        self.last_type = Typed::literal(Type::List);
+    }
+
+    // Parses and compiles a map literal
+    fn map(&mut self, chunk: &mut Chunk) {
+        let mut entry_count = 0;
+
+        if !self.check(TokenKind::RightBrace) {
+            loop {
+                // Allow a trailing comma
+                if self.check(TokenKind::RightBrace) {
+                    break;
+                }
+                self.expression(chunk);
+                self.consume(TokenKind::Colon, "Expect ':' after map key.");
+                self.expression(chunk);
+                if entry_count == 255 {
+                    self.error("Can't have more than 255 entries in a map literal.");
+                }
+                entry_count += 1;
+                if !self.match_token(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after map entries.");
+        self.emit_bytes(chunk, opcode::OP_BUILD_MAP, entry_count as u8);
+        self.last_type = Typed::literal(Type::Map);
+    }
+
+    // Parses and compiles an index access or assignment
+    fn index(&mut self, chunk: &mut Chunk, can_assign: bool) {
+        self.expression(chunk);
+        self.consume(TokenKind::RightBracket, "Expect ']' after index.");
+
+        let target = AssignTarget::Index;
+        if self
+            .assignment(chunk, target, can_assign, &Typed::any())
+            .is_none()
+        {
+            self.read_target(chunk, target);
+        }
+        self.last_type = Typed::any();
+    }
+
+    // Compiles a 'this' expression
+    fn this(&mut self, chunk: &mut Chunk) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        // 'this' is just a local in slot 0 which can't be assigned to
+        self.variable(chunk, false);
+        self.last_target = None;
+    }
+
+    // Compiles a yield expression, which pauses the generator and evaluates to the value it is resumed with
+    // Any function containing a yield becomes a generator function
+    fn yield_(&mut self, chunk: &mut Chunk) {
+        match self.function_type {
+            FunctionType::Script => self.error("Can't yield from top-level code."),
+            FunctionType::Initializer => self.error("Can't yield from an initializer."),
+            _ => self.current_function.set_generator(),
+        }
+
+        // A bare yield produces nil
+        match self.parser.current.kind {
+            TokenKind::Semicolon
+            | TokenKind::RightParen
+            | TokenKind::RightBracket
+            | TokenKind::RightBrace
+            | TokenKind::Comma
+            | TokenKind::Colon
+            | TokenKind::Eof => self.emit_byte(chunk, opcode::OP_NIL),
+            _ => self.expression(chunk),
+        }
+        self.emit_byte(chunk, opcode::OP_YIELD);
+        self.last_target = None;
+        self.last_type = Typed::any();
     }
 
     // Compiles a literal
@@ -787,17 +2709,40 @@ impl Compiler {
             TokenKind::Nil => self.emit_byte(chunk, opcode::OP_NIL),
             _ => (),
         }
+        self.last_type = match self.parser.previous.kind {
+            TokenKind::Nil => Typed::literal(Type::Nil),
+            _ => Typed::literal(Type::Bool),
+        };
     }
 
     // Parses and compiles a prefix expression
     fn parse_prefix(&mut self, chunk: &mut Chunk, can_assign: bool) {
+        // Expressions the checker doesn't know about are any
+        self.last_type = Typed::any();
+        // Only a function that is the start of a documented initializer takes the doc comment
+        let doc = self.function_doc.take();
         match self.parser.previous.kind {
-            TokenKind::LeftParen => self.grouping(chunk),
-            TokenKind::Minus | TokenKind::Bang => self.unary(chunk),
+            TokenKind::LeftParen => self.grouping(chunk, doc),
+            TokenKind::Minus | TokenKind::Bang | TokenKind::Tilde => self.unary(chunk),
+            TokenKind::PlusPlus | TokenKind::MinusMinus => self.prefix_increment(chunk),
             TokenKind::Number => self.number(chunk),
             TokenKind::String => self.string(chunk),
+            TokenKind::Interpolation => self.interpolation(chunk),
+            TokenKind::RawString => self.raw_string(chunk),
             TokenKind::True | TokenKind::False | TokenKind::Nil => self.literal(chunk),
             TokenKind::Identifier => self.variable(chunk, can_assign),
+            TokenKind::This => self.this(chunk),
+            TokenKind::Super => self.super_(chunk),
+            TokenKind::Yield => self.yield_(chunk),
+            TokenKind::Match => self.match_(chunk, false),
+            TokenKind::LeftBracket => self.list(chunk),
+            TokenKind::LeftBrace => self.map(chunk),
+            TokenKind::Fun => self.function(
+                chunk,
+                FunctionType::Function,
+                String::from("anonymous"),
+                doc,
+            ),
             _ => {
                 self.error("Expect prefix expression.");
             }
@@ -805,13 +2750,21 @@ impl Compiler {
     }
 
     // Parses and compiles an infix expression
-    fn parse_infix(&mut self, chunk: &mut Chunk) {
+    fn parse_infix(&mut self, chunk: &mut Chunk, can_assign: bool) {
         match self.parser.previous.kind {
             TokenKind::Percent
             | TokenKind::Minus
             | TokenKind::Plus
             | TokenKind::Slash
             | TokenKind::Star
+            | TokenKind::TildeSlash
+            | TokenKind::StarStar
+            | TokenKind::Ampersand
+            | TokenKind::Pipe
+            | TokenKind::Caret
+            | TokenKind::LessLess
+            | TokenKind::GreaterGreater
+            | TokenKind::DotDot
             | TokenKind::BangEqual
             | TokenKind::EqualEqual
             | TokenKind::Greater
@@ -820,7 +2773,11 @@ impl Compiler {
             | TokenKind::LessEqual => self.binary(chunk),
             TokenKind::And => self.and(chunk),
             TokenKind::Or => self.or(chunk),
+            TokenKind::Question => self.conditional(chunk),
+            TokenKind::QuestionQuestion => self.coalesce(chunk),
             TokenKind::LeftParen => self.call(chunk),
+            TokenKind::Dot => self.dot(chunk, can_assign),
+            TokenKind::LeftBracket => self.index(chunk, can_assign),
             _ => {
                 self.error("Expect infix expression.");
             }
@@ -840,21 +2797,32 @@ impl Compiler {
                 break;
             }
             self.advance();
-            self.parse_infix(chunk);
+            self.parse_infix(chunk, can_assign);
         }
 
-        if can_assign && self.match_token(TokenKind::Equal) {
+        if can_assign
+            && (self.match_token(TokenKind::Equal) || self.compound_operator(true).is_some())
+        {
             self.error("Invalid assignment target.");
             // NOTE: I am not sure if this will be valid in all contexts
             self.advance();
         }
+
+        if self.increment_operator().is_some() {
+            self.error("Invalid increment target.");
+        }
     }
 
     // Adds an identifier constant to the chunk
-    fn identifier_constant(&mut self, chunk: &mut Chunk, token: Token) -> u8 {
+    fn identifier_constant(&mut self, token: Token) -> u8 {
         let lexeme = self.lexer.get_lexeme(&token).to_string();
 
-        self.make_constant(chunk, Value::String(Rc::from(lexeme)))
+        self.name_constant(&lexeme)
+    }
+
+    // Adds a name constant to the chunk
+    fn name_constant(&mut self, name: &str) -> u8 {
+        self.make_constant(Value::String(Rc::from(name)))
     }
 
     // Adds a local variable to scope
@@ -866,15 +2834,30 @@ impl Compiler {
         self.locals.declare(name);
     }
 
+    // Declares a variable that doesn't come from an identifier token
+    // Returns the name constant for globals
+    fn declare_named_variable(&mut self, name: &str) -> u8 {
+        if !self.is_scoped() {
+            self.check_global_redeclaration(name);
+            return self.name_constant(name);
+        }
+        if self.locals.contains(name) {
+            self.error("Variable with this name already declared in this scope.");
+        }
+        self.add_local(name.to_string());
+        0
+    }
+
     // Gets variable name and adds it to the scope
     fn declare_variable(&mut self) {
-        // Global, ignore
+        let name = self.lexer.get_lexeme(&self.parser.previous).to_string();
+
+        // Globals can be declared again, unless they are constant
         if !self.is_scoped() {
+            self.check_global_redeclaration(&name);
             return;
         }
 
-        let name = self.lexer.get_lexeme(&self.parser.previous).to_string();
-
         if self.locals.contains(&name) {
             self.error("Variable with this name already declared in this scope.");
         }
@@ -882,8 +2865,15 @@ impl Compiler {
         self.add_local(name);
     }
 
+    // Reports an error if the global is a constant, which can't be replaced by declaring it again
+    fn check_global_redeclaration(&mut self, name: &str) {
+        if self.module.is_constant(name) {
+            self.error("Can't redeclare a constant.");
+        }
+    }
+
     // Parses a variable expression and adds it to the scope and constants
-    fn parse_variable(&mut self, chunk: &mut Chunk, message: &str) -> u8 {
+    fn parse_variable(&mut self, message: &str) -> u8 {
         // Consume the identifier
 
         self.consume(TokenKind::Identifier, message);
@@ -895,7 +2885,7 @@ impl Compiler {
         }
 
         // Make identifier constant
-        self.identifier_constant(chunk, self.parser.previous)
+        self.identifier_constant(self.parser.previous)
     }
 
     // Marks a local as initialized
@@ -916,18 +2906,18 @@ impl Compiler {
         self.emit_bytes(chunk, opcode::OP_DEFINE_GLOBAL, global);
     }
 
-    // Parses an argument list and returns the number of arguments
-    fn argument_list(&mut self, chunk: &mut Chunk) -> u8 {
-        let mut argument_count = 0;
+    // Parses an argument list and returns the types of the arguments
+    fn argument_list(&mut self, chunk: &mut Chunk) -> Vec<Typed> {
+        let mut arguments = Vec::new();
 
         if !self.check(TokenKind::RightParen) {
             // Continue parsing argument expressions until we see no more commas
             loop {
                 self.expression(chunk);
-                if argument_count == 255 {
+                if arguments.len() == 255 {
                     self.error("Can't have more than 255 arguments.");
                 }
-                argument_count += 1;
+                arguments.push(self.last_type.clone());
                 if !self.match_token(TokenKind::Comma) {
                     break;
                 }
@@ -936,7 +2926,7 @@ impl Compiler {
 
         self.consume(TokenKind::RightParen, "Expect ')' after arguments.");
 
-        argument_count
+        arguments
     }
 
     // Compiles an 'and' statement
@@ -948,7 +2938,9 @@ impl Compiler {
         self.emit_byte(chunk, opcode::OP_POP);
 
         // Parse the right operand
+        let left = self.last_type.clone();
         self.parse_expression(chunk, Precedence::And);
+        self.last_type = self.either_type(&left);
 
         self.patch_jump(chunk, end_jump);
     }
@@ -967,33 +2959,199 @@ impl Compiler {
         self.emit_byte(chunk, opcode::OP_POP);
 
         // Parse the right operand
+        let left = self.last_type.clone();
         self.parse_expression(chunk, Precedence::Or);
+        self.last_type = self.either_type(&left);
+
+        self.patch_jump(chunk, end_jump);
+    }
+
+    // Compiles a conditional 'a ? b : c' expression
+    fn conditional(&mut self, chunk: &mut Chunk) {
+        // Jump to the else branch if the condition is falsey
+        let else_jump = self.emit_jump(chunk, opcode::OP_JUMP_IF_FALSE);
+
+        // Pop the condition and parse the then branch
+        self.emit_byte(chunk, opcode::OP_POP);
+        self.parse_expression(chunk, Precedence::Conditional);
+        let then_type = self.last_type.clone();
+        let end_jump = self.emit_jump(chunk, opcode::OP_JUMP);
+
+        self.consume(
+            TokenKind::Colon,
+            "Expect ':' after then branch of conditional.",
+        );
+
+        // Pop the condition and parse the else branch, it nests to the right
+        self.patch_jump(chunk, else_jump);
+        self.emit_byte(chunk, opcode::OP_POP);
+        self.parse_expression(chunk, Precedence::Conditional);
+        self.last_type = self.either_type(&then_type);
+
+        self.patch_jump(chunk, end_jump);
+    }
+
+    // Compiles a nil-coalescing 'a ?? b' expression
+    fn coalesce(&mut self, chunk: &mut Chunk) {
+        // Short circuit if the left operand is not nil
+        let end_jump = self.emit_jump(chunk, opcode::OP_JUMP_IF_NOT_NIL);
+
+        // Pop the nil and parse the right operand
+        let left = self.last_type.clone();
+        self.emit_byte(chunk, opcode::OP_POP);
+        self.parse_expression(chunk, Precedence::Coalesce);
+
+        // The left operand is only the result when it isn't nil
+        let left = Typed::new(left.ty.base().clone(), left.annotated);
+        self.last_type = self.either_type(&left);
 
         self.patch_jump(chunk, end_jump);
     }
+
+    // The type of an expression that results in either the given type or the last compiled one
+    fn either_type(&self, other: &Typed) -> Typed {
+        Typed::new(
+            Type::either(other.ty.base(), &self.last_type.ty),
+            other.annotated && self.last_type.annotated,
+        )
+    }
+}
+
+// A variable, property or index that can be assigned to
+#[derive(Clone, Copy)]
+enum AssignTarget {
+    Variable(u8, u8, u8), // The get and set opcodes and the variable operand
+    Constant(u8, u8),     // The get opcode and operand of a variable that can't be assigned
+    Property(u8),         // The name constant, the instance is on the stack
+    Index,                // The list or map and the index are on the stack
+}
+
+impl AssignTarget {
+    // Number of values on the stack that make up the target
+    fn depth(&self) -> u8 {
+        match self {
+            AssignTarget::Variable(..) | AssignTarget::Constant(..) => 0,
+            AssignTarget::Property(_) => 1,
+            AssignTarget::Index => 2,
+        }
+    }
+
+    // Size of the instruction reading the target
+    fn get_size(&self) -> usize {
+        match self {
+            AssignTarget::Variable(..) | AssignTarget::Constant(..) | AssignTarget::Property(_) => {
+                2
+            }
+            AssignTarget::Index => 1,
+        }
+    }
+}
+
+// State of a class declaration being compiled
+struct ClassState {
+    has_superclass: bool,
+}
+
+// State of a loop being compiled
+struct LoopState {
+    label: Option<String>,   // Optional label used to break out of nested loops
+    continue_target: usize,  // Address 'continue' jumps back to
+    scope_depth: usize, // Scope depth outside of the loop body, deeper locals are popped when jumping
+    try_depth: usize,   // Try statements outside of the loop, deeper ones are left when jumping
+    break_jumps: Vec<usize>, // Jumps to patch to the end of the loop
+}
+
+// State of a try statement being compiled
+struct TryState {
+    handler_active: bool, // False once the try block is done and no handler guards the catch block
+    finally: Option<FinallyState>, // Only for try statements with a finally block
+}
+
+// The finally block of a try statement, every way out of the statement runs it
+// What to do after the block is kept in the action slot, nil to carry on after the statement
+struct FinallyState {
+    scope_depth: usize,      // Scope depth of the action and value slots
+    action_slot: usize,      // 0 to rethrow the value, otherwise the position of the exit plus one
+    value_slot: usize,       // The exception or the return value
+    exits: Vec<Exit>,        // Exits out of the statement that continue after the block
+    entry_jumps: Vec<usize>, // Jumps to patch to the start of the block
+}
+
+// A way of jumping out of try statements
+#[derive(Clone, Copy, PartialEq)]
+enum Exit {
+    Return,          // The return value is on top of the stack
+    Break(usize),    // Out of the loop at the given index
+    Continue(usize), // With the loop at the given index
+}
+
+// State of the enclosing function, put aside while a nested function is compiled
+struct EnclosingFunction {
+    body_jump: usize,            // Jump over the nested function body
+    start_addr: usize,           // Address the nested function body starts at
+    function_type: FunctionType, // Type of the enclosing function
+    function: Function,          // The enclosing function being built
+    loops: Vec<LoopState>,       // Loops the nested function is declared in
+    tries: Vec<TryState>,        // Try statements the nested function is declared in
+    signature: Signature,        // The types of the enclosing function
 }
 
 #[repr(u8)]
 #[derive(PartialEq, PartialOrd)]
 enum Precedence {
     None,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > >= <=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
+    Assignment,  // =
+    Conditional, // ?:
+    Coalesce,    // ??
+    Or,          // or
+    And,         // and
+    Equality,    // == !=
+    Comparison,  // < > >= <=
+    Range,       // ..
+    BitOr,       // |
+    BitXor,      // ^
+    BitAnd,      // &
+    Shift,       // << >>
+    Term,        // + -
+    Factor,      // * /
+    Unary,       // ! - ~
+    Exponent,    // **
+    Call,        // . ()
+}
+
+impl Precedence {
+    // The next higher precedence, the right operand of a left associative operator is parsed with it
+    fn next(&self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Conditional,
+            Precedence::Conditional => Precedence::Coalesce,
+            Precedence::Coalesce => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Range,
+            Precedence::Range => Precedence::BitOr,
+            Precedence::BitOr => Precedence::BitXor,
+            Precedence::BitXor => Precedence::BitAnd,
+            Precedence::BitAnd => Precedence::Shift,
+            Precedence::Shift => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Exponent,
+            Precedence::Exponent | Precedence::Call => Precedence::Call,
+        }
+    }
 }
 
 // Retrieves the precedence of the current token
-impl<'a> From<TokenKind> for Precedence {
+impl From<TokenKind> for Precedence {
     fn from(kind: TokenKind) -> Self {
         match kind {
             TokenKind::Minus | TokenKind::Plus => Precedence::Term,
-            TokenKind::Slash | TokenKind::Star | TokenKind::Percent => Precedence::Factor,
+            TokenKind::Slash | TokenKind::Star | TokenKind::Percent | TokenKind::TildeSlash => {
+                Precedence::Factor
+            }
             TokenKind::BangEqual | TokenKind::EqualEqual => Precedence::Equality,
             TokenKind::Greater
             | TokenKind::GreaterEqual
@@ -1001,7 +3159,15 @@ impl<'a> From<TokenKind> for Precedence {
             | TokenKind::LessEqual => Precedence::Comparison,
             TokenKind::And => Precedence::And,
             TokenKind::Or => Precedence::Or,
-            TokenKind::LeftParen => Precedence::Call,
+            TokenKind::DotDot => Precedence::Range,
+            TokenKind::Pipe => Precedence::BitOr,
+            TokenKind::Caret => Precedence::BitXor,
+            TokenKind::Ampersand => Precedence::BitAnd,
+            TokenKind::LessLess | TokenKind::GreaterGreater => Precedence::Shift,
+            TokenKind::StarStar => Precedence::Exponent,
+            TokenKind::QuestionQuestion => Precedence::Coalesce,
+            TokenKind::Question => Precedence::Conditional,
+            TokenKind::LeftParen | TokenKind::Dot | TokenKind::LeftBracket => Precedence::Call,
             _ => Precedence::None,
         }
     }
@@ -0,0 +1,28 @@
+use std::rc::Rc;
+
+use super::value::closure::Closure;
+
+#[derive(Debug, PartialEq)]
+pub struct CallFrame {
+    pub closure: Rc<Closure>, // The closure being called
+    pub slot_offset: usize,   // The index of the first local slot in the call frame
+    pub return_addr: usize,   // The address to return to after executing this callframe
+}
+
+impl CallFrame {
+    pub fn new(closure: Rc<Closure>, slot_offset: usize, return_addr: usize) -> Self {
+        Self {
+            closure,
+            slot_offset,
+            return_addr,
+        }
+    }
+}
+
+// An active try block
+#[derive(Debug, PartialEq)]
+pub struct Handler {
+    pub catch_addr: usize,  // The address of the code handling the exception
+    pub frame_count: usize, // Call frames to keep when unwinding
+    pub stack_size: usize,  // Values to keep when unwinding
+}
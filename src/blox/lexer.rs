@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::rc::Rc;
+
 #[derive(Clone)]
 pub struct Lexer {
     pub source: String,
@@ -6,6 +8,9 @@ pub struct Lexer {
     pub current: usize,
     pub line: usize,
     keywords: HashMap<&'static str, TokenKind>,
+    interpolations: Vec<usize>, // Open braces inside each string interpolation that is being scanned
+    doc_lines: Vec<String>,     // Lines of the doc comment in front of the token being scanned
+    docs: HashMap<usize, Rc<str>>, // Doc comments by the start of the token following them
 }
 
 // TODO: move this
@@ -16,7 +21,7 @@ fn is_alpha(c: char) -> bool {
 
 // Check if the character is a digit
 fn is_digit(c: char) -> bool {
-    c.is_digit(10)
+    c.is_ascii_digit()
 }
 
 impl Lexer {
@@ -43,10 +48,23 @@ impl Lexer {
                 ("break", TokenKind::Break),
                 ("continue", TokenKind::Continue),
                 ("var", TokenKind::Var),
+                ("const", TokenKind::Const),
                 ("nil", TokenKind::Nil),
                 ("print", TokenKind::Print),
                 ("sleep", TokenKind::Sleep),
+                ("try", TokenKind::Try),
+                ("catch", TokenKind::Catch),
+                ("finally", TokenKind::Finally),
+                ("throw", TokenKind::Throw),
+                ("import", TokenKind::Import),
+                ("in", TokenKind::In),
+                ("yield", TokenKind::Yield),
+                ("match", TokenKind::Match),
+                ("enum", TokenKind::Enum),
             ]),
+            interpolations: Vec::new(),
+            doc_lines: Vec::new(),
+            docs: HashMap::new(),
         }
     }
 
@@ -54,18 +72,38 @@ impl Lexer {
         self.source = source;
     }
 
+    // Checks if the text would be scanned as a single identifier
+    pub fn is_identifier(&self, text: &str) -> bool {
+        let mut chars = text.chars();
+        chars.next().is_some_and(is_alpha)
+            && chars.all(|c| is_alpha(c) || is_digit(c))
+            && !self.keywords.contains_key(text)
+    }
+
     // Gets the lexeme for the token from the source
     pub fn get_lexeme(&self, token: &Token) -> &str {
         &self.source[token.start..token.start + token.length]
     }
 
+    // Gets the doc comment written right in front of the token
+    pub fn doc_comment(&self, token: &Token) -> Option<Rc<str>> {
+        self.docs.get(&token.start).cloned()
+    }
+
     // Gets the next token in the source
     pub fn scan_token(&mut self) -> Result<Token, LexerError> {
         // Advance to the next valid character
-        self.skip_whitespace();
+        self.skip_whitespace()?;
 
         self.start = self.current;
 
+        // Doc comments belong to the token after them
+        if !self.doc_lines.is_empty() {
+            let doc = self.doc_lines.join("\n");
+            self.docs.insert(self.start, Rc::from(doc));
+            self.doc_lines.clear();
+        }
+
         // Check if EOF
         if self.is_at_end() {
             return Ok(self.make_token(TokenKind::Eof));
@@ -73,21 +111,54 @@ impl Lexer {
 
         let c = self.advance();
 
+        // The brace closing an interpolated expression continues the string around it
+        if c == '}' && self.interpolations.last() == Some(&0) {
+            self.interpolations.pop();
+            return self.string();
+        }
+
         let kind_res = self.match_token(c);
         match kind_res {
             Ok(kind) => Ok(match kind {
-                TokenKind::String => match self.string() {
-                    Ok(t) => t,
-                    Err(e) => return Err(e),
-                },
+                TokenKind::String => self.string()?,
+                TokenKind::LeftBrace | TokenKind::RightBrace => self.brace(kind),
+                TokenKind::Identifier if c == 'r' && matches!(self.peek(), '"' | '#') => {
+                    self.raw_string()?
+                }
                 TokenKind::Identifier => self.identifier(),
-                TokenKind::Number => self.number(),
+                TokenKind::Number => self.number()?,
                 _ => self.make_token(kind),
             }),
             Err(err) => Err(err),
         }
     }
 
+    // Scans the next token without consuming it
+    pub fn peek_token(&mut self) -> Result<Token, LexerError> {
+        let state = self.state();
+        let token = self.scan_token();
+        self.restore(state);
+        token
+    }
+
+    // Saves the position of the lexer so it can be rewound with restore
+    pub fn state(&self) -> LexerState {
+        LexerState {
+            start: self.start,
+            current: self.current,
+            line: self.line,
+            interpolations: self.interpolations.clone(),
+        }
+    }
+
+    // Rewinds the lexer to a saved position
+    pub fn restore(&mut self, state: LexerState) {
+        self.start = state.start;
+        self.current = state.current;
+        self.line = state.line;
+        self.interpolations = state.interpolations;
+    }
+
     // Matches the current character to a token kind
     fn match_token(&mut self, c: char) -> Result<TokenKind, LexerError> {
         match c {
@@ -95,18 +166,37 @@ impl Lexer {
             ')' => Ok(TokenKind::RightParen),
             '{' => Ok(TokenKind::LeftBrace),
             '}' => Ok(TokenKind::RightBrace),
+            '[' => Ok(TokenKind::LeftBracket),
+            ']' => Ok(TokenKind::RightBracket),
             ';' => Ok(TokenKind::Semicolon),
+            ':' => Ok(TokenKind::Colon),
             ',' => Ok(TokenKind::Comma),
-            '.' => Ok(TokenKind::Dot),
-            '%' => Ok(TokenKind::Percent),
-            '-' => Ok(TokenKind::Minus),
-            '+' => Ok(TokenKind::Plus),
-            '/' => Ok(TokenKind::Slash),
-            '*' => Ok(TokenKind::Star),
+            '.' if self.peek() == '.' && self.peek_next() == '.' => {
+                self.advance();
+                self.advance();
+                Ok(TokenKind::DotDotDot)
+            }
+            '.' => Ok(self.match_either('.', TokenKind::DotDot, TokenKind::Dot)),
+            '~' => Ok(self.match_either('/', TokenKind::TildeSlash, TokenKind::Tilde)),
+            '&' => Ok(TokenKind::Ampersand),
+            '|' => Ok(TokenKind::Pipe),
+            '^' => Ok(TokenKind::Caret),
+            '?' => Ok(self.match_either('?', TokenKind::QuestionQuestion, TokenKind::Question)),
+            '%' => Ok(self.match_either('=', TokenKind::PercentEqual, TokenKind::Percent)),
+            '-' if self.match_char('-') => Ok(TokenKind::MinusMinus),
+            '-' => Ok(self.match_either('=', TokenKind::MinusEqual, TokenKind::Minus)),
+            '+' if self.match_char('+') => Ok(TokenKind::PlusPlus),
+            '+' => Ok(self.match_either('=', TokenKind::PlusEqual, TokenKind::Plus)),
+            '/' => Ok(self.match_either('=', TokenKind::SlashEqual, TokenKind::Slash)),
+            '*' if self.match_char('*') => Ok(TokenKind::StarStar),
+            '*' => Ok(self.match_either('=', TokenKind::StarEqual, TokenKind::Star)),
 
             '!' => Ok(self.match_either('=', TokenKind::BangEqual, TokenKind::Bang)),
+            '=' if self.match_char('>') => Ok(TokenKind::Arrow),
             '=' => Ok(self.match_either('=', TokenKind::EqualEqual, TokenKind::Equal)),
+            '<' if self.match_char('<') => Ok(TokenKind::LessLess),
             '<' => Ok(self.match_either('=', TokenKind::LessEqual, TokenKind::Less)),
+            '>' if self.match_char('>') => Ok(TokenKind::GreaterGreater),
             '>' => Ok(self.match_either('=', TokenKind::GreaterEqual, TokenKind::Greater)),
 
             '"' => Ok(TokenKind::String),
@@ -140,28 +230,60 @@ impl Lexer {
     }
 
     // Advances the current position past the comment
+    // Comments starting with exactly three slashes are doc comments, their text is kept
     fn skip_comment(&mut self) {
+        let start = self.current;
         self.advance();
         while self.peek() != '\n' && !self.is_at_end() {
             self.advance();
         }
+
+        let comment = &self.source[start..self.current];
+        if let Some(text) = comment.strip_prefix("///") {
+            if !text.starts_with('/') {
+                let text = text.strip_prefix(' ').unwrap_or(text).trim_end();
+                self.doc_lines.push(text.to_string());
+            }
+        }
+    }
+
+    // Advances the current position past a block comment, block comments can be nested
+    fn skip_block_comment(&mut self) -> Result<(), LexerError> {
+        let mut open = Token::new(TokenKind::Eof);
+        open.start = self.current;
+        open.length = 2;
+        open.line = self.line;
+
+        self.advance();
+        self.advance();
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return Err(LexerError::at("Unterminated block comment", open));
+            }
+            match self.advance() {
+                '\n' => self.line += 1,
+                '/' if self.match_char('*') => depth += 1,
+                '*' if self.match_char('/') => depth -= 1,
+                _ => (),
+            }
+        }
+        Ok(())
     }
 
-    // Advances the current position past whitespace
-    fn skip_whitespace(&mut self) {
+    // Advances the current position past whitespace and comments
+    fn skip_whitespace(&mut self) -> Result<(), LexerError> {
         loop {
             match self.peek() {
                 '\n' => {
                     self.line += 1;
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        self.skip_comment();
-                    } else {
-                        break;
-                    }
-                }
+                '/' => match self.peek_next() {
+                    '/' => self.skip_comment(),
+                    '*' => self.skip_block_comment()?,
+                    _ => break,
+                },
                 c => {
                     if c.is_whitespace() {
                         self.advance();
@@ -171,6 +293,7 @@ impl Lexer {
                 }
             }
         }
+        Ok(())
     }
 
     // Scans an identifier and returns the token
@@ -185,14 +308,46 @@ impl Lexer {
         }
     }
 
+    // Keeps track of braces inside interpolated expressions so the closing one can be found
+    fn brace(&mut self, kind: TokenKind) -> Token {
+        if let Some(depth) = self.interpolations.last_mut() {
+            match kind {
+                TokenKind::LeftBrace => *depth += 1,
+                _ => *depth -= 1,
+            }
+        }
+        self.make_token(kind)
+    }
+
     // Scans a string and returns the token
+    // A string containing '${' is split up, everything up to it becomes an interpolation token
+    // and the rest of the string is scanned after the embedded expression
     fn string(&mut self) -> Result<Token, LexerError> {
+        // An invalid escape is reported once the whole string is scanned, so scanning resumes after it
+        let mut error = None;
+
         // Opening quote
         while self.peek() != '"' && !self.is_at_end() {
             // Allow newlines inside strings
             if self.peek() == '\n' {
                 self.line += 1;
             }
+            if self.peek() == '\\' {
+                self.advance();
+                if let Err(err) = self.escape() {
+                    error.get_or_insert(err);
+                }
+                continue;
+            }
+            if self.peek() == '$' && self.peek_next() == '{' {
+                self.advance();
+                self.advance();
+                self.interpolations.push(0);
+                return match error {
+                    Some(err) => Err(err),
+                    None => Ok(self.make_token(TokenKind::Interpolation)),
+                };
+            }
             self.advance();
         }
 
@@ -203,40 +358,179 @@ impl Lexer {
         // Closing quote
         self.advance();
 
-        Ok(self.make_token(TokenKind::String))
+        match error {
+            Some(err) => Err(err),
+            None => Ok(self.make_token(TokenKind::String)),
+        }
+    }
+
+    // Checks the escape sequence after a backslash, the compiler replaces it later with unescape
+    fn escape(&mut self) -> Result<(), LexerError> {
+        if self.is_at_end() {
+            return Err(LexerError::new("Unterminated string", self.line));
+        }
+        match self.advance() {
+            'n' | 't' | 'r' | '0' | '"' | '\\' | '$' => Ok(()),
+            'u' => {
+                if !self.match_char('{') {
+                    return Err(LexerError::new("Expect '{' after '\\u'", self.line));
+                }
+                let start = self.current;
+                while self.peek().is_ascii_hexdigit() {
+                    self.advance();
+                }
+                let digits = &self.source[start..self.current];
+                let valid = (1..=6).contains(&digits.len())
+                    && u32::from_str_radix(digits, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .is_some();
+                if !self.match_char('}') || !valid {
+                    return Err(LexerError::new("Invalid unicode escape", self.line));
+                }
+                Ok(())
+            }
+            '\n' => {
+                self.line += 1;
+                Err(LexerError::new("Invalid escape sequence", self.line - 1))
+            }
+            _ => Err(LexerError::new("Invalid escape sequence", self.line)),
+        }
+    }
+
+    // Scans a raw string and returns the token
+    // Raw strings start with 'r', optionally followed by '#'s which have to be repeated after the closing quote.
+    // Nothing inside them is escaped or interpolated
+    fn raw_string(&mut self) -> Result<Token, LexerError> {
+        let mut hashes = 0;
+        while self.match_char('#') {
+            hashes += 1;
+        }
+        if !self.match_char('"') {
+            return Err(LexerError::new(
+                "Expect '\"' to start raw string",
+                self.line,
+            ));
+        }
+
+        loop {
+            if self.is_at_end() {
+                return Err(LexerError::new("Unterminated raw string", self.line));
+            }
+            match self.advance() {
+                '\n' => self.line += 1,
+                '"' if self.source[self.current..].starts_with(&"#".repeat(hashes)) => {
+                    self.current += hashes;
+                    return Ok(self.make_token(TokenKind::RawString));
+                }
+                _ => (),
+            }
+        }
     }
 
     // Scans a number and returns the token
-    fn number(&mut self) -> Token {
-        while is_digit(self.peek()) {
+    // Numbers are decimal with an optional fraction and exponent, or integers prefixed with 0x, 0b or 0o
+    fn number(&mut self) -> Result<Token, LexerError> {
+        let radix = match self.source[self.start..].get(..2) {
+            Some("0x" | "0X") => 16,
+            Some("0b" | "0B") => 2,
+            Some("0o" | "0O") => 8,
+            _ => 10,
+        };
+
+        if radix != 10 {
             self.advance();
+            if !self.digits(radix, false)? {
+                return Err(self.number_error(match radix {
+                    16 => "Expect hexadecimal digits after '0x'",
+                    8 => "Expect octal digits after '0o'",
+                    _ => "Expect binary digits after '0b'",
+                }));
+            }
+        } else {
+            self.digits(10, true)?;
+            if self.peek() == '.' && is_digit(self.peek_next()) {
+                self.advance();
+                self.digits(10, false)?;
+            }
+            if matches!(self.peek(), 'e' | 'E') {
+                self.advance();
+                if matches!(self.peek(), '+' | '-') {
+                    self.advance();
+                }
+                if !self.digits(10, false)? {
+                    return Err(self.number_error("Expect digits in exponent"));
+                }
+            }
         }
 
-        if self.peek() == '.' && is_digit(self.peek_next()) {
-            self.advance();
+        // A number can't run into letters, like a digit that is too large for the radix
+        if is_alpha(self.peek()) || is_digit(self.peek()) {
+            return Err(self.number_error(match radix {
+                16 => "Invalid digit in hexadecimal literal",
+                8 => "Invalid digit in octal literal",
+                2 => "Invalid digit in binary literal",
+                _ => "Invalid character in number literal",
+            }));
+        }
 
-            while is_digit(self.peek()) {
-                self.advance();
+        // The digits of the smallest integer are too large on their own, the compiler checks they are negated
+        let token = self.make_token(TokenKind::Number);
+        let lexeme = self.get_lexeme(&token);
+        match parse_number(lexeme) {
+            Ok(_) => Ok(token),
+            Err(_) if is_min_integer_magnitude(lexeme) => Ok(token),
+            Err(message) => Err(LexerError::at(message, token)),
+        }
+    }
+
+    // Scans the digits of a number in the given radix and returns false if there are none
+    // Digits can be separated by '_', after_digit is true if the number already has a digit before them
+    fn digits(&mut self, radix: u32, after_digit: bool) -> Result<bool, LexerError> {
+        let mut any = false;
+        let mut after_digit = after_digit;
+        loop {
+            let c = self.peek();
+            if c.is_digit(radix) {
+                after_digit = true;
+                any = true;
+            } else if c == '_' && after_digit && self.peek_next().is_digit(radix) {
+                after_digit = false;
+            } else if c == '_' {
+                return Err(self.number_error("Digit separator '_' must be between digits"));
+            } else {
+                return Ok(any);
             }
+            self.advance();
         }
+    }
 
-        self.make_token(TokenKind::Number)
+    // Skips the rest of an invalid number and creates an error spanning all of it
+    fn number_error(&mut self, message: &'static str) -> LexerError {
+        while is_alpha(self.peek()) || is_digit(self.peek()) {
+            self.advance();
+        }
+        LexerError::at(message, self.make_token(TokenKind::Number))
     }
 
-    // Gets the current character
+    // Gets the character starting at the given byte index
     fn get_char(&self, index: usize) -> char {
-        self.source
+        self.source[index..]
             .chars()
-            .nth(index)
+            .next()
             .expect("Failed to get character")
     }
 
     // Peek the next character
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
+        if self.is_at_end() {
+            return '\0';
+        }
+        let next = self.current + self.peek().len_utf8();
+        if next >= self.source.len() {
             return '\0';
         }
-        self.get_char(self.current + 1)
+        self.get_char(next)
     }
 
     // Peeks the current character
@@ -249,8 +543,9 @@ impl Lexer {
 
     // Advances the current position by one character and returns it
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.get_char(self.current - 1)
+        let c = self.get_char(self.current);
+        self.current += c.len_utf8();
+        c
     }
 
     // Checks if the current position is the expected character
@@ -263,7 +558,7 @@ impl Lexer {
             return false;
         }
 
-        self.current += 1;
+        self.current += expected.len_utf8();
         true
     }
 
@@ -272,13 +567,36 @@ impl Lexer {
         self.current >= self.source.len()
     }
 }
+// A saved position of the lexer
+#[derive(Clone)]
+pub struct LexerState {
+    start: usize,
+    current: usize,
+    line: usize,
+    interpolations: Vec<usize>,
+}
+
 pub struct LexerError {
     pub message: &'static str,
     pub line: usize,
+    pub token: Option<Token>, // The invalid text, if it is more precise than the current token
 }
 impl LexerError {
     pub fn new(message: &'static str, line: usize) -> Self {
-        Self { message, line }
+        Self {
+            message,
+            line,
+            token: None,
+        }
+    }
+
+    // An error for the text of the given token
+    pub fn at(message: &'static str, token: Token) -> Self {
+        Self {
+            message,
+            line: token.line,
+            token: Some(token),
+        }
     }
 }
 
@@ -308,14 +626,21 @@ pub enum TokenKind {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Percent,
     Minus,
     Plus,
     Semicolon,
+    Colon,
     Slash,
     Star,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
 
     // One or two character tokens
     Bang,
@@ -326,10 +651,28 @@ pub enum TokenKind {
     GreaterEqual,
     Less,
     LessEqual,
+    Arrow,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    PercentEqual,
+    PlusPlus,
+    MinusMinus,
+    Question,
+    QuestionQuestion,
+    DotDotDot,
+    DotDot,
+    TildeSlash,
+    LessLess,
+    GreaterGreater,
+    StarStar,
 
     // Literals
     Identifier,
     String,
+    Interpolation, // The part of a string before an embedded expression
+    RawString,
     Number,
 
     // Keywords
@@ -349,11 +692,97 @@ pub enum TokenKind {
     Break,
     Continue,
     Var,
+    Const,
     Nil, // Also literal
 
     Print,
     Sleep,
+    Try,
+    Catch,
+    Finally,
+    Throw,
+    Import,
+    In,
+    Yield,
+    Match,
+    Enum,
 
     Whitespace,
     Eof,
 }
+
+// The value of a number literal
+pub enum Number {
+    Integer(i64),
+    Float(f64), // Numbers with a fraction or exponent
+}
+
+// Converts a number literal to its value, the lexer has already checked its digits
+pub fn parse_number(text: &str) -> Result<Number, &'static str> {
+    let digits = text.replace('_', "");
+    let radix = match digits.get(..2) {
+        Some("0x" | "0X") => 16,
+        Some("0b" | "0B") => 2,
+        Some("0o" | "0O") => 8,
+        _ => 10,
+    };
+    if radix != 10 {
+        i64::from_str_radix(&digits[2..], radix)
+            .map(Number::Integer)
+            .map_err(|_| "Integer literal is too large")
+    } else if digits.contains(['.', 'e', 'E']) {
+        match digits.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Number::Float(n)),
+            _ => Err("Float literal is too large"),
+        }
+    } else {
+        digits
+            .parse()
+            .map(Number::Integer)
+            .map_err(|_| "Integer literal is too large")
+    }
+}
+
+// Checks if an integer literal is 2^63, which is only in range as the smallest integer after a minus
+pub fn is_min_integer_magnitude(text: &str) -> bool {
+    let digits = text.replace('_', "");
+    let magnitude = match digits.get(..2) {
+        Some("0x" | "0X") => u64::from_str_radix(&digits[2..], 16),
+        Some("0b" | "0B") => u64::from_str_radix(&digits[2..], 2),
+        Some("0o" | "0O") => u64::from_str_radix(&digits[2..], 8),
+        _ => digits.parse(),
+    };
+    magnitude == Ok(1 << 63)
+}
+
+// Replaces the escape sequences in a string with the characters they stand for
+// The lexer has already checked that every escape sequence is valid
+pub fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('0') => result.push('\0'),
+            Some('u') => {
+                let digits: String = chars
+                    .by_ref()
+                    .skip(1) // '{'
+                    .take_while(|&c| c != '}')
+                    .collect();
+                let code = u32::from_str_radix(&digits, 16).expect("Invalid unicode escape");
+                result.push(char::from_u32(code).expect("Invalid unicode escape"));
+            }
+            // Quotes, backslashes and dollar signs stand for themselves
+            Some(c) => result.push(c),
+            None => (),
+        }
+    }
+    result
+}
@@ -1,8 +1,11 @@
+use super::types::Typed;
+
 #[derive(Clone)]
 pub struct Locals {
     stack: Vec<Local>,
     locals_count: u8,
     scope_depth: usize,
+    upvalues: Vec<Upvalue>, // Variables captured from enclosing functions
 }
 
 impl Locals {
@@ -11,11 +14,15 @@ impl Locals {
             stack: vec![Local::new(); u8::MAX as usize],
             locals_count: 0,
             scope_depth: 0,
+            upvalues: Vec::new(),
         }
     }
     pub fn scope_depth(&self) -> usize {
         self.scope_depth
     }
+    pub fn len(&self) -> usize {
+        self.locals_count as usize
+    }
     pub fn is_full(&self) -> bool {
         self.locals_count == u8::MAX
     }
@@ -23,11 +30,12 @@ impl Locals {
         self.scope_depth += 1;
     }
 
-    // Returns the amount of locals removed from the stack.
-    pub fn end_scope(&mut self) -> usize {
+    // Removes the locals of the current scope.
+    // Returns whether each removed local was captured by a closure, starting with the top of the stack.
+    pub fn end_scope(&mut self) -> Vec<bool> {
         self.scope_depth -= 1;
 
-        let previous_count = self.locals_count;
+        let mut removed = Vec::new();
 
         // I would have loved to make this more functional, but I'm not sure how to do that with local arrays limited by locals_count.
         // it would have sacrificed performance
@@ -36,32 +44,62 @@ impl Locals {
             if local.depth <= self.scope_depth {
                 break;
             }
+            removed.push(local.captured);
             self.locals_count -= 1;
         }
-        (previous_count - self.locals_count) as usize
+        removed
+    }
+    // Returns whether each local declared deeper than the given scope depth was captured, starting with the top of the stack
+    // The locals are kept, this is used when jumping out of scopes that are still being compiled
+    pub fn captured_above(&self, depth: usize) -> Vec<bool> {
+        self.stack[..self.locals_count as usize]
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .map(|local| local.captured)
+            .collect()
     }
+
     // Declares a local variable
     pub fn declare(&mut self, name: String) {
         self.stack[self.locals_count as usize] = Local {
             name,
             depth: self.scope_depth,
             initialized: false,
+            captured: false,
+            binding: Binding::Variable,
+            ty: Typed::any(),
         };
         self.locals_count += 1;
     }
 
+    // Sets how the most recently declared local can be used
+    pub fn set_binding(&mut self, binding: Binding) {
+        self.stack[self.locals_count as usize - 1].binding = binding;
+    }
+    pub fn binding(&self, index: usize) -> Binding {
+        self.stack[index].binding
+    }
+    pub fn set_type(&mut self, index: usize, ty: Typed) {
+        self.stack[index].ty = ty;
+    }
+    pub fn local_type(&self, index: usize) -> Typed {
+        self.stack[index].ty.clone()
+    }
+
     // Marks the local variable as initialized
     pub fn define(&mut self) {
         self.stack[self.locals_count as usize - 1].initialized = true;
         self.stack[self.locals_count as usize - 1].depth = self.scope_depth;
     }
 
+    // Checks if a local with the given name is declared in the current scope
     pub fn contains(&self, name: &str) -> bool {
-        // TODO: Optimize, also limit to locals_count
-        self.stack
+        self.stack[..self.locals_count as usize]
             .iter()
             .rev()
-            .any(|local| local.depth == self.scope_depth && local.name == name)
+            .take_while(|local| local.depth >= self.scope_depth)
+            .any(|local| local.name == name)
     }
 
     // Returns the index of the first local variable with the given name, scanning from the top
@@ -76,6 +114,25 @@ impl Locals {
         None
     }
 
+    // Marks the local at the given index as captured by a closure
+    pub fn capture(&mut self, index: usize) {
+        self.stack[index].captured = true;
+    }
+
+    // Adds an upvalue if it doesn't exist yet and returns its index
+    pub fn add_upvalue(&mut self, index: u8, is_local: bool) -> usize {
+        let upvalue = Upvalue { index, is_local };
+        if let Some(existing) = self.upvalues.iter().position(|u| *u == upvalue) {
+            return existing;
+        }
+        self.upvalues.push(upvalue);
+        self.upvalues.len() - 1
+    }
+
+    pub fn upvalues(&self) -> &[Upvalue] {
+        &self.upvalues
+    }
+
     #[allow(dead_code)]
     // Prints the locals
     pub fn print(&self) {
@@ -91,6 +148,9 @@ pub struct Local {
     name: String,
     depth: usize,
     initialized: bool,
+    captured: bool, // If true the local is captured by a closure and has to be closed when it goes out of scope
+    binding: Binding,
+    ty: Typed, // The static type of the local, fixed if it was annotated
 }
 
 impl Local {
@@ -99,6 +159,24 @@ impl Local {
             name: String::new(),
             depth: 0,
             initialized: false,
+            captured: false,
+            binding: Binding::Variable,
+            ty: Typed::any(),
         }
     }
 }
+
+// How a variable can be used
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Binding {
+    Variable,    // Can be reassigned
+    Constant,    // Can't be reassigned
+    Literal(u8), // A constant with a literal value, reads use the constant at this index instead
+}
+
+// Describes where a closure finds a captured variable when it is created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Upvalue {
+    pub index: u8,      // The local slot or upvalue index in the enclosing function
+    pub is_local: bool, // True if it captures a local of the enclosing function, false if it captures one of its upvalues
+}
@@ -1,4 +1,5 @@
 mod chunk;
+mod frame;
 mod opcode;
 mod value;
 pub mod vm;
@@ -7,3 +8,4 @@ mod compiler;
 mod lexer;
 mod locals;
 mod parser;
+mod types;
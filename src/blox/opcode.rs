@@ -39,11 +39,33 @@ ops!(
     OP_TRUE,
     OP_FALSE,
     OP_POP,
+    OP_DUP,
+    OP_BURY,
     OP_GET_LOCAL,
     OP_SET_LOCAL,
     OP_GET_GLOBAL,
     OP_DEFINE_GLOBAL,
     OP_SET_GLOBAL,
+    OP_GET_UPVALUE,
+    OP_SET_UPVALUE,
+    OP_GET_PROPERTY,
+    OP_SET_PROPERTY,
+    OP_GET_SUPER,
+    OP_BUILD_LIST,
+    OP_BUILD_MAP,
+    OP_GET_INDEX,
+    OP_SET_INDEX,
+    OP_INTERPOLATE,
+    OP_TRY,
+    OP_END_TRY,
+    OP_THROW,
+    OP_IMPORT,
+    OP_END_IMPORT,
+    OP_RANGE,
+    OP_RANGE_STEP,
+    OP_GET_ITERATOR,
+    OP_ITERATE,
+    OP_YIELD,
     OP_EQUAL,
     OP_GREATER,
     OP_LESS,
@@ -52,14 +74,31 @@ ops!(
     OP_SUBTRACT,
     OP_MULTIPLY,
     OP_DIVIDE,
+    OP_INT_DIVIDE,
+    OP_POWER,
+    OP_BIT_AND,
+    OP_BIT_OR,
+    OP_BIT_XOR,
+    OP_SHIFT_LEFT,
+    OP_SHIFT_RIGHT,
     OP_NOT,
     OP_NEGATE,
+    OP_BIT_NOT,
     OP_PRINT,
+    OP_SLEEP,
     OP_JUMP_BACK,
     OP_JUMP,
     OP_JUMP_IF_FALSE,
+    OP_JUMP_IF_NOT_NIL,
     OP_CALL,
-    OP_RETURN
+    OP_INVOKE,
+    OP_SUPER_INVOKE,
+    OP_CLOSURE,
+    OP_CLOSE_UPVALUE,
+    OP_RETURN,
+    OP_CLASS,
+    OP_INHERIT,
+    OP_METHOD
 );
 
 /// Returns the name for the given opcode
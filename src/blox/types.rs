@@ -0,0 +1,257 @@
+use core::fmt;
+use std::rc::Rc;
+
+// The static type of a value, as far as the compiler can tell
+// Annotations are only checked while compiling, they don't change how the code runs
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Any, // Unknown, it fits everywhere
+    Nil,
+    Bool,
+    Int,
+    Float,
+    Number, // An int or a float
+    String,
+    List,
+    Map,
+    Range,
+    Function(Option<Rc<Signature>>), // A function, with its signature if it is known
+    Class(Rc<ClassType>),
+    Instance(Rc<ClassType>),
+    Enum(Rc<EnumType>),
+    Variant(Rc<EnumType>), // One of the values of the enum
+    Optional(Box<Type>),   // The type or nil
+}
+
+// The parameter and return types of a function
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub name: String,
+    pub parameters: Vec<Type>,
+    pub required: usize, // Parameters without a default value
+    pub variadic: bool,  // True if extra arguments are collected into a list
+    pub result: Type,    // The type of the returned value
+    pub annotated: bool, // True if any of the types come from annotations
+}
+
+// A class declared in the compiled code
+#[derive(Debug, PartialEq)]
+pub struct ClassType {
+    pub name: String,
+    pub superclass: Option<Type>, // The class it inherits from, Any if that isn't known
+}
+
+// An enum declared in the compiled code
+#[derive(Debug, PartialEq)]
+pub struct EnumType {
+    pub name: String,
+    pub variants: Vec<String>,
+}
+
+// The type of a compiled expression
+// Operators only report types that come from annotations, so unannotated code runs as it always did
+#[derive(Debug, Clone, PartialEq)]
+pub struct Typed {
+    pub ty: Type,
+    pub annotated: bool,
+}
+
+impl Type {
+    // Gets the type with the given name, classes and enums are looked up by the compiler
+    pub fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "any" => Some(Type::Any),
+            "nil" => Some(Type::Nil),
+            "bool" => Some(Type::Bool),
+            "int" => Some(Type::Int),
+            "float" => Some(Type::Float),
+            "number" => Some(Type::Number),
+            "string" => Some(Type::String),
+            "list" => Some(Type::List),
+            "map" => Some(Type::Map),
+            "range" => Some(Type::Range),
+            "fun" => Some(Type::Function(None)),
+            _ => None,
+        }
+    }
+
+    // The type without its optional marker
+    pub fn base(&self) -> &Type {
+        match self {
+            Type::Optional(ty) => ty.base(),
+            ty => ty,
+        }
+    }
+
+    // Checks if a value of this type can be stored where the target type is expected
+    // Only clear mismatches fail, a number fits an int because it could be one
+    // Optional values fit their base type, there is no narrowing to prove they aren't nil
+    pub fn fits(&self, target: &Type) -> bool {
+        match (self.base(), target) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Nil, Type::Optional(_)) => true,
+            (value, Type::Optional(target)) => value.fits(target),
+            (Type::Int | Type::Float | Type::Number, Type::Number) => true,
+            (Type::Number, Type::Int | Type::Float) => true,
+            (Type::Function(_), Type::Function(_)) => true,
+            (Type::Instance(class), Type::Instance(target)) => class.inherits(target),
+            (value, target) => value == target,
+        }
+    }
+
+    // Checks if the type could be a number
+    pub fn is_numeric(&self) -> bool {
+        matches!(
+            self.base(),
+            Type::Any | Type::Int | Type::Float | Type::Number
+        )
+    }
+
+    // The result of an arithmetic operation, integers stay integers and floats spread
+    pub fn arithmetic(a: &Type, b: &Type) -> Type {
+        match (a.base(), b.base()) {
+            (Type::Any, _) | (_, Type::Any) => Type::Any,
+            (Type::Int, Type::Int) => Type::Int,
+            (Type::Float, b) | (b, Type::Float) if b.is_numeric() => Type::Float,
+            (a, b) if a.is_numeric() && b.is_numeric() => Type::Number,
+            _ => Type::Any,
+        }
+    }
+
+    // The type of a value that is one of the two types
+    pub fn either(a: &Type, b: &Type) -> Type {
+        if a == b {
+            a.clone()
+        } else if *a == Type::Nil && *b != Type::Any {
+            Type::Optional(Box::new(b.clone()))
+        } else if *b == Type::Nil && *a != Type::Any {
+            Type::Optional(Box::new(a.clone()))
+        } else {
+            Type::Any
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Any => write!(f, "any"),
+            Type::Nil => write!(f, "nil"),
+            Type::Bool => write!(f, "bool"),
+            Type::Int => write!(f, "int"),
+            Type::Float => write!(f, "float"),
+            Type::Number => write!(f, "number"),
+            Type::String => write!(f, "string"),
+            Type::List => write!(f, "list"),
+            Type::Map => write!(f, "map"),
+            Type::Range => write!(f, "range"),
+            Type::Function(_) => write!(f, "fun"),
+            Type::Class(class) => write!(f, "class {}", class.name),
+            Type::Instance(class) => write!(f, "{}", class.name),
+            Type::Enum(enumeration) => write!(f, "enum {}", enumeration.name),
+            Type::Variant(enumeration) => write!(f, "{}", enumeration.name),
+            Type::Optional(ty) => write!(f, "{}?", ty),
+        }
+    }
+}
+
+impl Signature {
+    // A function without annotations, its parameters are added as they are compiled
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            parameters: Vec::new(),
+            required: 0,
+            variadic: false,
+            result: Type::Any,
+            annotated: false,
+        }
+    }
+
+    // Describes the number of arguments the function accepts, like Function::arity_range
+    pub fn arity_range(&self) -> String {
+        let total = self.parameters.len();
+        if self.variadic {
+            format!("at least {}", self.required)
+        } else if self.required < total {
+            format!("{} to {}", self.required, total)
+        } else {
+            total.to_string()
+        }
+    }
+
+    pub fn accepts(&self, argument_count: usize) -> bool {
+        argument_count >= self.required
+            && (self.variadic || argument_count <= self.parameters.len())
+    }
+}
+
+impl ClassType {
+    // Checks if the class is the target class or inherits from it
+    // A class with an unknown superclass could inherit from anything
+    fn inherits(&self, target: &ClassType) -> bool {
+        if self.name == target.name {
+            return true;
+        }
+        match &self.superclass {
+            Some(Type::Class(superclass)) => superclass.inherits(target),
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+impl Typed {
+    pub fn new(ty: Type, annotated: bool) -> Self {
+        Self { ty, annotated }
+    }
+
+    // An expression the compiler knows nothing about
+    pub fn any() -> Self {
+        Self::new(Type::Any, false)
+    }
+
+    // The type of a literal, it isn't checked in operators
+    pub fn literal(ty: Type) -> Self {
+        Self::new(ty, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fits() {
+        assert!(Type::Int.fits(&Type::Number));
+        assert!(Type::Number.fits(&Type::Int));
+        assert!(!Type::Int.fits(&Type::Float));
+        assert!(!Type::String.fits(&Type::Int));
+        assert!(Type::Any.fits(&Type::String));
+        assert!(Type::Nil.fits(&Type::Optional(Box::new(Type::String))));
+        assert!(!Type::Nil.fits(&Type::String));
+        assert!(Type::Optional(Box::new(Type::Int)).fits(&Type::Int));
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let animal = Rc::new(ClassType {
+            name: String::from("Animal"),
+            superclass: None,
+        });
+        let dog = Rc::new(ClassType {
+            name: String::from("Dog"),
+            superclass: Some(Type::Class(animal.clone())),
+        });
+        assert!(Type::Instance(dog.clone()).fits(&Type::Instance(animal.clone())));
+        assert!(!Type::Instance(animal).fits(&Type::Instance(dog)));
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(Type::arithmetic(&Type::Int, &Type::Int), Type::Int);
+        assert_eq!(Type::arithmetic(&Type::Int, &Type::Float), Type::Float);
+        assert_eq!(Type::arithmetic(&Type::Int, &Type::Number), Type::Number);
+        assert_eq!(Type::arithmetic(&Type::Int, &Type::Any), Type::Any);
+    }
+}
@@ -0,0 +1,105 @@
+use core::fmt;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::{closure::Closure, Value};
+
+pub struct Class {
+    name: Rc<str>,                          // name of the class
+    methods: HashMap<Rc<str>, Rc<Closure>>, // methods declared in the class body
+}
+
+impl Class {
+    pub fn new(name: Rc<str>) -> Self {
+        Self {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn method(&self, name: &str) -> Option<Rc<Closure>> {
+        self.methods.get(name).cloned()
+    }
+    pub fn add_method(&mut self, name: Rc<str>, method: Rc<Closure>) {
+        self.methods.insert(name, method);
+    }
+
+    // Copies all methods of the superclass into this class
+    // This happens before the class body adds its own methods, so overrides replace the inherited ones
+    // and a method lookup never has to walk up the class hierarchy
+    pub fn inherit(&mut self, superclass: &Class) {
+        for (name, method) in superclass.methods.iter() {
+            self.methods.insert(name.clone(), method.clone());
+        }
+    }
+}
+
+pub struct Instance {
+    class: Rc<RefCell<Class>>,       // class the instance was created from
+    fields: HashMap<Rc<str>, Value>, // fields set on the instance
+}
+
+impl Instance {
+    pub fn new(class: Rc<RefCell<Class>>) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+    pub fn class(&self) -> &Rc<RefCell<Class>> {
+        &self.class
+    }
+    pub fn field(&self, name: &str) -> Option<Value> {
+        self.fields.get(name).cloned()
+    }
+    pub fn set_field(&mut self, name: Rc<str>, value: Value) {
+        self.fields.insert(name, value);
+    }
+}
+
+// A method together with the instance it was accessed on
+#[derive(Debug, PartialEq)]
+pub struct BoundMethod {
+    receiver: Value,     // the instance 'this' refers to
+    method: Rc<Closure>, // the method to call
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: Rc<Closure>) -> Self {
+        Self { receiver, method }
+    }
+    pub fn receiver(&self) -> &Value {
+        &self.receiver
+    }
+    pub fn method(&self) -> &Rc<Closure> {
+        &self.method
+    }
+}
+
+// Classes and instances are compared by identity, their contents can refer back to themselves
+impl PartialEq for Class {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialEq for Instance {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Class {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<class {}>", self.name)
+    }
+}
+
+impl fmt::Debug for Instance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{} instance>", self.class.borrow().name())
+    }
+}
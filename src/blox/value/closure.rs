@@ -0,0 +1,39 @@
+use core::fmt;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::{function::Function, Value};
+
+// A captured variable
+// While the variable still lives on the value stack the upvalue points at its slot,
+// once it goes out of scope the value is moved into the upvalue itself
+#[derive(Debug, PartialEq, Clone)]
+pub enum Upvalue {
+    Open(usize),   // Absolute slot of the captured variable in the value stack
+    Closed(Value), // The captured value after the variable went out of scope
+}
+
+#[derive(PartialEq)]
+pub struct Closure {
+    function: Rc<Function>,              // The function being wrapped
+    upvalues: Vec<Rc<RefCell<Upvalue>>>, // The variables captured by the function
+}
+
+impl Closure {
+    pub fn new(function: Rc<Function>, upvalues: Vec<Rc<RefCell<Upvalue>>>) -> Self {
+        Self { function, upvalues }
+    }
+    pub fn function(&self) -> &Rc<Function> {
+        &self.function
+    }
+    pub fn upvalue(&self, index: usize) -> &Rc<RefCell<Upvalue>> {
+        &self.upvalues[index]
+    }
+}
+
+// A closure can capture itself, so its upvalues aren't formatted
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<closure {}>", self.function.name())
+    }
+}
@@ -0,0 +1,62 @@
+use core::fmt;
+use std::rc::Rc;
+
+// An enum declaration, a namespace holding each of its variants
+#[derive(Debug, PartialEq)]
+pub struct Enum {
+    name: Rc<str>,
+    variants: Vec<Rc<Variant>>, // in declaration order
+}
+
+// One of the values of an enum, variants are only equal to themselves
+#[derive(Debug, PartialEq)]
+pub struct Variant {
+    enum_name: Rc<str>,
+    name: Rc<str>,
+    index: usize, // position in the declaration
+}
+
+impl Enum {
+    pub fn new(name: &str, variants: &[String]) -> Self {
+        let name: Rc<str> = Rc::from(name);
+        let variants = variants
+            .iter()
+            .enumerate()
+            .map(|(index, variant)| {
+                Rc::new(Variant {
+                    enum_name: name.clone(),
+                    name: Rc::from(variant.as_str()),
+                    index,
+                })
+            })
+            .collect();
+        Self { name, variants }
+    }
+    pub fn name(&self) -> &Rc<str> {
+        &self.name
+    }
+    pub fn variants(&self) -> &[Rc<Variant>] {
+        &self.variants
+    }
+    pub fn variant(&self, name: &str) -> Option<Rc<Variant>> {
+        self.variants
+            .iter()
+            .find(|variant| &*variant.name == name)
+            .cloned()
+    }
+}
+
+impl Variant {
+    pub fn name(&self) -> &Rc<str> {
+        &self.name
+    }
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.enum_name, self.name)
+    }
+}
@@ -0,0 +1,23 @@
+use std::rc::Rc;
+
+// A built-in runtime error turned into a value so it can be caught
+#[derive(Debug, PartialEq)]
+pub struct Exception {
+    message: Rc<str>, // What went wrong
+    line: usize,      // The line the error happened on
+}
+
+impl Exception {
+    pub fn new(message: &str, line: usize) -> Self {
+        Self {
+            message: Rc::from(message),
+            line,
+        }
+    }
+    pub fn message(&self) -> &Rc<str> {
+        &self.message
+    }
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
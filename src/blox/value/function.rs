@@ -1,45 +1,108 @@
 use std::rc::Rc;
 
+use super::module::Module;
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Function {
     name: Rc<str>,        // name of the function
     arity: usize,         // number of arguments
+    optional: usize,      // number of trailing arguments with a default value
+    variadic: bool,       // true if extra arguments are collected into a list
+    generator: bool,      // true if the function yields, calling it creates a generator
     start_address: usize, // start address of the function
+    upvalue_count: usize, // number of variables captured from enclosing functions
+    module: Rc<Module>,   // module whose globals the function uses
+    doc: Option<Rc<str>>, // doc comment written in front of the declaration
 }
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum FunctionType {
     Function,
+    Method,
+    Initializer,
     Script,
 }
 
 impl Function {
-    pub fn new() -> Self {
+    pub fn new(module: Rc<Module>) -> Self {
         Self {
             name: Rc::from(""),
             arity: 0,
+            optional: 0,
+            variadic: false,
+            generator: false,
             start_address: 0,
+            upvalue_count: 0,
+            module,
+            doc: None,
         }
     }
     pub fn start_address(&self) -> usize {
         self.start_address
     }
-    pub fn set_arity(&mut self, arity: usize) {
-        self.arity = arity;
-    }
     pub fn inc_arity(&mut self) {
         self.arity += 1;
     }
+    pub fn inc_optional(&mut self) {
+        self.optional += 1;
+    }
+    pub fn set_variadic(&mut self) {
+        self.variadic = true;
+    }
+    pub fn set_generator(&mut self) {
+        self.generator = true;
+    }
     pub fn set_start_address(&mut self, address: usize) {
         self.start_address = address;
     }
     pub fn set_name(&mut self, name: String) {
         self.name = Rc::from(name);
     }
+    pub fn set_doc(&mut self, doc: Option<Rc<str>>) {
+        self.doc = doc;
+    }
+    pub fn set_upvalue_count(&mut self, count: usize) {
+        self.upvalue_count = count;
+    }
+    pub fn upvalue_count(&self) -> usize {
+        self.upvalue_count
+    }
     pub fn arity(&self) -> usize {
         self.arity
     }
+    pub fn optional(&self) -> usize {
+        self.optional
+    }
+    pub fn variadic(&self) -> bool {
+        self.variadic
+    }
+    pub fn generator(&self) -> bool {
+        self.generator
+    }
+    pub fn doc(&self) -> Option<Rc<str>> {
+        self.doc.clone()
+    }
+
+    // Checks if the function can be called with the given number of arguments
+    pub fn accepts(&self, arg_count: usize) -> bool {
+        arg_count >= self.arity - self.optional && (self.variadic || arg_count <= self.arity)
+    }
+
+    // Describes the number of arguments the function accepts
+    pub fn arity_range(&self) -> String {
+        let required = self.arity - self.optional;
+        if self.variadic {
+            format!("at least {}", required)
+        } else if self.optional > 0 {
+            format!("{} to {}", required, self.arity)
+        } else {
+            self.arity.to_string()
+        }
+    }
     pub fn name(&self) -> &str {
         &self.name
     }
+    pub fn module(&self) -> &Rc<Module> {
+        &self.module
+    }
 }
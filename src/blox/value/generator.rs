@@ -0,0 +1,78 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::{
+    closure::{Closure, Upvalue},
+    Value,
+};
+use crate::blox::frame::{CallFrame, Handler};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GeneratorState {
+    Created,   // The body hasn't started running yet
+    Suspended, // Paused at a yield
+    Running,   // The body is running on the VM stacks
+    Done,      // The body returned or was unwound by an exception
+}
+
+// The execution state of a generator while it is not running
+// Slots, frames and handlers are relative to the start of its stack segment
+#[derive(Debug, PartialEq, Default)]
+pub struct Context {
+    pub pc: usize,                                    // Where to continue running
+    pub stack: Vec<Value>,                            // The values of its call frames
+    pub frames: Vec<CallFrame>,                       // Its call frames, the function body first
+    pub handlers: Vec<Handler>,                       // Try blocks it is inside of
+    pub upvalues: Vec<(usize, Rc<RefCell<Upvalue>>)>, // Captured slots, closed while suspended
+}
+
+// A function call that can be paused at a yield and resumed later
+#[derive(Debug, PartialEq)]
+pub struct Generator {
+    closure: Rc<Closure>, // The generator function
+    state: GeneratorState,
+    context: Context,
+}
+
+impl Generator {
+    // Creates a generator for the closure, the arguments include the closure itself in slot 0
+    pub fn new(closure: Rc<Closure>, arguments: Vec<Value>) -> Self {
+        let context = Context {
+            pc: closure.function().start_address(),
+            stack: arguments,
+            frames: vec![CallFrame::new(closure.clone(), 0, 0)],
+            ..Context::default()
+        };
+        Self {
+            closure,
+            state: GeneratorState::Created,
+            context,
+        }
+    }
+    pub fn closure(&self) -> &Rc<Closure> {
+        &self.closure
+    }
+    pub fn state(&self) -> GeneratorState {
+        self.state
+    }
+    pub fn is_done(&self) -> bool {
+        self.state == GeneratorState::Done
+    }
+
+    // Hands out the saved state to run it, the generator is running until it is suspended again
+    pub fn resume(&mut self) -> Context {
+        self.state = GeneratorState::Running;
+        std::mem::take(&mut self.context)
+    }
+
+    // Saves the state of the generator when it yields
+    pub fn suspend(&mut self, context: Context) {
+        self.state = GeneratorState::Suspended;
+        self.context = context;
+    }
+
+    pub fn finish(&mut self) {
+        self.state = GeneratorState::Done;
+        self.context = Context::default();
+    }
+}
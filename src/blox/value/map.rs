@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::Value;
+
+// The hashable form of a value used as a map key
+// Only values that are compared by content can be keys, floats are stored by their bits
+// Enum variants are the exception, each one is unique
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HashKey {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(u64),
+    String(Rc<str>),
+    Variant(usize), // The address of the variant
+}
+
+impl HashKey {
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        match value {
+            Value::Nil => Ok(HashKey::Nil),
+            Value::Boolean(b) => Ok(HashKey::Boolean(*b)),
+            // NaN is never equal to itself, so it could never be looked up again
+            Value::Number(n) if n.is_nan() => Err(String::from("Map key can't be NaN.")),
+            // Floats equal to an integer have to hash the same as it, this includes 0 and -0
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => {
+                Ok(HashKey::Integer(*n as i64))
+            }
+            Value::Number(n) => Ok(HashKey::Number(n.to_bits())),
+            Value::Integer(n) => Ok(HashKey::Integer(*n)),
+            Value::String(s) => Ok(HashKey::String(s.clone())),
+            Value::Variant(variant) => Ok(HashKey::Variant(Rc::as_ptr(variant) as usize)),
+            value => Err(format!("Can't use {} as a map key.", value)),
+        }
+    }
+}
+
+// A mutable key/value map which remembers the order keys were first inserted in
+#[derive(Debug, PartialEq, Default)]
+pub struct Map {
+    entries: Vec<(Value, Value)>,     // key/value pairs in insertion order
+    indices: HashMap<HashKey, usize>, // position of each key in entries
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn entries(&self) -> &[(Value, Value)] {
+        &self.entries
+    }
+    pub fn keys(&self) -> Vec<Value> {
+        self.entries.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    pub fn get(&self, key: &Value) -> Result<Option<Value>, String> {
+        let key = HashKey::from_value(key)?;
+        Ok(self
+            .indices
+            .get(&key)
+            .map(|&index| self.entries[index].1.clone()))
+    }
+
+    pub fn contains(&self, key: &Value) -> Result<bool, String> {
+        Ok(self.indices.contains_key(&HashKey::from_value(key)?))
+    }
+
+    // Sets the value of a key, an existing key keeps its position
+    pub fn insert(&mut self, key: Value, value: Value) -> Result<(), String> {
+        let hash_key = HashKey::from_value(&key)?;
+        match self.indices.get(&hash_key) {
+            Some(&index) => self.entries[index].1 = value,
+            None => {
+                self.indices.insert(hash_key, self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    // Removes a key and returns its value if it was in the map
+    pub fn remove(&mut self, key: &Value) -> Result<Option<Value>, String> {
+        let index = match self.indices.remove(&HashKey::from_value(key)?) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let (_, value) = self.entries.remove(index);
+
+        // Everything after the removed entry moved one step down
+        for position in self.indices.values_mut() {
+            if *position > index {
+                *position -= 1;
+            }
+        }
+        Ok(Some(value))
+    }
+}
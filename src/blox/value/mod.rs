@@ -1,25 +1,52 @@
+pub mod class;
+pub mod closure;
+pub mod enumeration;
+pub mod exception;
 pub mod function;
+pub mod generator;
+pub mod map;
+pub mod module;
 pub mod native_function;
+pub mod range;
 pub mod value_array;
 
-use self::{function::Function, native_function::NativeFunction};
+use self::{
+    class::{BoundMethod, Class, Instance},
+    closure::Closure,
+    enumeration::{Enum, Variant},
+    exception::Exception,
+    function::Function,
+    generator::Generator,
+    map::Map,
+    module::Module,
+    native_function::NativeFunction,
+    range::Range,
+};
 use core::fmt;
-use std::{rc::Rc, str::FromStr};
+use std::{cell::RefCell, rc::Rc, str::FromStr};
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Clone, Default)]
 pub enum Value {
     Boolean(bool),
+    #[default]
     Nil,
     Number(f64),
+    Integer(i64),
     String(Rc<str>),
     Function(Rc<Function>),
+    Closure(Rc<Closure>),
     NativeFunction(Rc<NativeFunction>),
-}
-
-impl Default for Value {
-    fn default() -> Self {
-        Self::Nil
-    }
+    Class(Rc<RefCell<Class>>),
+    Instance(Rc<RefCell<Instance>>),
+    BoundMethod(Rc<BoundMethod>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<Map>>),
+    Exception(Rc<Exception>),
+    Module(Rc<Module>),
+    Range(Rc<Range>),
+    Generator(Rc<RefCell<Generator>>),
+    Enum(Rc<Enum>),
+    Variant(Rc<Variant>),
 }
 
 impl Value {
@@ -38,10 +65,32 @@ impl Value {
             (Value::Boolean(a), Value::Boolean(b)) => a == b,
             (Value::Nil, Value::Nil) => true,
             (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Integer(a), Value::Number(b)) | (Value::Number(b), Value::Integer(a)) => {
+                a as f64 == b
+            }
             (Value::String(a), Value::String(b)) => a == b,
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(&a, &b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(&a, &b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(&a, &b),
+            (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(&a, &b),
+            (Value::Module(a), Value::Module(b)) => Rc::ptr_eq(&a, &b),
+            (Value::Range(a), Value::Range(b)) => a == b,
+            (Value::Generator(a), Value::Generator(b)) => Rc::ptr_eq(&a, &b),
+            (Value::Enum(a), Value::Enum(b)) => Rc::ptr_eq(&a, &b),
+            (Value::Variant(a), Value::Variant(b)) => Rc::ptr_eq(&a, &b),
             _ => false,
         }
     }
+
+    // Gets the value of a number as a float, integers are converted
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Integer(n) => Some(*n as f64),
+            _ => None,
+        }
+    }
 }
 
 pub trait Printer {
@@ -60,7 +109,9 @@ impl fmt::Display for Value {
         match self {
             Value::Boolean(b) => write!(f, "{}", b),
             Value::Nil => write!(f, "nil"),
-            Value::Number(n) => write!(f, "{}", n),
+            // Floats always show a fraction or exponent to tell them apart from integers
+            Value::Number(n) => write!(f, "{:?}", n),
+            Value::Integer(n) => write!(f, "{}", n),
             Value::String(s) => write!(f, "{}", s),
             Value::Function(fun) => {
                 // If it's empty it's a script
@@ -70,9 +121,122 @@ impl fmt::Display for Value {
                     write!(f, "<fun '{}'>", fun.name())
                 }
             }
+            Value::Closure(closure) => write!(f, "{}", Value::Function(closure.function().clone())),
             Value::NativeFunction(fun) => {
                 write!(f, "<native fun '{}'>", fun.name())
             }
+            Value::Class(class) => write!(f, "<class '{}'>", class.borrow().name()),
+            Value::Instance(instance) => {
+                write!(
+                    f,
+                    "<{} instance>",
+                    instance.borrow().class().borrow().name()
+                )
+            }
+            Value::BoundMethod(bound) => {
+                write!(f, "{}", Value::Closure(bound.method().clone()))
+            }
+            Value::List(list) => fmt_once(Rc::as_ptr(list) as usize, "[...]", f, |f| {
+                write!(f, "[")?;
+                for (i, value) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    value.fmt_element(f)?;
+                }
+                write!(f, "]")
+            }),
+            Value::Exception(exception) => write!(f, "Error: {}", exception.message()),
+            Value::Module(module) => write!(f, "<module '{}'>", module.name()),
+            Value::Range(range) => write!(f, "{}", range),
+            Value::Generator(generator) => write!(
+                f,
+                "<generator '{}'>",
+                generator.borrow().closure().function().name()
+            ),
+            Value::Enum(enumeration) => write!(f, "<enum '{}'>", enumeration.name()),
+            Value::Variant(variant) => write!(f, "{}", variant),
+            Value::Map(map) => fmt_once(Rc::as_ptr(map) as usize, "{...}", f, |f| {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.borrow().entries().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    key.fmt_element(f)?;
+                    write!(f, ": ")?;
+                    value.fmt_element(f)?;
+                }
+                write!(f, "}}")
+            }),
+        }
+    }
+}
+
+// Implements Debug for Value, the same as a derived one except for lists and maps that contain themselves
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Boolean(b) => f.debug_tuple("Boolean").field(b).finish(),
+            Value::Nil => write!(f, "Nil"),
+            Value::Number(n) => f.debug_tuple("Number").field(n).finish(),
+            Value::Integer(n) => f.debug_tuple("Integer").field(n).finish(),
+            Value::String(s) => f.debug_tuple("String").field(s).finish(),
+            Value::Function(fun) => f.debug_tuple("Function").field(fun).finish(),
+            Value::Closure(closure) => f.debug_tuple("Closure").field(closure).finish(),
+            Value::NativeFunction(fun) => f.debug_tuple("NativeFunction").field(fun).finish(),
+            Value::Class(class) => f.debug_tuple("Class").field(class).finish(),
+            Value::Instance(instance) => f.debug_tuple("Instance").field(instance).finish(),
+            Value::BoundMethod(bound) => f.debug_tuple("BoundMethod").field(bound).finish(),
+            Value::List(list) => fmt_once(Rc::as_ptr(list) as usize, "[...]", f, |f| {
+                f.debug_tuple("List").field(list).finish()
+            }),
+            Value::Map(map) => fmt_once(Rc::as_ptr(map) as usize, "{...}", f, |f| {
+                f.debug_tuple("Map").field(map).finish()
+            }),
+            Value::Exception(exception) => f.debug_tuple("Exception").field(exception).finish(),
+            Value::Module(module) => f.debug_tuple("Module").field(module).finish(),
+            Value::Range(range) => f.debug_tuple("Range").field(range).finish(),
+            Value::Generator(generator) => f.debug_tuple("Generator").field(generator).finish(),
+            Value::Enum(enumeration) => f.debug_tuple("Enum").field(enumeration).finish(),
+            Value::Variant(variant) => f.debug_tuple("Variant").field(variant).finish(),
+        }
+    }
+}
+
+thread_local! {
+    // Addresses of the collections being formatted, to find ones that contain themselves
+    static FORMATTING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+// Formats a collection, or writes the placeholder if it is already being formatted further up
+fn fmt_once(
+    address: usize,
+    placeholder: &str,
+    f: &mut fmt::Formatter,
+    format: impl FnOnce(&mut fmt::Formatter) -> fmt::Result,
+) -> fmt::Result {
+    let recurses = FORMATTING.with(|formatting| {
+        let mut formatting = formatting.borrow_mut();
+        let recurses = formatting.contains(&address);
+        if !recurses {
+            formatting.push(address);
+        }
+        recurses
+    });
+    if recurses {
+        return write!(f, "{}", placeholder);
+    }
+    let result = format(f);
+    FORMATTING.with(|formatting| formatting.borrow_mut().pop());
+    result
+}
+
+impl Value {
+    // Formats the value as an element of a collection, strings are quoted to tell them apart
+    fn fmt_element(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "\"{}\"", s),
+            value => write!(f, "{}", value),
         }
     }
 }
@@ -85,9 +249,10 @@ impl FromStr for Value {
             "true" => Ok(Value::Boolean(true)),
             "false" => Ok(Value::Boolean(false)),
             "nil" => Ok(Value::Nil),
-            s => Ok(match s.parse::<f64>() {
-                Ok(n) => Value::Number(n),
-                Err(_) => Value::String(Rc::from(s)),
+            s => Ok(match (s.parse::<i64>(), s.parse::<f64>()) {
+                (Ok(n), _) => Value::Integer(n),
+                (_, Ok(n)) => Value::Number(n),
+                _ => Value::String(Rc::from(s)),
             }),
         }
     }
@@ -106,9 +271,9 @@ mod tests {
 
     #[test]
     fn test_is_falsey() {
-        assert_eq!(Value::Boolean(true).is_falsy(), false);
-        assert_eq!(Value::Boolean(false).is_falsy(), true);
-        assert_eq!(Value::Nil.is_falsy(), true);
-        assert_eq!(Value::Number(1.0).is_falsy(), false);
+        assert!(!Value::Boolean(true).is_falsy());
+        assert!(Value::Boolean(false).is_falsy());
+        assert!(Value::Nil.is_falsy());
+        assert!(!Value::Number(1.0).is_falsy());
     }
 }
@@ -0,0 +1,130 @@
+use core::fmt;
+use std::cell::{Ref, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use super::{value_array::ValueArray, Value};
+use crate::blox::types::Typed;
+
+// A script file with its own global variables
+// Functions remember the module they were compiled in and use its globals
+pub struct Module {
+    name: Rc<str>,         // name of the module, the file stem for imported modules
+    path: Option<PathBuf>, // file the module was loaded from, if any
+    globals: RefCell<BTreeMap<Rc<str>, Value>>, // global variables defined by the module
+    constants: RefCell<BTreeMap<Rc<str>, Option<u8>>>, // globals that can't be reassigned, with the constant index of a literal value
+    types: RefCell<BTreeMap<Rc<str>, Typed>>, // static types of globals known to the compiler, others are any
+    untyped: RefCell<BTreeSet<Rc<str>>>, // globals assigned values of another type, which are never inferred
+    docs: RefCell<BTreeMap<Rc<str>, Rc<str>>>, // doc comments of the declared globals that have one
+    values: RefCell<ValueArray>, // constants of the code compiled for the module, every module has its own
+}
+
+impl Module {
+    pub fn new(name: &str, path: Option<PathBuf>) -> Self {
+        Self {
+            name: Rc::from(name),
+            path,
+            globals: RefCell::new(BTreeMap::new()),
+            constants: RefCell::new(BTreeMap::new()),
+            types: RefCell::new(BTreeMap::new()),
+            untyped: RefCell::new(BTreeSet::new()),
+            docs: RefCell::new(BTreeMap::new()),
+            values: RefCell::new(ValueArray::new()),
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.globals.borrow().get(name).cloned()
+    }
+    pub fn define_global(&self, name: Rc<str>, value: Value) {
+        self.globals.borrow_mut().insert(name, value);
+    }
+
+    // Adds a constant for the code of the module and returns its index
+    pub fn add_constant(&self, value: Value) -> usize {
+        self.values.borrow_mut().add_constant(value)
+    }
+    pub fn constant(&self, index: usize) -> Value {
+        self.values.borrow().get_value(index)
+    }
+    pub fn constants(&self) -> Ref<'_, ValueArray> {
+        self.values.borrow()
+    }
+
+    // Marks a global as constant, a literal value is folded into the code reading it
+    pub fn define_constant(&self, name: Rc<str>, literal: Option<u8>) {
+        self.constants.borrow_mut().insert(name, literal);
+    }
+    pub fn is_constant(&self, name: &str) -> bool {
+        self.constants.borrow().contains_key(name)
+    }
+    pub fn literal(&self, name: &str) -> Option<u8> {
+        self.constants.borrow().get(name).copied().flatten()
+    }
+
+    // The static type of a global, kept between compilations of code in the module
+    pub fn global_type(&self, name: &str) -> Option<Typed> {
+        self.types.borrow().get(name).cloned()
+    }
+    pub fn set_global_type(&self, name: &str, ty: Option<Typed>) {
+        match ty {
+            Some(ty) => self.types.borrow_mut().insert(Rc::from(name), ty),
+            None => self.types.borrow_mut().remove(name),
+        };
+    }
+
+    // Drops the type of a global that was assigned a value of another type, even before its declaration
+    pub fn forget_global_type(&self, name: &str) {
+        self.types.borrow_mut().remove(name);
+        self.untyped.borrow_mut().insert(Rc::from(name));
+    }
+    pub fn is_untyped(&self, name: &str) -> bool {
+        self.untyped.borrow().contains(name)
+    }
+
+    // Sets the doc comment of a global, declaring it again without one removes it
+    pub fn set_doc(&self, name: &str, doc: Option<Rc<str>>) {
+        match doc {
+            Some(doc) => self.docs.borrow_mut().insert(Rc::from(name), doc),
+            None => self.docs.borrow_mut().remove(name),
+        };
+    }
+    // Gets the documented globals in order of their names
+    pub fn docs(&self) -> Vec<(Rc<str>, Rc<str>)> {
+        self.docs
+            .borrow()
+            .iter()
+            .map(|(name, doc)| (name.clone(), doc.clone()))
+            .collect()
+    }
+
+    // Sets an existing global, returns false if it isn't defined
+    pub fn set_global(&self, name: &str, value: Value) -> bool {
+        match self.globals.borrow_mut().get_mut(name) {
+            Some(global) => {
+                *global = value;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Modules are compared by identity, their globals can refer back to themselves
+impl PartialEq for Module {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<module {}>", self.name)
+    }
+}
@@ -1,18 +1,21 @@
 use core::fmt;
+use std::cell::RefCell;
 use std::rc::Rc;
-use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use super::Value;
+use super::{map::Map, range::Range, Value};
+
+// Native functions return an error message on failure, which the VM reports as a runtime error
+pub type NativeResult = Result<Value, String>;
 
 #[derive(Clone)]
 pub struct NativeFunction {
     name: Rc<str>,
-    function: fn(&[Value]) -> Value,
+    function: fn(&[Value]) -> NativeResult,
 }
 
 impl NativeFunction {
-    pub fn new(name: &str, function: fn(&[Value]) -> Value) -> Self {
+    pub fn new(name: &str, function: fn(&[Value]) -> NativeResult) -> Self {
         Self {
             name: Rc::from(name),
             function,
@@ -21,7 +24,7 @@ impl NativeFunction {
     pub fn name(&self) -> &str {
         &self.name
     }
-    pub fn call(&self, args: &[Value]) -> Value {
+    pub fn call(&self, args: &[Value]) -> NativeResult {
         (self.function)(args)
     }
 }
@@ -38,50 +41,261 @@ impl fmt::Debug for NativeFunction {
     }
 }
 
-// TODO: These should really be able to report errors to the VM
-// Examples are invalid arity or invalid types
+// Checks that the native function got the expected number of arguments
+fn check_arity(args: &[Value], arity: usize) -> Result<(), String> {
+    if args.len() != arity {
+        return Err(format!(
+            "Expected {} arguments, but got {}.",
+            arity,
+            args.len()
+        ));
+    }
+    Ok(())
+}
+
+// Gets the list argument at the given position
+fn list_arg(args: &[Value], index: usize) -> Result<Rc<RefCell<Vec<Value>>>, String> {
+    match &args[index] {
+        Value::List(list) => Ok(list.clone()),
+        value => Err(format!("Expected a list. Got {:?}", value)),
+    }
+}
+
+// Gets the map argument at the given position
+fn map_arg(args: &[Value], index: usize) -> Result<Rc<RefCell<Map>>, String> {
+    match &args[index] {
+        Value::Map(map) => Ok(map.clone()),
+        value => Err(format!("Expected a map. Got {:?}", value)),
+    }
+}
+
+// Converts a value into a list index, the index has to be less than `len`
+pub fn list_index(index: &Value, len: usize) -> Result<usize, String> {
+    match index {
+        Value::Integer(n) if *n >= 0 && (*n as usize) < len => Ok(*n as usize),
+        Value::Integer(n) => Err(format!(
+            "List index out of range. Got {} for a list of length {}",
+            n, len
+        )),
+        value => Err(format!("List index must be an integer. Got {:?}", value)),
+    }
+}
 
 // Native clock function
-pub fn clock(_: &[Value]) -> Value {
+pub fn clock(args: &[Value]) -> NativeResult {
+    check_arity(args, 0)?;
     let start = SystemTime::now();
     let since_epoch = start
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
 
     // Return the number of seconds since the UNIX epoch with *some* accuracy
-    Value::Number(since_epoch.as_nanos() as f64 / 1_000_000_000.0)
+    Ok(Value::Number(
+        since_epoch.as_nanos() as f64 / 1_000_000_000.0,
+    ))
 }
 
-pub fn read_line(_: &[Value]) -> Value {
+pub fn read_line(args: &[Value]) -> NativeResult {
+    check_arity(args, 0)?;
     let mut input = String::new();
     std::io::stdin()
         .read_line(&mut input)
         .expect("Failed to read input");
-    Value::String(Rc::from(input))
+    Ok(Value::String(Rc::from(input)))
+}
+
+// Converts a string to a number, it is an integer if it has no fraction or exponent
+pub fn to_number(args: &[Value]) -> NativeResult {
+    check_arity(args, 1)?;
+    match &args[0] {
+        Value::String(s) => match (s.trim().parse(), s.trim().parse()) {
+            (Ok(n), _) => Ok(Value::Integer(n)),
+            (_, Ok(n)) => Ok(Value::Number(n)),
+            _ => Err(format!("Can't convert '{}' to a number.", s)),
+        },
+        Value::Number(_) | Value::Integer(_) => Ok(args[0].clone()),
+        value => Err(format!("Expected a string. Got {:?}", value)),
+    }
 }
 
-pub fn to_number(args: &[Value]) -> Value {
-    if let Value::String(s) = args[0].clone() {
-        Value::Number(s.parse().unwrap())
+// Converts a number or string to an integer, floats are truncated towards zero
+pub fn to_integer(args: &[Value]) -> NativeResult {
+    check_arity(args, 1)?;
+    let float = match &args[0] {
+        Value::Integer(n) => return Ok(Value::Integer(*n)),
+        Value::Number(n) => *n,
+        Value::String(s) => match (s.trim().parse(), s.trim().parse::<f64>()) {
+            (Ok(n), _) => return Ok(Value::Integer(n)),
+            (_, Ok(n)) => n,
+            _ => return Err(format!("Can't convert '{}' to an integer.", s)),
+        },
+        value => return Err(format!("Expected a number or string. Got {:?}", value)),
+    };
+
+    // i64::MAX as f64 rounds up to 2^63, which is out of range
+    let truncated = float.trunc();
+    if truncated >= i64::MIN as f64 && truncated < i64::MAX as f64 {
+        Ok(Value::Integer(truncated as i64))
     } else {
-        panic!("to_number: expected string")
+        Err(format!("Can't convert {:?} to an integer.", float))
     }
 }
 
+// Converts a number or string to a float
+pub fn to_float(args: &[Value]) -> NativeResult {
+    check_arity(args, 1)?;
+    match &args[0] {
+        Value::String(s) => match s.trim().parse() {
+            Ok(n) => Ok(Value::Number(n)),
+            Err(_) => Err(format!("Can't convert '{}' to a float.", s)),
+        },
+        value => match value.as_float() {
+            Some(n) => Ok(Value::Number(n)),
+            None => Err(format!("Expected a number or string. Got {:?}", value)),
+        },
+    }
+}
+
+// Returns the number of elements in a list, entries in a map or characters in a string
+pub fn len(args: &[Value]) -> NativeResult {
+    check_arity(args, 1)?;
+    match &args[0] {
+        Value::List(list) => Ok(Value::Integer(list.borrow().len() as i64)),
+        Value::Map(map) => Ok(Value::Integer(map.borrow().len() as i64)),
+        Value::String(s) => Ok(Value::Integer(s.chars().count() as i64)),
+        Value::Range(range) => match i64::try_from(range.len()) {
+            Ok(len) => Ok(Value::Integer(len)),
+            Err(_) => Err(String::from("Range is too long to get its length.")),
+        },
+        Value::Enum(enumeration) => Ok(Value::Integer(enumeration.variants().len() as i64)),
+        value => Err(format!("Can't get the length of {:?}", value)),
+    }
+}
+
+// Adds a value to the end of a list
+pub fn append(args: &[Value]) -> NativeResult {
+    check_arity(args, 2)?;
+    list_arg(args, 0)?.borrow_mut().push(args[1].clone());
+    Ok(Value::Nil)
+}
+
+// Removes the last value of a list and returns it
+pub fn pop(args: &[Value]) -> NativeResult {
+    check_arity(args, 1)?;
+    match list_arg(args, 0)?.borrow_mut().pop() {
+        Some(value) => Ok(value),
+        None => Err(String::from("Can't pop from an empty list.")),
+    }
+}
+
+// Inserts a value into a list before the given index
+pub fn insert(args: &[Value]) -> NativeResult {
+    check_arity(args, 3)?;
+    let list = list_arg(args, 0)?;
+    let len = list.borrow().len();
+    // Inserting at the length appends, errors still report the real length
+    let index = match &args[1] {
+        Value::Integer(n) if *n >= 0 && *n as usize == len => len,
+        index => list_index(index, len)?,
+    };
+    list.borrow_mut().insert(index, args[2].clone());
+    Ok(Value::Nil)
+}
+
+// Removes the value at the given index of a list or the given key of a map and returns it
+pub fn remove(args: &[Value]) -> NativeResult {
+    check_arity(args, 2)?;
+    if let Value::Map(map) = &args[0] {
+        return match map.borrow_mut().remove(&args[1])? {
+            Some(value) => Ok(value),
+            None => Err(format!("Key {} is not in the map.", args[1])),
+        };
+    }
+    let list = list_arg(args, 0)?;
+    let len = list.borrow().len();
+    let index = list_index(&args[1], len)?;
+    let value = list.borrow_mut().remove(index);
+    Ok(value)
+}
+
+// Checks if a map contains the given key
+pub fn has(args: &[Value]) -> NativeResult {
+    check_arity(args, 2)?;
+    Ok(Value::Boolean(
+        map_arg(args, 0)?.borrow().contains(&args[1])?,
+    ))
+}
+
+// Returns a list of the keys of a map in insertion order
+pub fn keys(args: &[Value]) -> NativeResult {
+    check_arity(args, 1)?;
+    let keys = map_arg(args, 0)?.borrow().keys();
+    Ok(Value::List(Rc::new(RefCell::new(keys))))
+}
+
+// Gets the doc comment of a function or method, or a map of the documented globals of a module
+// Anything without documentation gives nil
+pub fn doc(args: &[Value]) -> NativeResult {
+    check_arity(args, 1)?;
+    let doc = match &args[0] {
+        Value::Closure(closure) => closure.function().doc(),
+        Value::BoundMethod(bound) => bound.method().function().doc(),
+        Value::Module(module) => {
+            let mut docs = Map::new();
+            for (name, doc) in module.docs() {
+                docs.insert(Value::String(name), Value::String(doc))?;
+            }
+            return Ok(Value::Map(Rc::new(RefCell::new(docs))));
+        }
+        _ => None,
+    };
+    Ok(doc.map_or(Value::Nil, Value::String))
+}
+
+// Creates a range of integers, range(end), range(start, end) or range(start, end, step)
+pub fn range(args: &[Value]) -> NativeResult {
+    let mut bounds = Vec::with_capacity(args.len());
+    for arg in args {
+        match arg {
+            Value::Integer(n) => bounds.push(*n),
+            value => return Err(format!("Range bounds must be integers. Got {:?}", value)),
+        }
+    }
+    let (start, end, step) = match bounds[..] {
+        [end] => (0, end, 1),
+        [start, end] => (start, end, 1),
+        [start, end, step] => (start, end, step),
+        _ => {
+            return Err(format!(
+                "Expected 1 to 3 arguments, but got {}.",
+                args.len()
+            ))
+        }
+    };
+    if step == 0 {
+        return Err(String::from("Range step can't be zero."));
+    }
+    Ok(Value::Range(Rc::new(Range::new(start, end, step))))
+}
+
 /*
     These are very dangerous functions at the moment, no arg checks
 */
 
-pub fn test_func_single_arg(args: &[Value]) -> Value {
-    args[0].clone()
+pub fn test_func_single_arg(args: &[Value]) -> NativeResult {
+    Ok(args[0].clone())
 }
 
-pub fn test_func_add_two_args(args: &[Value]) -> Value {
-    match args.len() {
+pub fn test_func_add_two_args(args: &[Value]) -> NativeResult {
+    Ok(match args.len() {
         2 => match (&args[0], &args[1]) {
             (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+            (Value::Integer(a), Value::Integer(b)) => match a.checked_add(*b) {
+                Some(sum) => Value::Integer(sum),
+                None => return Err(String::from("Integer overflow.")),
+            },
             _ => Value::Nil,
         },
         _ => Value::Nil,
-    }
+    })
 }
@@ -0,0 +1,54 @@
+use core::fmt;
+
+// A range of integers from start up to, but not including, end
+#[derive(Debug, PartialEq, Clone)]
+pub struct Range {
+    start: i64, // first value of the range
+    end: i64,   // the range stops before reaching this value
+    step: i64,  // distance between two values, negative to count down
+}
+
+impl Range {
+    pub fn new(start: i64, end: i64, step: i64) -> Self {
+        Self { start, end, step }
+    }
+
+    // Gets the value at the given position, or None if it is past the end
+    pub fn get(&self, index: usize) -> Option<i64> {
+        let value = i64::try_from(index)
+            .ok()?
+            .checked_mul(self.step)?
+            .checked_add(self.start)?;
+        let in_range = if self.step > 0 {
+            value < self.end
+        } else {
+            value > self.end
+        };
+        in_range.then_some(value)
+    }
+
+    // Number of values in the range
+    pub fn len(&self) -> usize {
+        let distance = if self.step > 0 {
+            self.end as i128 - self.start as i128
+        } else {
+            self.start as i128 - self.end as i128
+        };
+        let step = (self.step as i128).abs();
+        if distance <= 0 {
+            0
+        } else {
+            ((distance + step - 1) / step) as usize
+        }
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.step == 1 {
+            write!(f, "{}..{}", self.start, self.end)
+        } else {
+            write!(f, "{}..{} step {}", self.start, self.end, self.step)
+        }
+    }
+}
@@ -1,19 +1,52 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
 use super::Value;
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct ValueArray {
     values: Vec<Value>,
+    interned: HashMap<ConstantKey, usize>, // Index of each literal constant, so names and literals are stored once
+}
+
+// A literal constant, floats are kept by their bits so 1 and 1.0 stay apart
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+enum ConstantKey {
+    String(Rc<str>),
+    Integer(i64),
+    Float(u64),
 }
 
 impl ValueArray {
     pub fn new() -> Self {
-        Self { values: Vec::new() }
+        Self {
+            values: Vec::new(),
+            interned: HashMap::new(),
+        }
     }
     pub fn add_value(&mut self, value: Value) {
         self.values.push(value);
     }
-    pub fn set_value(&mut self, index: usize, value: Value) {
-        self.values[index] = value;
+
+    // Adds a constant and returns its index
+    // Literals that are already in the array reuse their index, a module's REPL lines and functions share it
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = match &value {
+            Value::String(s) => Some(ConstantKey::String(s.clone())),
+            Value::Integer(n) => Some(ConstantKey::Integer(*n)),
+            Value::Number(n) => Some(ConstantKey::Float(n.to_bits())),
+            _ => None,
+        };
+        if let Some(index) = key.as_ref().and_then(|key| self.interned.get(key)) {
+            return *index;
+        }
+
+        self.add_value(value);
+        let index = self.len() - 1;
+        if let Some(key) = key {
+            self.interned.insert(key, index);
+        }
+        index
     }
     pub fn get_value(&self, index: usize) -> Value {
         self.values[index].clone()
@@ -1,26 +1,32 @@
-use std::collections::btree_map::Entry;
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::Duration;
 
 use super::chunk::Chunk;
-use super::value::native_function::{self, NativeFunction};
+use super::frame::{CallFrame, Handler};
+use super::value::class::{BoundMethod, Class, Instance};
+use super::value::closure::{Closure, Upvalue};
+use super::value::exception::Exception;
+use super::value::generator::{Context, Generator, GeneratorState};
+use super::value::map::Map;
+use super::value::module::Module;
+use super::value::native_function::{self, NativeFunction, NativeResult};
+use super::value::range::Range;
 use super::{compiler::Compiler, opcode};
 
-use super::value::{function::Function, Value};
+use super::value::Value;
 
 const MAX_FRAMES: usize = 255;
 
-struct CallFrame {
-    function: Rc<Function>, // The function being called
-    slot_offset: usize,     // The index of the first local slot in the call frame
-    return_addr: usize,     // The address to return to after executing this callframe
-}
-
 pub struct Settings {
     pub trace_execution: bool,
     pub trace_stack: bool,
     pub disassembly: bool,
     pub frame_info: bool,
+    pub virtual_sleep: bool, // If true 'sleep' only advances a virtual clock instead of pausing
 }
 
 impl Settings {
@@ -30,41 +36,119 @@ impl Settings {
             trace_stack: false,
             disassembly: false,
             frame_info: false,
+            virtual_sleep: false,
         }
     }
 }
 
-impl CallFrame {
-    fn new(function: Rc<Function>, slot_offset: usize, return_addr: usize) -> Self {
-        Self {
-            function,
-            slot_offset,
-            return_addr,
-        }
-    }
-}
 pub struct VM {
-    chunk: Chunk,                      // The chunk of code being executed
-    value_stack: Vec<Value>,           // The value stack
-    last_printed: Option<Value>,       // The last value printed (used in tests)
-    globals: BTreeMap<Rc<str>, Value>, // The global variables
-    frame_stack: Vec<CallFrame>,       // The also known as the call stack
-    pc: usize,                         // The program counter
-    settings: Settings,                // The settings for the VM
+    chunk: Chunk,                             // The chunk of code being executed
+    value_stack: Vec<Value>,                  // The value stack
+    last_printed: Option<Value>,              // The last value printed (used in tests)
+    slept: f64,                               // Total number of seconds slept
+    builtins: BTreeMap<Rc<str>, Value>,       // The native functions, visible from every module
+    main_module: Rc<Module>, // The module of the interpreted script, holding its globals
+    modules: HashMap<PathBuf, Rc<Module>>, // The imported modules by their full path
+    importing: Vec<(Rc<Module>, usize)>, // Modules whose top level code is running, with the frame count of the importer
+    frame_stack: Vec<CallFrame>,         // The also known as the call stack
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>, // Upvalues still pointing into the value stack, sorted by slot
+    handlers: Vec<Handler>,                   // The active exception handlers, innermost last
+    generators: Vec<(Rc<RefCell<Generator>>, usize, usize)>, // Running generators, with the frame count and stack size they start at
+    caught_error: bool, // Set when a runtime error was caught by a handler
+    pc: usize,          // The program counter
+    settings: Settings, // The settings for the VM
 }
 
 // Macro to execute a binary operation on two numbers
 macro_rules! binary_op {
-        ($self:ident, $value_type:ident, $op:tt) => {
+        // Comparison, integers are compared exactly and mixed with floats as floats
+        ($self:ident, Boolean, $op:tt) => {
             match ($self.pop(), $self.pop()) {
-                (Value::Number(b), Value::Number(a)) => $self.push(Value::$value_type(a $op b)),
-                (a,b) => {
-                    $self.runtime_error(format!("Operands must be numbers. Got {:?} and {:?}", a, b).as_str(),
+                (Value::Integer(b), Value::Integer(a)) => $self.push(Value::Boolean(a $op b)),
+                (b, a) => match (a.as_float(), b.as_float()) {
+                    (Some(a), Some(b)) => $self.push(Value::Boolean(a $op b)),
+                    _ => {
+                        $self.runtime_error(format!("Operands must be numbers. Got {:?} and {:?}", a, b).as_str(),
 );
+                        return InterpretResult::RuntimeError;
+                    }
+                },
+            }
+        };
+        // Bitwise, only integers have bits to operate on
+        ($self:ident, Integer, $op:expr) => {
+            match ($self.pop(), $self.pop()) {
+                (Value::Integer(b), Value::Integer(a)) => match $op(a, b) {
+                    Ok(result) => $self.push(Value::Integer(result)),
+                    Err(message) => {
+                        $self.runtime_error(message);
+                        return InterpretResult::RuntimeError;
+                    }
+                },
+                (b, a) => {
+                    $self.runtime_error(format!("Operands must be integers. Got {:?} and {:?}", a, b).as_str());
                     return InterpretResult::RuntimeError;
                 }
             }
         };
+        // Arithmetic, integers stay integers and overflowing them is an error
+        // As soon as one operand is a float the other is converted and the result is a float
+        ($self:ident, $checked_op:ident, $float_op:expr) => {
+            match ($self.pop(), $self.pop()) {
+                (Value::Integer(b), Value::Integer(a)) => match a.$checked_op(b) {
+                    Some(result) => $self.push(Value::Integer(result)),
+                    None => {
+                        $self.runtime_error(if b == 0 { "Division by zero." } else { "Integer overflow." });
+                        return InterpretResult::RuntimeError;
+                    }
+                },
+                (b, a) => match (a.as_float(), b.as_float()) {
+                    (Some(a), Some(b)) => $self.push(Value::Number($float_op(a, b))),
+                    _ => {
+                        $self.runtime_error(format!("Operands must be numbers. Got {:?} and {:?}", a, b).as_str(),
+);
+                        return InterpretResult::RuntimeError;
+                    }
+                },
+            }
+        };
+}
+
+// Checks the right operand of a shift, bits can only be shifted by less than the width of an integer
+fn shift_amount(amount: i64) -> Result<i64, &'static str> {
+    if (0..64).contains(&amount) {
+        Ok(amount)
+    } else {
+        Err("Shift amount must be between 0 and 63.")
+    }
+}
+
+// Gets the element of a built-in iterable at the given position and the position after it
+// Strings are iterated by character, their position is a byte offset
+fn next_element(iterable: &Value, position: i64) -> Result<Option<(Value, i64)>, String> {
+    let index = position as usize;
+    let next = match iterable {
+        Value::List(list) => list.borrow().get(index).cloned(),
+        Value::Map(map) => map
+            .borrow()
+            .entries()
+            .get(index)
+            .map(|(key, _)| key.clone()),
+        Value::Range(range) => range.get(index).map(Value::Integer),
+        Value::Enum(enumeration) => enumeration
+            .variants()
+            .get(index)
+            .cloned()
+            .map(Value::Variant),
+        Value::String(s) => {
+            return Ok(s[index..].chars().next().map(|c| {
+                let character = Value::String(Rc::from(c.to_string()));
+                (character, position + c.len_utf8() as i64)
+            }))
+        }
+        value => return Err(format!("Can't iterate over {:?}.", value)),
+    };
+    Ok(next.map(|value| (value, position + 1)))
 }
 
 impl VM {
@@ -73,8 +157,16 @@ impl VM {
             chunk: Chunk::new(),
             value_stack: Vec::with_capacity(8192),
             last_printed: None,
-            globals: BTreeMap::new(),
+            slept: 0.0,
+            builtins: BTreeMap::new(),
+            main_module: Rc::new(Module::new("main", None)),
+            modules: HashMap::new(),
+            importing: Vec::new(),
             frame_stack: Vec::with_capacity(MAX_FRAMES),
+            open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+            generators: Vec::new(),
+            caught_error: false,
             pc: 0,
             settings,
         };
@@ -82,6 +174,17 @@ impl VM {
         vm.define_native("clock", native_function::clock);
         vm.define_native("read_line", native_function::read_line);
         vm.define_native("num", native_function::to_number);
+        vm.define_native("int", native_function::to_integer);
+        vm.define_native("float", native_function::to_float);
+        vm.define_native("len", native_function::len);
+        vm.define_native("append", native_function::append);
+        vm.define_native("pop", native_function::pop);
+        vm.define_native("insert", native_function::insert);
+        vm.define_native("remove", native_function::remove);
+        vm.define_native("has", native_function::has);
+        vm.define_native("keys", native_function::keys);
+        vm.define_native("range", native_function::range);
+        vm.define_native("doc", native_function::doc);
 
         // These are just used in tests
         vm.define_native(
@@ -95,18 +198,30 @@ impl VM {
         vm
     }
 
+    // Sets the file the interpreted script comes from, imports are resolved relative to it
+    // The path is made absolute like the paths of imports, so an import of the script itself is found
+    pub fn set_script_path(&mut self, path: &Path) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| String::from("main"));
+        self.main_module = Rc::new(Module::new(&name, Some(path)));
+    }
+
     // Compiles and executes the given sourcecode
     pub fn interpret(&mut self, source: String) -> InterpretResult {
-        let mut compiler = Compiler::new();
+        let mut compiler = Compiler::new(self.main_module.clone());
         let compile_result = compiler.compile(source, &mut self.chunk, self.settings.disassembly);
 
         match &compile_result {
             Some(function) => {
                 // Push the entry function onto the stack.
-                self.push(Value::Function(function.clone()));
+                let closure = Rc::new(Closure::new(function.clone(), Vec::new()));
+                self.push(Value::Closure(closure.clone()));
 
                 // Call the entry function
-                self.call(function, 0);
+                self.call(&closure, 0);
 
                 self.run()
             }
@@ -139,13 +254,24 @@ impl VM {
         // Set none (for testing purposes), we didn't print anything for the context
         self.last_printed = None;
 
+        loop {
+            match self.execute() {
+                // The error was caught, execution continues in the handler
+                InterpretResult::RuntimeError if self.caught_error => self.caught_error = false,
+                result => return result,
+            }
+        }
+    }
+
+    // Executes instructions until the script returns or an error stops it
+    fn execute(&mut self) -> InterpretResult {
         loop {
             // Print debug data if enabled
             if self.settings.trace_stack {
                 self.print_value_stack();
             }
             if self.settings.trace_execution {
-                self.chunk.disassemble_instruction(self.pc);
+                self.disassemble_instruction();
             }
             if self.settings.frame_info {
                 println!("Frame count: {}", self.frame_stack.len());
@@ -156,47 +282,145 @@ impl VM {
             match self.read_byte() {
                 opcode::OP_GREATER => binary_op!(self, Boolean, >),
                 opcode::OP_LESS => binary_op!(self, Boolean, <),
-                opcode::OP_MODULO => binary_op!(self, Number, %),
-                opcode::OP_ADD => match (self.pop(), self.pop()) {
-                    (Value::Number(b), Value::Number(a)) => self.push(Value::Number(a + b)),
-                    (Value::String(b), Value::String(a)) => {
-                        self.push(Value::String(Rc::from(a.to_string() + &b.to_string())))
-                    }
-                    (Value::Number(b), Value::String(a)) => {
-                        self.push(Value::String(Rc::from(a.to_string() + &b.to_string())))
-                    }
-                    (Value::Boolean(b), Value::String(a)) => {
-                        self.push(Value::String(Rc::from(a.to_string() + &b.to_string())))
+                opcode::OP_MODULO => binary_op!(self, checked_rem, |a: f64, b: f64| a % b),
+                opcode::OP_ADD if matches!(self.peek_n(1), Value::String(_)) => {
+                    match (self.pop(), self.pop()) {
+                        (Value::String(b), Value::String(a)) => {
+                            self.push(Value::String(Rc::from(a.to_string() + &b)))
+                        }
+                        (
+                            b @ (Value::Number(_)
+                            | Value::Integer(_)
+                            | Value::Boolean(_)
+                            | Value::Nil),
+                            Value::String(a),
+                        ) => self.push(Value::String(Rc::from(a.to_string() + &b.to_string()))),
+                        (b, a) => {
+                            self.runtime_error(
+                                format!("Operands must be numbers. Got {:?} and {:?}", a, b)
+                                    .as_str(),
+                            );
+                            return InterpretResult::RuntimeError;
+                        }
                     }
-                    (Value::Nil, Value::String(b)) => {
-                        self.push(Value::String(Rc::from(b.to_string() + "nil")))
+                }
+                opcode::OP_ADD => binary_op!(self, checked_add, |a: f64, b: f64| a + b),
+                opcode::OP_SUBTRACT => binary_op!(self, checked_sub, |a: f64, b: f64| a - b),
+                opcode::OP_MULTIPLY => binary_op!(self, checked_mul, |a: f64, b: f64| a * b),
+                opcode::OP_DIVIDE => {
+                    // Division always gives a float, ~/ divides integers
+                    let (b, a) = (self.pop(), self.pop());
+                    match (a.as_float(), b.as_float()) {
+                        (Some(a), Some(b)) => self.push(Value::Number(a / b)),
+                        _ => {
+                            self.runtime_error(
+                                format!("Operands must be numbers. Got {:?} and {:?}", a, b)
+                                    .as_str(),
+                            );
+                            return InterpretResult::RuntimeError;
+                        }
                     }
-                    (b, a) => {
-                        self.runtime_error(
-                            format!("Operands must be numbers. Got {:?} and {:?}", a, b).as_str(),
-                        );
-                        return InterpretResult::RuntimeError;
+                }
+                opcode::OP_INT_DIVIDE => {
+                    binary_op!(self, checked_div, |a: f64, b: f64| (a / b).trunc())
+                }
+                opcode::OP_POWER => match (self.pop(), self.pop()) {
+                    // Integers stay integers unless the exponent is negative
+                    (Value::Integer(b), Value::Integer(a)) if b >= 0 => {
+                        match u32::try_from(b).ok().and_then(|b| a.checked_pow(b)) {
+                            Some(result) => self.push(Value::Integer(result)),
+                            None => {
+                                self.runtime_error("Integer overflow.");
+                                return InterpretResult::RuntimeError;
+                            }
+                        }
                     }
+                    (b, a) => match (a.as_float(), b.as_float()) {
+                        (Some(a), Some(b)) => self.push(Value::Number(a.powf(b))),
+                        _ => {
+                            self.runtime_error(
+                                format!("Operands must be numbers. Got {:?} and {:?}", a, b)
+                                    .as_str(),
+                            );
+                            return InterpretResult::RuntimeError;
+                        }
+                    },
                 },
-                opcode::OP_SUBTRACT => binary_op!(self, Number, -),
-                opcode::OP_MULTIPLY => binary_op!(self, Number, *),
-                opcode::OP_DIVIDE => binary_op!(self, Number, /),
+                opcode::OP_BIT_AND => binary_op!(self, Integer, |a: i64, b: i64| Ok(a & b)),
+                opcode::OP_BIT_OR => binary_op!(self, Integer, |a: i64, b: i64| Ok(a | b)),
+                opcode::OP_BIT_XOR => binary_op!(self, Integer, |a: i64, b: i64| Ok(a ^ b)),
+                opcode::OP_SHIFT_LEFT => binary_op!(self, Integer, |a: i64, b: i64| {
+                    shift_amount(b).map(|b| a << b)
+                }),
+                // Shifting right keeps the sign
+                opcode::OP_SHIFT_RIGHT => binary_op!(self, Integer, |a: i64, b: i64| {
+                    shift_amount(b).map(|b| a >> b)
+                }),
                 opcode::OP_NOT => {
                     let val = self.pop();
                     self.push(Value::Boolean(val.is_falsy()));
                 }
                 opcode::OP_NEGATE => match self.pop() {
                     Value::Number(n) => self.push(Value::Number(-n)),
+                    Value::Integer(n) => match n.checked_neg() {
+                        Some(n) => self.push(Value::Integer(n)),
+                        None => {
+                            self.runtime_error("Integer overflow.");
+                            return InterpretResult::RuntimeError;
+                        }
+                    },
                     _ => {
                         self.runtime_error("Operand must be a number.");
                         return InterpretResult::RuntimeError;
                     }
                 },
+                opcode::OP_BIT_NOT => match self.pop() {
+                    Value::Integer(n) => self.push(Value::Integer(!n)),
+                    _ => {
+                        self.runtime_error("Operand must be an integer.");
+                        return InterpretResult::RuntimeError;
+                    }
+                },
                 opcode::OP_PRINT => {
                     let value = self.pop();
                     self.last_printed = Some(value.clone());
                     println!("{}", value);
                 }
+                opcode::OP_SLEEP => {
+                    let value = self.pop();
+                    let (seconds, duration) = match value.as_float() {
+                        Some(seconds) if seconds < 0.0 || !seconds.is_finite() => {
+                            self.runtime_error(&format!(
+                                "Sleep duration must be a non-negative number of seconds. Got {}",
+                                seconds
+                            ));
+                            return InterpretResult::RuntimeError;
+                        }
+                        // Checked even when sleeping is virtual, so both settings fail the same way
+                        Some(seconds) => match Duration::try_from_secs_f64(seconds) {
+                            Ok(duration) => (seconds, duration),
+                            Err(_) => {
+                                self.runtime_error(&format!(
+                                    "Sleep duration is too long. Got {}",
+                                    value
+                                ));
+                                return InterpretResult::RuntimeError;
+                            }
+                        },
+                        None => {
+                            self.runtime_error(&format!(
+                                "Sleep duration must be a number. Got {:?}",
+                                value
+                            ));
+                            return InterpretResult::RuntimeError;
+                        }
+                    };
+
+                    self.slept += seconds;
+                    if !self.settings.virtual_sleep {
+                        std::thread::sleep(duration);
+                    }
+                }
                 opcode::OP_JUMP_BACK => {
                     let offset = self.read_short();
                     self.pc -= offset as usize;
@@ -212,6 +436,12 @@ impl VM {
                     }
                     // Else keep on churning
                 }
+                opcode::OP_JUMP_IF_NOT_NIL => {
+                    let offset = self.read_short();
+                    if *self.peek() != Value::Nil {
+                        self.pc += offset as usize;
+                    }
+                }
                 opcode::OP_CALL => {
                     let arg_count = self.read_byte() as usize;
                     let function = self.peek_n(arg_count).clone();
@@ -219,6 +449,449 @@ impl VM {
                         return InterpretResult::RuntimeError;
                     }
                 }
+                opcode::OP_CLOSURE => {
+                    let function = match self.read_constant() {
+                        Value::Function(function) => function,
+                        _ => unreachable!("Closure constant should always be a function"),
+                    };
+
+                    // Capture the upvalues the function refers to
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count());
+                    for _ in 0..function.upvalue_count() {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let slot = self.frame().slot_offset + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.frame().closure.upvalue(index).clone());
+                        }
+                    }
+
+                    self.push(Value::Closure(Rc::new(Closure::new(function, upvalues))));
+                }
+                opcode::OP_GET_UPVALUE => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalue(index).clone();
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.value_stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.push(value);
+                }
+                opcode::OP_SET_UPVALUE => {
+                    let index = self.read_byte() as usize;
+                    let value = self.peek().clone();
+                    let upvalue = self.frame().closure.upvalue(index).clone();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.value_stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                opcode::OP_CLOSE_UPVALUE => {
+                    // Move the top of the stack into any upvalue pointing at it
+                    self.close_upvalues(self.value_stack.len() - 1);
+                    self.pop();
+                }
+                opcode::OP_INVOKE => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte();
+                    if !self.invoke(&name, arg_count) {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                opcode::OP_CLASS => {
+                    let name = self.read_string();
+                    self.push(Value::Class(Rc::new(RefCell::new(Class::new(name)))));
+                }
+                opcode::OP_INHERIT => {
+                    let subclass = self.pop();
+                    match (self.peek(), subclass) {
+                        (Value::Class(superclass), Value::Class(subclass)) => {
+                            subclass.borrow_mut().inherit(&superclass.borrow());
+                        }
+                        _ => {
+                            self.runtime_error("Superclass must be a class.");
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                opcode::OP_GET_SUPER => {
+                    let name = self.read_string();
+                    let superclass = match self.pop() {
+                        Value::Class(superclass) => superclass,
+                        _ => unreachable!("'super' should always be a class"),
+                    };
+                    if !self.bind_method(&superclass, &name) {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                opcode::OP_SUPER_INVOKE => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte();
+                    let superclass = match self.pop() {
+                        Value::Class(superclass) => superclass,
+                        _ => unreachable!("'super' should always be a class"),
+                    };
+                    let method = superclass.borrow().method(&name);
+                    let called = match method {
+                        Some(method) => self.call(&method, arg_count),
+                        None => {
+                            self.runtime_error(&format!("Undefined property '{}'.", name));
+                            false
+                        }
+                    };
+                    if !called {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                opcode::OP_METHOD => {
+                    let name = self.read_string();
+                    let method = self.pop();
+                    match (self.peek(), method) {
+                        (Value::Class(class), Value::Closure(method)) => {
+                            class.borrow_mut().add_method(name, method);
+                        }
+                        _ => unreachable!("Methods are only compiled inside class bodies"),
+                    }
+                }
+                opcode::OP_GET_PROPERTY => {
+                    let name = self.read_string();
+                    let instance = match self.peek() {
+                        Value::Instance(instance) => instance.clone(),
+                        Value::Exception(exception) => {
+                            let value = match &*name {
+                                "message" => Value::String(exception.message().clone()),
+                                "line" => Value::Integer(exception.line() as i64),
+                                _ => {
+                                    self.runtime_error(&format!(
+                                        "Undefined property '{}' on error.",
+                                        name
+                                    ));
+                                    return InterpretResult::RuntimeError;
+                                }
+                            };
+                            self.pop();
+                            self.push(value);
+                            continue;
+                        }
+                        Value::Module(module) => {
+                            match module.global(&name) {
+                                Some(value) => {
+                                    self.pop();
+                                    self.push(value);
+                                }
+                                None => {
+                                    self.runtime_error(&format!(
+                                        "Undefined variable '{}' in module '{}'.",
+                                        name,
+                                        module.name()
+                                    ));
+                                    return InterpretResult::RuntimeError;
+                                }
+                            }
+                            continue;
+                        }
+                        Value::Enum(enumeration) => {
+                            match enumeration.variant(&name) {
+                                Some(variant) => {
+                                    self.pop();
+                                    self.push(Value::Variant(variant));
+                                }
+                                None => {
+                                    self.runtime_error(&format!(
+                                        "Undefined variant '{}' in enum '{}'.",
+                                        name,
+                                        enumeration.name()
+                                    ));
+                                    return InterpretResult::RuntimeError;
+                                }
+                            }
+                            continue;
+                        }
+                        Value::Variant(variant) => {
+                            let value = match &*name {
+                                "name" => Value::String(variant.name().clone()),
+                                "index" => Value::Integer(variant.index() as i64),
+                                _ => {
+                                    self.runtime_error(&format!(
+                                        "Undefined property '{}' on enum variant.",
+                                        name
+                                    ));
+                                    return InterpretResult::RuntimeError;
+                                }
+                            };
+                            self.pop();
+                            self.push(value);
+                            continue;
+                        }
+                        Value::Generator(generator) if &*name == "done" => {
+                            let done = generator.borrow().is_done();
+                            self.pop();
+                            self.push(Value::Boolean(done));
+                            continue;
+                        }
+                        _ => {
+                            self.runtime_error("Only instances have properties.");
+                            return InterpretResult::RuntimeError;
+                        }
+                    };
+
+                    // Fields shadow methods
+                    let field = instance.borrow().field(&name);
+                    if let Some(value) = field {
+                        self.pop();
+                        self.push(value);
+                    } else {
+                        let class = instance.borrow().class().clone();
+                        if !self.bind_method(&class, &name) {
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                opcode::OP_SET_PROPERTY => {
+                    let name = self.read_string();
+                    let value = self.pop();
+                    match self.pop() {
+                        Value::Instance(instance) => {
+                            instance.borrow_mut().set_field(name, value.clone());
+                            self.push(value);
+                        }
+                        Value::Module(module) if module.is_constant(&name) => {
+                            self.runtime_error(&format!(
+                                "Can't assign to constant '{}' in module '{}'.",
+                                name,
+                                module.name()
+                            ));
+                            return InterpretResult::RuntimeError;
+                        }
+                        Value::Module(module) => {
+                            module.define_global(name, value.clone());
+                            self.push(value);
+                        }
+                        _ => {
+                            self.runtime_error("Only instances have fields.");
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                opcode::OP_BUILD_LIST => {
+                    let count = self.read_byte() as usize;
+                    let elements = self.pop_n(count);
+                    self.push(Value::List(Rc::new(RefCell::new(elements))));
+                }
+                opcode::OP_BUILD_MAP => {
+                    let count = self.read_byte() as usize;
+                    let entries = self.pop_n(count * 2);
+                    let mut map = Map::new();
+                    for pair in entries.chunks(2) {
+                        if let Err(message) = map.insert(pair[0].clone(), pair[1].clone()) {
+                            self.runtime_error(&message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                    self.push(Value::Map(Rc::new(RefCell::new(map))));
+                }
+                opcode::OP_GET_INDEX => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    match Self::get_index(&target, &index) {
+                        Ok(value) => self.push(value),
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                opcode::OP_SET_INDEX => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let target = self.pop();
+                    match Self::set_index(&target, index, value.clone()) {
+                        // Assignment is an expression, leave the value on the stack
+                        Ok(()) => self.push(value),
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                opcode::OP_INTERPOLATE => {
+                    let count = self.read_byte() as usize;
+                    let parts = self.pop_n(count);
+                    let string: String = parts.iter().map(|part| part.to_string()).collect();
+                    self.push(Value::String(Rc::from(string)));
+                }
+                opcode::OP_TRY => {
+                    let offset = self.read_short();
+                    self.handlers.push(Handler {
+                        catch_addr: self.pc + offset as usize,
+                        frame_count: self.frame_stack.len(),
+                        stack_size: self.value_stack.len(),
+                    });
+                }
+                opcode::OP_END_TRY => {
+                    self.handlers.pop();
+                }
+                opcode::OP_THROW => {
+                    let exception = self.pop();
+                    if !self.throw(exception.clone()) {
+                        match exception {
+                            Value::Exception(exception) => {
+                                self.report_error(exception.line(), exception.message())
+                            }
+                            value => self.report_error(
+                                self.chunk.get_line(self.pc - 1),
+                                &format!("Uncaught exception: {}", value),
+                            ),
+                        }
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                opcode::OP_RANGE => match (self.pop(), self.pop()) {
+                    (Value::Integer(end), Value::Integer(start)) => {
+                        self.push(Value::Range(Rc::new(Range::new(start, end, 1))))
+                    }
+                    (end, start) => {
+                        self.runtime_error(&format!(
+                            "Range bounds must be integers. Got {:?} and {:?}",
+                            start, end
+                        ));
+                        return InterpretResult::RuntimeError;
+                    }
+                },
+                opcode::OP_RANGE_STEP => match (self.pop(), self.pop(), self.pop()) {
+                    (Value::Integer(0), _, _) => {
+                        self.runtime_error("Range step can't be zero.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    (Value::Integer(step), Value::Integer(end), Value::Integer(start)) => {
+                        self.push(Value::Range(Rc::new(Range::new(start, end, step))))
+                    }
+                    (step, end, start) => {
+                        self.runtime_error(&format!(
+                            "Range bounds and step must be integers. Got {:?}, {:?} and {:?}",
+                            start, end, step
+                        ));
+                        return InterpretResult::RuntimeError;
+                    }
+                },
+                opcode::OP_GET_ITERATOR => match self.peek().clone() {
+                    Value::List(_)
+                    | Value::Map(_)
+                    | Value::String(_)
+                    | Value::Range(_)
+                    | Value::Enum(_)
+                    | Value::Generator(_) => {}
+                    Value::Instance(instance) => {
+                        // An iterable object makes its iterator, otherwise it is an iterator itself
+                        let has_iterator = instance.borrow().field("iterator").is_some()
+                            || instance
+                                .borrow()
+                                .class()
+                                .borrow()
+                                .method("iterator")
+                                .is_some();
+                        if has_iterator && !self.invoke("iterator", 0) {
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                    value => {
+                        self.runtime_error(&format!("Can't iterate over {:?}.", value));
+                        return InterpretResult::RuntimeError;
+                    }
+                },
+                opcode::OP_ITERATE => {
+                    // The iterable is in the given slot and the position in it in the slot after
+                    let instruction = self.pc - 1;
+                    let slot = self.read_byte() as usize;
+                    let offset = self.read_short();
+
+                    let iterable = self.get_value(slot).clone();
+                    let position = self.get_value(slot + 1).clone();
+                    let next = match (iterable, position) {
+                        // Came back with the result of 'next', nil or a finished generator ends the loop
+                        (iterable, Value::Nil) => {
+                            self.set_value(slot + 1, &Value::Integer(0));
+                            let value = self.pop();
+                            match iterable {
+                                Value::Generator(generator) if generator.borrow().is_done() => None,
+                                Value::Generator(_) => Some(value),
+                                _ => Some(value).filter(|value| *value != Value::Nil),
+                            }
+                        }
+                        (Value::Generator(generator), _) => {
+                            // Resume the generator and run this instruction again once it yields
+                            self.set_value(slot + 1, &Value::Nil);
+                            self.pc = instruction;
+                            if !self.resume(generator, Value::Nil) {
+                                return InterpretResult::RuntimeError;
+                            }
+                            continue;
+                        }
+                        (iterator @ Value::Instance(_), _) => {
+                            // Call 'next' on the iterator and run this instruction again once it returns
+                            self.set_value(slot + 1, &Value::Nil);
+                            self.pc = instruction;
+                            self.push(iterator);
+                            if !self.invoke("next", 0) {
+                                return InterpretResult::RuntimeError;
+                            }
+                            continue;
+                        }
+                        (iterable, Value::Integer(position)) => {
+                            match next_element(&iterable, position) {
+                                Ok(Some((value, position))) => {
+                                    self.set_value(slot + 1, &Value::Integer(position));
+                                    Some(value)
+                                }
+                                Ok(None) => None,
+                                Err(message) => {
+                                    self.runtime_error(&message);
+                                    return InterpretResult::RuntimeError;
+                                }
+                            }
+                        }
+                        (_, position) => unreachable!("Invalid iteration position {:?}", position),
+                    };
+
+                    match next {
+                        Some(value) => self.push(value),
+                        None => self.pc += offset as usize,
+                    }
+                }
+                opcode::OP_IMPORT => {
+                    let path = self.read_string();
+                    if !self.import(&path) {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                opcode::OP_END_IMPORT => {
+                    // Discard the result of the top level code, leaving the module
+                    self.pop();
+                    if let (Some((loading, _)), Value::Module(module)) =
+                        (self.importing.last(), self.peek())
+                    {
+                        if Rc::ptr_eq(loading, module) {
+                            self.importing.pop();
+                        }
+                    }
+                }
+                opcode::OP_YIELD => {
+                    let value = self.pop();
+                    let (generator, frame_count, stack_size) = self
+                        .generators
+                        .pop()
+                        .expect("Only generator functions yield");
+                    let return_addr = self.frame_stack[frame_count].return_addr;
+                    let context = self.save_context(frame_count, stack_size);
+                    generator.borrow_mut().suspend(context);
+
+                    // Continue in the code that resumed the generator with the yielded value
+                    self.pc = return_addr;
+                    self.push(value);
+                }
                 opcode::OP_RETURN => {
                     let result = self.pop();
 
@@ -226,8 +899,19 @@ impl VM {
                     let slot = self.frame().slot_offset;
                     let return_addr = self.frame().return_addr;
 
+                    // Locals of the returning function that were captured now live on in their closures
+                    self.close_upvalues(slot);
+
                     self.frame_stack.pop();
 
+                    // Returning from the body of a generator finishes it
+                    if let Some((generator, frame_count, _)) = self.generators.last() {
+                        if *frame_count == self.frame_stack.len() {
+                            generator.borrow_mut().finish();
+                            self.generators.pop();
+                        }
+                    }
+
                     if self.frame_stack.is_empty() {
                         self.pop();
                         return InterpretResult::Ok;
@@ -255,6 +939,19 @@ impl VM {
                         self.pop();
                     }
                 }
+                opcode::OP_DUP => {
+                    // Copies the value the given distance below the top of the stack
+                    let distance = self.read_byte();
+                    let value = self.peek_n(distance as usize).clone();
+                    self.push(value);
+                }
+                opcode::OP_BURY => {
+                    // Moves the top of the stack the given distance down
+                    let distance = self.read_byte() as usize;
+                    let value = self.pop();
+                    let slot = self.value_stack.len() - distance;
+                    self.value_stack.insert(slot, value);
+                }
                 opcode::OP_SET_LOCAL => {
                     let slot = self.read_byte() as usize;
 
@@ -274,8 +971,8 @@ impl VM {
                     let name = self.read_constant();
                     match &name {
                         Value::String(str) => {
-                            let value = self.globals.get(str).cloned();
-                            if let Some(value) = value {
+                            let value = self.frame().closure.function().module().global(str);
+                            if let Some(value) = value.or_else(|| self.builtins.get(str).cloned()) {
                                 self.push(value);
                             } else {
                                 self.runtime_error(&format!("Undefined variable '{}'.", name));
@@ -294,7 +991,11 @@ impl VM {
                     let value = self.pop();
                     match name {
                         Value::String(str) => {
-                            self.globals.insert(str, value);
+                            self.frame()
+                                .closure
+                                .function()
+                                .module()
+                                .define_global(str, value);
                         }
                         _ => {
                             self.runtime_error("Expected a string.");
@@ -307,9 +1008,13 @@ impl VM {
                     match name {
                         Value::String(str) => {
                             let value = self.peek().clone();
-                            if let Entry::Occupied(mut entry) = self.globals.entry(str) {
-                                entry.insert(value);
-                            } else {
+                            let module = self.frame().closure.function().module().clone();
+                            // Assignments compiled before the constant was declared are caught here
+                            if module.is_constant(&str) {
+                                self.runtime_error(&format!("Can't assign to constant '{}'.", str));
+                                return InterpretResult::RuntimeError;
+                            }
+                            if !module.set_global(&str, value) {
                                 self.runtime_error("Undefined variable.");
                                 return InterpretResult::RuntimeError;
                             };
@@ -344,18 +1049,78 @@ impl VM {
         self.value_stack.last().expect("Stack empty")
     }
 
-    // Calls a given function
-    fn call(&mut self, function: &Rc<Function>, arg_count: u8) -> bool {
+    // Returns an upvalue for the given stack slot, reusing an open one if the slot is already captured
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        // Open upvalues are sorted by slot so we can find the insertion point with a binary search
+        let position =
+            self.open_upvalues
+                .binary_search_by_key(&slot, |upvalue| match &*upvalue.borrow() {
+                    Upvalue::Open(slot) => *slot,
+                    Upvalue::Closed(_) => unreachable!("Open upvalues should never be closed"),
+                });
+
+        match position {
+            Ok(index) => self.open_upvalues[index].clone(),
+            Err(index) => {
+                let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+                self.open_upvalues.insert(index, upvalue.clone());
+                upvalue
+            }
+        }
+    }
+
+    // Closes all open upvalues pointing at the given slot or above it
+    fn close_upvalues(&mut self, last_slot: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match &*upvalue.borrow() {
+                Upvalue::Open(slot) => *slot,
+                Upvalue::Closed(_) => unreachable!("Open upvalues should never be closed"),
+            };
+            if slot < last_slot {
+                break;
+            }
+
+            // Move the value off the stack and into the upvalue
+            *upvalue.borrow_mut() = Upvalue::Closed(self.value_stack[slot].clone());
+            self.open_upvalues.pop();
+        }
+    }
+
+    // Calls a given closure
+    fn call(&mut self, closure: &Rc<Closure>, arg_count: u8) -> bool {
+        let function = closure.function();
         // Check arity
-        if arg_count as usize != function.arity() {
+        if !function.accepts(arg_count as usize) {
             self.runtime_error(&format!(
                 "Expected {} arguments, but got {}.",
-                function.arity(),
+                function.arity_range(),
                 arg_count
             ));
             return false;
         }
 
+        // Missing arguments are nil, the function replaces them with their default values
+        let mut arg_count = arg_count as usize;
+        while arg_count < function.arity() {
+            self.push(Value::Nil);
+            arg_count += 1;
+        }
+
+        // Extra arguments are collected into the rest parameter
+        if function.variadic() {
+            let rest = self.pop_n(arg_count - function.arity());
+            self.push(Value::List(Rc::new(RefCell::new(rest))));
+            arg_count = function.arity() + 1;
+        }
+
+        // A generator function doesn't run yet, its arguments are saved in the generator instead
+        if function.generator() {
+            let arguments = self.pop_n(arg_count + 1);
+            let generator = Generator::new(closure.clone(), arguments);
+            self.push(Value::Generator(Rc::new(RefCell::new(generator))));
+            return true;
+        }
+
         // Check if too many frames
         if self.frame_stack.len() == MAX_FRAMES {
             self.runtime_error("Stack overflow.");
@@ -364,8 +1129,8 @@ impl VM {
 
         // Insert a new callframe for the function
         let frame = CallFrame::new(
-            function.clone(),
-            self.value_stack.len() - arg_count as usize - 1,
+            closure.clone(),
+            self.value_stack.len() - arg_count - 1,
             self.pc,
         );
         self.frame_stack.push(frame);
@@ -376,37 +1141,270 @@ impl VM {
         true
     }
 
+    // Continues running a generator from where it last yielded
+    // The sent value becomes the result of the yield it is paused at
+    fn resume(&mut self, generator: Rc<RefCell<Generator>>, sent: Value) -> bool {
+        let state = generator.borrow().state();
+        match state {
+            GeneratorState::Running => {
+                self.runtime_error("Generator is already running.");
+                return false;
+            }
+            // A finished generator only produces nil
+            GeneratorState::Done => {
+                self.push(Value::Nil);
+                return true;
+            }
+            GeneratorState::Created | GeneratorState::Suspended => {}
+        }
+
+        let context = generator.borrow_mut().resume();
+        if self.frame_stack.len() + context.frames.len() > MAX_FRAMES {
+            generator.borrow_mut().finish();
+            self.runtime_error("Stack overflow.");
+            return false;
+        }
+
+        // Move the frames and values of the generator on top of the stacks
+        let frame_count = self.frame_stack.len();
+        let stack_size = self.value_stack.len();
+        for mut frame in context.frames {
+            frame.slot_offset += stack_size;
+            self.frame_stack.push(frame);
+        }
+        // The body returns to the code resuming it
+        self.frame_stack[frame_count].return_addr = self.pc;
+        for mut handler in context.handlers {
+            handler.frame_count += frame_count;
+            handler.stack_size += stack_size;
+            self.handlers.push(handler);
+        }
+        self.value_stack.extend(context.stack);
+
+        // Captured variables go back to living on the stack, they may have been changed while suspended
+        for (slot, upvalue) in context.upvalues {
+            let slot = stack_size + slot;
+            let value = std::mem::replace(&mut *upvalue.borrow_mut(), Upvalue::Open(slot));
+            if let Upvalue::Closed(value) = value {
+                self.value_stack[slot] = value;
+            }
+            self.open_upvalues.push(upvalue);
+        }
+
+        // The first resume starts the body, later ones finish the yield it is paused at
+        if state == GeneratorState::Suspended {
+            self.push(sent);
+        }
+        self.pc = context.pc;
+        self.generators.push((generator, frame_count, stack_size));
+        true
+    }
+
+    // Moves the frames and values starting at the given frame count and stack size off the stacks
+    fn save_context(&mut self, frame_count: usize, stack_size: usize) -> Context {
+        let frames = self
+            .frame_stack
+            .drain(frame_count..)
+            .map(|mut frame| {
+                frame.slot_offset -= stack_size;
+                frame
+            })
+            .collect();
+
+        // Try blocks entered inside the frames, the ones before belong to the code below them
+        let first = self
+            .handlers
+            .iter()
+            .position(|handler| handler.frame_count > frame_count)
+            .unwrap_or(self.handlers.len());
+        let handlers = self
+            .handlers
+            .drain(first..)
+            .map(|mut handler| {
+                handler.frame_count -= frame_count;
+                handler.stack_size -= stack_size;
+                handler
+            })
+            .collect();
+
+        // Upvalues pointing into the saved values hold them while they are off the stack
+        let first = self
+            .open_upvalues
+            .iter()
+            .position(
+                |upvalue| matches!(*upvalue.borrow(), Upvalue::Open(slot) if slot >= stack_size),
+            )
+            .unwrap_or(self.open_upvalues.len());
+        let mut upvalues = Vec::new();
+        for upvalue in self.open_upvalues.split_off(first) {
+            let slot = match &*upvalue.borrow() {
+                Upvalue::Open(slot) => *slot,
+                Upvalue::Closed(_) => unreachable!("Open upvalues should never be closed"),
+            };
+            *upvalue.borrow_mut() = Upvalue::Closed(self.value_stack[slot].clone());
+            upvalues.push((slot - stack_size, upvalue));
+        }
+
+        Context {
+            pc: self.pc,
+            stack: self.value_stack.split_off(stack_size),
+            frames,
+            handlers,
+            upvalues,
+        }
+    }
+
     // Prints the stack trace
     fn stack_trace(&self) {
+        // Each caller is at the call instruction just before the return address of the frame above it
+        let mut pc = self.pc;
         for frame in self.frame_stack.iter().rev() {
             let chunk = &self.chunk;
-            let line = chunk.get_line(self.pc);
+            let line = chunk.get_line(pc - 1);
             println!(
                 "[line {}] in {}",
                 line,
-                Value::Function(frame.function.clone())
+                Value::Closure(frame.closure.clone())
             );
+            pc = frame.return_addr;
         }
     }
 
-    // Calls a given function value, with the given number of arguments
-    fn call_function(&mut self, function: Value, arg_count: u8) -> bool {
-        match &function {
-            // Handle compiled function
-            Value::Function(f) => self.call(f, arg_count),
-            // Handle native function
+    // Replaces the instance on top of the stack with the named method bound to it
+    fn bind_method(&mut self, class: &Rc<RefCell<Class>>, name: &str) -> bool {
+        let method = class.borrow().method(name);
+        match method {
+            Some(method) => {
+                let instance = self.pop();
+                self.push(Value::BoundMethod(Rc::new(BoundMethod::new(
+                    instance, method,
+                ))));
+                true
+            }
+            None => {
+                self.runtime_error(&format!("Undefined property '{}'.", name));
+                false
+            }
+        }
+    }
+
+    // Invokes the named method on the receiver below the arguments
+    fn invoke(&mut self, name: &str, arg_count: u8) -> bool {
+        let instance = match self.peek_n(arg_count as usize) {
+            Value::Instance(instance) => instance.clone(),
+            // Functions of a module are called through its globals
+            Value::Module(module) => {
+                let module = module.clone();
+                return match module.global(name) {
+                    Some(value) => {
+                        let slot = self.value_stack.len() - 1 - arg_count as usize;
+                        self.value_stack[slot] = value.clone();
+                        self.call_function(value, arg_count)
+                    }
+                    None => {
+                        self.runtime_error(&format!(
+                            "Undefined variable '{}' in module '{}'.",
+                            name,
+                            module.name()
+                        ));
+                        false
+                    }
+                };
+            }
+            Value::Generator(generator) => {
+                let generator = generator.clone();
+                if name != "next" {
+                    self.runtime_error(&format!("Undefined property '{}'.", name));
+                    return false;
+                }
+                if arg_count > 1 {
+                    self.runtime_error(&format!(
+                        "Expected 0 to 1 arguments, but got {}.",
+                        arg_count
+                    ));
+                    return false;
+                }
+                let sent = if arg_count == 1 {
+                    self.pop()
+                } else {
+                    Value::Nil
+                };
+                self.pop();
+                return self.resume(generator, sent);
+            }
+            _ => {
+                self.runtime_error("Only instances have methods.");
+                return false;
+            }
+        };
+
+        // A field holding a function shadows the method and is called like any other value
+        let field = instance.borrow().field(name);
+        if let Some(value) = field {
+            let slot = self.value_stack.len() - 1 - arg_count as usize;
+            self.value_stack[slot] = value.clone();
+            return self.call_function(value, arg_count);
+        }
+
+        let method = instance.borrow().class().borrow().method(name);
+        match method {
+            Some(method) => self.call(&method, arg_count),
+            None => {
+                self.runtime_error(&format!("Undefined property '{}'.", name));
+                false
+            }
+        }
+    }
+
+    // Calls a given function value, with the given number of arguments
+    fn call_function(&mut self, function: Value, arg_count: u8) -> bool {
+        match &function {
+            // Handle compiled function
+            Value::Closure(closure) => self.call(closure, arg_count),
+            // Calling a class creates a new instance of it
+            Value::Class(class) => {
+                let slot = self.value_stack.len() - 1 - arg_count as usize;
+                self.value_stack[slot] =
+                    Value::Instance(Rc::new(RefCell::new(Instance::new(class.clone()))));
+
+                let initializer = class.borrow().method("init");
+                match initializer {
+                    Some(initializer) => self.call(&initializer, arg_count),
+                    None if arg_count != 0 => {
+                        self.runtime_error(&format!(
+                            "Expected 0 arguments, but got {}.",
+                            arg_count
+                        ));
+                        false
+                    }
+                    None => true,
+                }
+            }
+            // Bound methods put their instance in slot 0 of the new frame
+            Value::BoundMethod(bound) => {
+                let slot = self.value_stack.len() - 1 - arg_count as usize;
+                self.value_stack[slot] = bound.receiver().clone();
+                self.call(bound.method(), arg_count)
+            }
+            // Handle native function
             Value::NativeFunction(native) => {
                 // Pop the arguments off the stack
                 let args = self.pop_n(arg_count as usize);
                 // Call the native function
-                let result = native.call(&args);
-
-                // Remove the called function from the stack too
-                self.pop();
+                match native.call(&args) {
+                    Ok(result) => {
+                        // Remove the called function from the stack too
+                        self.pop();
 
-                self.push(result);
+                        self.push(result);
 
-                true
+                        true
+                    }
+                    Err(message) => {
+                        self.runtime_error(&message);
+                        false
+                    }
+                }
             }
             x => {
                 self.runtime_error(format!("Can only call functions. Got {:?}", x).as_str());
@@ -431,8 +1429,29 @@ impl VM {
     // Reads a constant from the chunk
     fn read_constant(&mut self) -> Value {
         let constant_index = self.read_byte();
-        //self.frame().get_value(constant_index as usize)
-        self.chunk.get_value(constant_index as usize)
+        // Every module has its own constants
+        self.frame()
+            .closure
+            .function()
+            .module()
+            .constant(constant_index as usize)
+    }
+
+    // Prints the instruction at the program counter, with the constants of the running module
+    fn disassemble_instruction(&self) {
+        if let Some(frame) = self.frame_stack.last() {
+            let module = frame.closure.function().module();
+            self.chunk
+                .disassemble_instruction(self.pc, &module.constants());
+        }
+    }
+
+    // Reads a string constant from the chunk
+    fn read_string(&mut self) -> Rc<str> {
+        match self.read_constant() {
+            Value::String(string) => string,
+            _ => unreachable!("Name constants should always be strings"),
+        }
     }
 
     // Prints the value stack
@@ -458,29 +1477,199 @@ impl VM {
         self.value_stack.pop().expect("Stack is empty")
     }
 
-    // Pops n values off the stack and returns them
-    fn pop_n(&mut self, n: usize) -> Vec<Value> {
-        let mut values = Vec::with_capacity(n);
-        for _ in 0..n {
-            values.push(self.pop());
+    // Reads the element of a list or the value of a map key
+    fn get_index(target: &Value, index: &Value) -> Result<Value, String> {
+        match target {
+            Value::List(list) => {
+                let list = list.borrow();
+                native_function::list_index(index, list.len()).map(|index| list[index].clone())
+            }
+            Value::Map(map) => match map.borrow().get(index)? {
+                Some(value) => Ok(value),
+                None => Err(format!("Key {} is not in the map.", index)),
+            },
+            value => Err(format!(
+                "Can only index into lists and maps. Got {:?}",
+                value
+            )),
         }
-        values
+    }
+
+    // Sets the element of a list or the value of a map key
+    fn set_index(target: &Value, index: Value, value: Value) -> Result<(), String> {
+        match target {
+            Value::List(list) => {
+                let mut list = list.borrow_mut();
+                let len = list.len();
+                native_function::list_index(&index, len).map(|index| list[index] = value)
+            }
+            Value::Map(map) => map.borrow_mut().insert(index, value),
+            value => Err(format!(
+                "Can only index into lists and maps. Got {:?}",
+                value
+            )),
+        }
+    }
+
+    // Pops n values off the stack and returns them in the order they were pushed
+    fn pop_n(&mut self, n: usize) -> Vec<Value> {
+        self.value_stack.split_off(self.value_stack.len() - n)
     }
 
     // Resets the stack to the default state with reserved value
     fn reset_stack(&mut self) {
+        // Closures that outlive the failed code keep the values they captured
+        self.close_upvalues(0);
         //self.value_stack.truncate(0);
         self.value_stack.clear();
         self.frame_stack.clear();
+        self.handlers.clear();
+        for (generator, _, _) in std::mem::take(&mut self.generators) {
+            generator.borrow_mut().finish();
+        }
+
+        // Modules that didn't finish loading are loaded again the next time they are imported
+        for (module, _) in std::mem::take(&mut self.importing) {
+            self.forget_module(&module);
+        }
+    }
+
+    // Removes a module from the loaded modules
+    fn forget_module(&mut self, module: &Module) {
+        if let Some(path) = module.path() {
+            self.modules.remove(path);
+        }
+    }
+
+    // Loads the module at the given path and starts running its top level code
+    // The module is pushed below the result of the top level code, which OP_END_IMPORT discards
+    fn import(&mut self, path: &str) -> bool {
+        // Paths are relative to the importing file
+        let importer = self.frame().closure.function().module().clone();
+        let relative = match importer.path().and_then(|path| path.parent()) {
+            Some(directory) => directory.join(path),
+            None => PathBuf::from(path),
+        };
+        let full_path = match fs::canonicalize(&relative) {
+            Ok(full_path) => full_path,
+            Err(err) => {
+                self.runtime_error(&format!("Could not find module '{}': {}", path, err));
+                return false;
+            }
+        };
+
+        // The modules currently loading, starting with the script itself
+        let loading: Vec<Rc<Module>> = std::iter::once(self.main_module.clone())
+            .chain(self.importing.iter().map(|(module, _)| module.clone()))
+            .collect();
+        if let Some(start) = loading
+            .iter()
+            .position(|module| module.path() == Some(&full_path))
+        {
+            let cycle: Vec<&str> = loading[start..]
+                .iter()
+                .map(|module| module.name())
+                .chain(std::iter::once(loading[start].name()))
+                .collect();
+            self.runtime_error(&format!("Import cycle: {}", cycle.join(" -> ")));
+            return false;
+        }
+
+        // Modules only run once, later imports get the same module
+        if let Some(module) = self.modules.get(&full_path).cloned() {
+            self.push(Value::Module(module));
+            self.push(Value::Nil);
+            return true;
+        }
+
+        let source = match fs::read_to_string(&full_path) {
+            Ok(source) => source,
+            Err(err) => {
+                self.runtime_error(&format!("Could not read module '{}': {}", path, err));
+                return false;
+            }
+        };
+        let name = full_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let module = Rc::new(Module::new(&name, Some(full_path.clone())));
+
+        let mut compiler = Compiler::new(module.clone());
+        compiler.set_file(path);
+        let function = match compiler.compile(source, &mut self.chunk, self.settings.disassembly) {
+            Some(function) => function,
+            None => {
+                self.runtime_error(&format!("Could not compile module '{}'.", path));
+                return false;
+            }
+        };
+
+        self.modules.insert(full_path, module.clone());
+        self.importing
+            .push((module.clone(), self.frame_stack.len()));
+        self.push(Value::Module(module));
+
+        let closure = Rc::new(Closure::new(function, Vec::new()));
+        self.push(Value::Closure(closure.clone()));
+        self.call(&closure, 0)
+    }
+
+    // Unwinds the stacks to the innermost exception handler and continues at its catch block
+    // Returns false if there is no handler
+    fn throw(&mut self, exception: Value) -> bool {
+        let handler = match self.handlers.pop() {
+            Some(handler) => handler,
+            None => return false,
+        };
+
+        // Modules whose top level code is unwound didn't finish loading
+        while let Some((module, frame_count)) = self.importing.last().cloned() {
+            if frame_count < handler.frame_count {
+                break;
+            }
+            self.importing.pop();
+            self.forget_module(&module);
+        }
+
+        // Generators whose body is unwound can't be resumed anymore
+        while let Some((generator, frame_count, _)) = self.generators.last() {
+            if *frame_count < handler.frame_count {
+                break;
+            }
+            generator.borrow_mut().finish();
+            self.generators.pop();
+        }
+
+        self.frame_stack.truncate(handler.frame_count);
+        self.close_upvalues(handler.stack_size);
+        self.value_stack.truncate(handler.stack_size);
+
+        // The catch block finds the exception on top of the stack
+        self.push(exception);
+        self.pc = handler.catch_addr;
+        true
     }
 
-    // Handle runtime error and print debug info
+    // Handle runtime error, it is thrown as an exception if there is a handler for it
     fn runtime_error(&mut self, message: &str) {
+        let line = self.chunk.get_line(self.pc - 1);
+        let exception = Value::Exception(Rc::new(Exception::new(message, line)));
+        if self.throw(exception) {
+            self.caught_error = true;
+            return;
+        }
+
+        self.report_error(line, message);
+    }
+
+    // Prints an uncaught error with debug info
+    fn report_error(&mut self, line: usize, message: &str) {
         // Print the line and the given message
-        println!("[line {}] {}", self.chunk.get_line(self.pc), message);
+        println!("[line {}] {}", line, message);
 
         // Disassemble the instruction
-        self.chunk.disassemble_instruction(self.pc);
+        self.disassemble_instruction();
 
         // Print stack trace
         self.stack_trace();
@@ -490,7 +1679,7 @@ impl VM {
     }
 
     // Defines a native function
-    fn define_native(&mut self, name: &str, function: fn(&[Value]) -> Value) {
+    fn define_native(&mut self, name: &str, function: fn(&[Value]) -> NativeResult) {
         // Create a new native function
         let native_function = NativeFunction::new(name, function);
 
@@ -507,7 +1696,7 @@ impl VM {
         */
 
         // Insert the native function into the global scope
-        self.globals.insert(
+        self.builtins.insert(
             Rc::from(name),
             Value::NativeFunction(Rc::from(native_function)),
         );
@@ -519,6 +1708,12 @@ impl VM {
         */
     }
 
+    #[allow(dead_code)]
+    // Returns the total number of seconds slept (for testing)
+    fn slept(&self) -> f64 {
+        self.slept
+    }
+
     #[allow(dead_code)]
     // Returns the last printed value (for testing)
     fn last_value(&self) -> Option<Value> {
@@ -541,6 +1736,8 @@ pub enum InterpretResult {
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::fs;
     use std::rc::Rc;
 
     use crate::blox::{
@@ -574,22 +1771,22 @@ mod tests {
     #[test]
     fn test_arithmetic() {
         let mut vm = new_vm();
-        expect_value(&mut vm, "print 1+3*4;", Value::Number(13.0));
+        expect_value(&mut vm, "print 1+3*4;", Value::Integer(13));
         expect_value(&mut vm, "print (1+3*3)/5+(4*3);", Value::Number(14.0));
     }
 
     #[test]
     fn test_modulo() {
         let mut vm = new_vm();
-        expect_value(&mut vm, "print 5%2;", Value::Number(1.0));
-        expect_value(&mut vm, "print 5%3;", Value::Number(2.0));
+        expect_value(&mut vm, "print 5%2;", Value::Integer(1));
+        expect_value(&mut vm, "print 5%3;", Value::Integer(2));
     }
 
     #[test]
     fn test_addition() {
         let mut vm = new_vm();
-        expect_value(&mut vm, "print 1+3;", Value::Number(4.0));
-        expect_value(&mut vm, "print 4+3;", Value::Number(7.0));
+        expect_value(&mut vm, "print 1+3;", Value::Integer(4));
+        expect_value(&mut vm, "print 4+3;", Value::Integer(7));
     }
     #[test]
     fn test_string_concatenation() {
@@ -638,16 +1835,16 @@ mod tests {
     #[test]
     fn test_subtraction() {
         let mut vm = new_vm();
-        expect_value(&mut vm, "print 1-3;", Value::Number(-2.0));
-        expect_value(&mut vm, "print 6-2;", Value::Number(4.0));
+        expect_value(&mut vm, "print 1-3;", Value::Integer(-2));
+        expect_value(&mut vm, "print 6-2;", Value::Integer(4));
     }
 
     #[test]
     fn test_multiplication() {
         let mut vm = new_vm();
-        expect_value(&mut vm, "print 2*10;", Value::Number(20.0));
-        expect_value(&mut vm, "print 3*2*1;", Value::Number(6.0));
-        expect_value(&mut vm, "print 1*2*3;", Value::Number(6.0));
+        expect_value(&mut vm, "print 2*10;", Value::Integer(20));
+        expect_value(&mut vm, "print 3*2*1;", Value::Integer(6));
+        expect_value(&mut vm, "print 1*2*3;", Value::Integer(6));
     }
 
     #[test]
@@ -669,11 +1866,11 @@ mod tests {
     #[test]
     fn test_negation() {
         let mut vm = new_vm();
-        expect_value(&mut vm, "print -1;", Value::Number(-1.0));
-        expect_value(&mut vm, "print -2;", Value::Number(-2.0));
-        expect_value(&mut vm, "print -3;", Value::Number(-3.0));
-        expect_value(&mut vm, "print --3;", Value::Number(3.0));
-        expect_value(&mut vm, "print ---3;", Value::Number(-3.0));
+        expect_value(&mut vm, "print -1;", Value::Integer(-1));
+        expect_value(&mut vm, "print -2;", Value::Integer(-2));
+        expect_value(&mut vm, "print -3;", Value::Integer(-3));
+        expect_value(&mut vm, "print - -3;", Value::Integer(3));
+        expect_value(&mut vm, "print - - -3;", Value::Integer(-3));
     }
 
     #[test]
@@ -692,9 +1889,9 @@ mod tests {
     #[test]
     fn test_comments() {
         let mut vm = new_vm();
-        expect_value(&mut vm, "print 1+3*4; // comment", Value::Number(13.0));
+        expect_value(&mut vm, "print 1+3*4; // comment", Value::Integer(13));
         expect_none(&mut vm, "// 1+3*4");
-        expect_value(&mut vm, "print 1; //+3*4", Value::Number(1.0));
+        expect_value(&mut vm, "print 1; //+3*4", Value::Integer(1));
         expect_value(
             &mut vm,
             r#"
@@ -702,7 +1899,7 @@ mod tests {
             //b = 14;
             print b;
         "#,
-            Value::Number(2.0),
+            Value::Integer(2),
         );
     }
 
@@ -794,7 +1991,7 @@ mod tests {
         var b = a + 3;
         print b + a;
         "#,
-            Value::Number(5.0),
+            Value::Integer(5),
         );
 
         expect_value(
@@ -804,7 +2001,7 @@ mod tests {
         var b = 3 + 1;
         print b + a;
         "#,
-            Value::Number(5.0),
+            Value::Integer(5),
         );
 
         expect_value(
@@ -814,7 +2011,7 @@ mod tests {
         var b = 3 + 1;
         print a + b;
         "#,
-            Value::Number(5.0),
+            Value::Integer(5),
         );
     }
 
@@ -828,7 +2025,7 @@ mod tests {
         a = 2;
         print a;
         "#,
-            Value::Number(2.0),
+            Value::Integer(2),
         );
 
         expect_value(
@@ -838,7 +2035,7 @@ mod tests {
         a = a + 2;
         print a;
         "#,
-            Value::Number(3.0),
+            Value::Integer(3),
         );
 
         // Assign to invalid assignment target
@@ -1002,7 +2199,7 @@ mod tests {
             }
 
         "#,
-            Value::Number(3.0),
+            Value::Integer(3),
         );
 
         expect_value(
@@ -1047,7 +2244,7 @@ mod tests {
             }
 
         "#,
-            Value::Number(3.0),
+            Value::Integer(3),
         );
     }
     #[test]
@@ -1189,7 +2386,7 @@ mod tests {
         }
         print a;
         "#,
-            Value::Number(5.0),
+            Value::Integer(5),
         );
     }
 
@@ -1205,7 +2402,7 @@ mod tests {
         }
         print a;
         "#,
-            Value::Number(5.0),
+            Value::Integer(5),
         );
 
         expect_value(
@@ -1215,7 +2412,7 @@ mod tests {
         for (;a < 5; a = a + 1) { }
         print a;
         "#,
-            Value::Number(5.0),
+            Value::Integer(5),
         );
 
         expect_value(
@@ -1225,7 +2422,7 @@ mod tests {
         for (a = 0;a < 5; a = a + 1) { }
         print a;
         "#,
-            Value::Number(5.0),
+            Value::Integer(5),
         );
 
         expect_value(
@@ -1238,7 +2435,7 @@ mod tests {
         }
         print b;
         "#,
-            Value::Number(2.0),
+            Value::Integer(2),
         );
 
         expect_value(
@@ -1250,7 +2447,7 @@ mod tests {
         }
         print b;
         "#,
-            Value::Number(2.0),
+            Value::Integer(2),
         );
 
         expect_value(
@@ -1263,8 +2460,179 @@ mod tests {
         }
         print b;
         "#,
-            Value::Number(2.0),
+            Value::Integer(2),
+        );
+    }
+
+    #[test]
+    fn test_break_continue() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"
+            var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                var square = i * i;
+                if (i == 2) continue;
+                if (i == 5) break;
+                sum = sum + square;
+            }
+            print sum;
+        "#,
+            Value::Integer(26),
+        );
+
+        expect_value(
+            &mut vm,
+            r#"
+            var n = 0;
+            while (true) {
+                n = n + 1;
+                var copy = n;
+                if (copy < 3) continue;
+                break;
+            }
+            print n;
+        "#,
+            Value::Integer(3),
+        );
+
+        // Locals declared in the loop are popped when jumping out, so later locals still resolve
+        expect_value(
+            &mut vm,
+            r#"
+            {
+                var before = "before";
+                while (true) {
+                    var a = 1;
+                    {
+                        var b = 2;
+                        break;
+                    }
+                }
+                var after = "after";
+                print before + after;
+            }
+        "#,
+            Value::String(Rc::from("beforeafter")),
+        );
+
+        // Captured loop locals are closed when jumping out
+        expect_value(
+            &mut vm,
+            r#"
+            var get;
+            for (var i = 0; i < 3; i = i + 1) {
+                var captured = i * 10;
+                fun getter() {
+                    return captured;
+                }
+                get = getter;
+                if (i == 1) break;
+            }
+            print get();
+        "#,
+            Value::Integer(10),
+        );
+
+        expect_interpreter_result(&mut vm, "break;", InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, "continue;", InterpretResult::CompileError);
+
+        // Functions can't break out of a loop they are declared in
+        expect_interpreter_result(
+            &mut vm,
+            r#"
+            while (true) {
+                fun escape() {
+                    break;
+                }
+                break;
+            }
+        "#,
+            InterpretResult::CompileError,
+        );
+    }
+
+    #[test]
+    fn test_labeled_loops() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"
+            var count = 0;
+            outer: for (var a = 0; a < 5; a = a + 1) {
+                for (var b = 0; b < 5; b = b + 1) {
+                    if (b == 2) continue outer;
+                    if (a == 3) break outer;
+                    count = count + 1;
+                }
+            }
+            print count;
+        "#,
+            Value::Integer(6),
+        );
+
+        expect_value(
+            &mut vm,
+            r#"
+            var found = nil;
+            search: while (true) {
+                var row = 0;
+                while (row < 10) {
+                    row = row + 1;
+                    if (row == 4) {
+                        found = row;
+                        break search;
+                    }
+                }
+            }
+            print found;
+        "#,
+            Value::Integer(4),
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            "while (true) { break missing; }",
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(&mut vm, "label: print 1;", InterpretResult::CompileError);
+    }
+
+    #[test]
+    fn test_sleep() {
+        let mut settings = Settings::new();
+        settings.virtual_sleep = true;
+        let mut vm = VM::new(settings);
+
+        expect_value(
+            &mut vm,
+            r#"
+            sleep 1.5;
+            var delay = 2;
+            sleep delay;
+            sleep 0;
+            print "awake";
+        "#,
+            Value::String(Rc::from("awake")),
+        );
+        assert_eq!(vm.slept(), 3.5);
+
+        expect_interpreter_result(&mut vm, "sleep -1;", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, r#"sleep "1";"#, InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "sleep nil;", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "sleep 1e300;", InterpretResult::RuntimeError);
+        expect_value(
+            &mut vm,
+            "try { sleep 1e300; } catch (e) { print e.message; }",
+            Value::String(Rc::from("Sleep duration is too long. Got 1e300")),
         );
+        expect_interpreter_result(&mut vm, "sleep 1", InterpretResult::CompileError);
+
+        // A real sleep without the virtual clock
+        let mut vm = new_vm();
+        expect_value(&mut vm, "sleep 0.001; print 1;", Value::Integer(1));
+        expect_interpreter_result(&mut vm, "sleep 1e300;", InterpretResult::RuntimeError);
     }
 
     #[test]
@@ -1278,7 +2646,7 @@ mod tests {
             }
             print test();
         "#,
-            Value::Number(5.0),
+            Value::Integer(5),
         );
 
         expect_interpreter_result(
@@ -1311,7 +2679,7 @@ mod tests {
             }
             print printer3(2);
         "#,
-            Value::Number(2.0),
+            Value::Integer(2),
         );
 
         expect_value(
@@ -1324,7 +2692,7 @@ mod tests {
 
             print fib(10);
         "#,
-            Value::Number(55.0),
+            Value::Integer(55),
         );
 
         expect_value(
@@ -1348,7 +2716,7 @@ print fib_non_recursive(n);
 
 
         "#,
-            Value::Number(55.0),
+            Value::Integer(55),
         );
 
         // See if locals work properly in function in nested scope, they should reset and just use the function slot as local offset
@@ -1365,7 +2733,7 @@ print fib_non_recursive(n);
 
         }
         "#,
-            Value::Number(2.0),
+            Value::Integer(2),
         );
 
         expect_value(
@@ -1382,7 +2750,7 @@ print fib_non_recursive(n);
 
         }
         "#,
-            Value::Number(3.0),
+            Value::Integer(3),
         );
 
         expect_value(
@@ -1396,7 +2764,7 @@ print fib_non_recursive(n);
 
             print test(a);
         "#,
-            Value::Number(3.0),
+            Value::Integer(3),
         );
     }
 
@@ -1411,7 +2779,7 @@ print fib_non_recursive(n);
             }
             print test(2, 3);
         "#,
-            Value::Number(5.0),
+            Value::Integer(5),
         );
 
         expect_interpreter_result(
@@ -1477,55 +2845,2813 @@ print fib_non_recursive(n);
             }
             print test(2, 3, 4);
         "#,
-            Value::Number(9.0),
+            Value::Integer(9),
         );
     }
 
     #[test]
-    fn test_native_function() {
+    fn test_nested_function() {
         let mut vm = new_vm();
         expect_value(
             &mut vm,
             r#"
-            print test_func_single_arg(1234);
-            "#,
-            Value::Number(1234.0),
+            fun outer() {
+                fun inner() {
+                    return 3;
+                }
+                return inner();
+            }
+            print outer();
+        "#,
+            Value::Integer(3),
         );
 
+        // Redeclaring in a sibling scope is fine
         expect_value(
             &mut vm,
             r#"
-            print test_func_add_two_args(9, 8);
-            "#,
-            Value::Number(17.0),
+            { var a = 1; }
+            { var a = 2; print a; }
+        "#,
+            Value::Integer(2),
         );
+    }
 
-        // Test more random placement
+    #[test]
+    fn test_closures() {
+        let mut vm = new_vm();
+
+        // Counter keeps its own state
         expect_value(
             &mut vm,
             r#"
-            fun clk() {
-                return clock();
+            fun make_counter() {
+                var count = 0;
+                fun increment() {
+                    count = count + 1;
+                    return count;
+                }
+                return increment;
             }
-            print clk();
+            var counter = make_counter();
+            counter();
+            counter();
+            var other = make_counter();
+            other();
+            print counter();
+        "#,
+            Value::Integer(3),
+        );
 
-            fun tester() {
-                return 1 + 2;
+        // Captured through several levels of functions
+        expect_value(
+            &mut vm,
+            r#"
+            fun outer() {
+                var x = "outer";
+                fun middle() {
+                    fun inner() {
+                        return x;
+                    }
+                    return inner;
+                }
+                return middle;
             }
+            print outer()()();
+        "#,
+            Value::String(Rc::from("outer")),
+        );
 
-            print tester();
-            "#,
-            Value::Number(3.0),
+        // Closures see assignments made after they were created
+        expect_value(
+            &mut vm,
+            r#"
+            {
+                var a = 1;
+                fun get() {
+                    return a;
+                }
+                a = 5;
+                print get();
+            }
+        "#,
+            Value::Integer(5),
         );
 
-        // Test lack of popping native function from stack
+        // Closures created in the same scope share the captured variable, even after it is closed
         expect_value(
             &mut vm,
             r#"
+            var get;
+            var set;
+            {
+                var shared = 0;
+                fun getter() {
+                    return shared;
+                }
+                fun setter(value) {
+                    shared = value;
+                }
+                get = getter;
+                set = setter;
+            }
+            set(42);
+            print get();
+        "#,
+            Value::Integer(42),
+        );
 
-            print 3 - test_func_single_arg(1);
-            "#,
-            Value::Number(2.0),
+        // Factory and callback
+        expect_value(
+            &mut vm,
+            r#"
+            fun adder(n) {
+                fun add(x) {
+                    return x + n;
+                }
+                return add;
+            }
+            fun apply(callback, value) {
+                return callback(value);
+            }
+            print apply(adder(10), 5);
+        "#,
+            Value::Integer(15),
         );
+
+        // Recursive local function
+        expect_value(
+            &mut vm,
+            r#"
+            {
+                fun sum(n) {
+                    if (n < 1) return 0;
+                    return n + sum(n - 1);
+                }
+                print sum(4);
+            }
+        "#,
+            Value::Integer(10),
+        );
+
+        // A closure that captured itself can be formatted in an error message
+        expect_interpreter_result(
+            &mut vm,
+            "fun outer() { fun inner() { return inner; } return inner; } print outer() + 1;",
+            InterpretResult::RuntimeError,
+        );
+
+        // Captured locals are closed when an error ends the code, like between lines of the REPL
+        expect_interpreter_result(
+            &mut vm,
+            "var f; { var a = 0; var b = 0; var x = 42; f = fun() { return x; }; print 1 + nil; }",
+            InterpretResult::RuntimeError,
+        );
+        expect_value(&mut vm, "print f();", Value::Integer(42));
+    }
+
+    #[test]
+    fn test_classes() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"
+            class Empty {}
+            var record = Empty();
+            record.name = "blox";
+            record.count = 1;
+            record.count = record.count + 1;
+            print record.name + record.count;
+        "#,
+            Value::String(Rc::from("blox2")),
+        );
+
+        // Instances are compared by identity
+        expect_value(
+            &mut vm,
+            r#"
+            class Empty {}
+            var a = Empty();
+            var b = a;
+            print a == b and a != Empty();
+        "#,
+            Value::Boolean(true),
+        );
+
+        // Classes can be declared in local scopes
+        expect_value(
+            &mut vm,
+            r#"
+            {
+                class Local {}
+                var local = Local();
+                local.value = 3;
+                print local.value;
+            }
+        "#,
+            Value::Integer(3),
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            r#"
+            class Empty {}
+            print Empty().missing;
+        "#,
+            InterpretResult::RuntimeError,
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            r#"
+            var number = 3;
+            number.field = 1;
+        "#,
+            InterpretResult::RuntimeError,
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            r#"
+            class Empty {}
+            Empty(1);
+        "#,
+            InterpretResult::RuntimeError,
+        );
+    }
+
+    #[test]
+    fn test_methods() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"
+            class Point {
+                init(x, y) {
+                    this.x = x;
+                    this.y = y;
+                }
+                sum() {
+                    return this.x + this.y;
+                }
+                scale(factor) {
+                    this.x = this.x * factor;
+                    this.y = this.y * factor;
+                    return this;
+                }
+            }
+            var point = Point(1, 2);
+            print point.scale(2).sum();
+        "#,
+            Value::Integer(6),
+        );
+
+        // Bound methods remember their instance
+        expect_value(
+            &mut vm,
+            r#"
+            class Greeter {
+                init(name) {
+                    this.name = name;
+                }
+                greet() {
+                    return "Hello, " + this.name;
+                }
+            }
+            var greet = Greeter("blox").greet;
+            print greet();
+        "#,
+            Value::String(Rc::from("Hello, blox")),
+        );
+
+        // Closures inside methods capture 'this'
+        expect_value(
+            &mut vm,
+            r#"
+            class Counter {
+                init() {
+                    this.count = 0;
+                }
+                incrementer() {
+                    fun increment() {
+                        this.count = this.count + 1;
+                        return this.count;
+                    }
+                    return increment;
+                }
+            }
+            var counter = Counter();
+            var increment = counter.incrementer();
+            increment();
+            increment();
+            print counter.count;
+        "#,
+            Value::Integer(2),
+        );
+
+        // Fields shadow methods
+        expect_value(
+            &mut vm,
+            r#"
+            class Shadow {
+                method() {
+                    return "method";
+                }
+            }
+            fun field() {
+                return "field";
+            }
+            var shadow = Shadow();
+            shadow.method = field;
+            print shadow.method();
+        "#,
+            Value::String(Rc::from("field")),
+        );
+
+        // Calling init directly returns the instance
+        expect_value(
+            &mut vm,
+            r#"
+            class Init {
+                init() {
+                    this.value = 1;
+                    return;
+                }
+            }
+            print Init().init().value;
+        "#,
+            Value::Integer(1),
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            r#"
+            class Init {
+                init() {
+                    return 1;
+                }
+            }
+        "#,
+            InterpretResult::CompileError,
+        );
+
+        expect_interpreter_result(&mut vm, "print this;", InterpretResult::CompileError);
+
+        expect_interpreter_result(
+            &mut vm,
+            r#"
+            class Point {
+                init(x, y) {}
+            }
+            Point(1);
+        "#,
+            InterpretResult::RuntimeError,
+        );
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"
+            class Animal {
+                init(name) {
+                    this.name = name;
+                }
+                speak() {
+                    return this.name + " makes a sound";
+                }
+            }
+            class Dog < Animal {}
+            print Dog("Rex").speak();
+        "#,
+            Value::String(Rc::from("Rex makes a sound")),
+        );
+
+        // Overrides and super calls, through several levels
+        expect_value(
+            &mut vm,
+            r#"
+            class A {
+                init(n) {
+                    this.n = n;
+                }
+                name() {
+                    return "A" + this.n;
+                }
+                describe() {
+                    return "I am " + this.name();
+                }
+            }
+            class B < A {
+                init(n) {
+                    super.init(n * 10);
+                }
+                name() {
+                    return "B/" + super.name();
+                }
+            }
+            class C < B {
+                name() {
+                    var method = super.name;
+                    return "C/" + method();
+                }
+            }
+            print C(2).describe();
+        "#,
+            Value::String(Rc::from("I am C/B/A20")),
+        );
+
+        // Methods added to the superclass are copied when the subclass is created
+        expect_value(
+            &mut vm,
+            r#"
+            {
+                class Base {
+                    value() {
+                        return 1;
+                    }
+                }
+                class Derived < Base {
+                    value() {
+                        return super.value() + 1;
+                    }
+                }
+                print Derived().value();
+            }
+        "#,
+            Value::Integer(2),
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            "class Self < Self {}",
+            InterpretResult::CompileError,
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            r#"
+            class NoSuper {
+                method() {
+                    return super.method();
+                }
+            }
+        "#,
+            InterpretResult::CompileError,
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            "print super.method();",
+            InterpretResult::CompileError,
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            r#"
+            var not_a_class = 1;
+            class Derived < not_a_class {}
+        "#,
+            InterpretResult::RuntimeError,
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            r#"
+            class Base {}
+            class Derived < Base {
+                method() {
+                    return super.missing();
+                }
+            }
+            Derived().method();
+        "#,
+            InterpretResult::RuntimeError,
+        );
+    }
+
+    #[test]
+    fn test_native_function() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"
+            print test_func_single_arg(1234);
+            "#,
+            Value::Integer(1234),
+        );
+
+        expect_value(
+            &mut vm,
+            r#"
+            print test_func_add_two_args(9, 8);
+            "#,
+            Value::Integer(17),
+        );
+        expect_interpreter_result(
+            &mut vm,
+            "test_func_add_two_args(9223372036854775807, 1);",
+            InterpretResult::RuntimeError,
+        );
+
+        // Test more random placement
+        expect_value(
+            &mut vm,
+            r#"
+            fun clk() {
+                return clock();
+            }
+            print clk();
+
+            fun tester() {
+                return 1 + 2;
+            }
+
+            print tester();
+            "#,
+            Value::Integer(3),
+        );
+
+        // Test lack of popping native function from stack
+        expect_value(
+            &mut vm,
+            r#"
+
+            print 3 - test_func_single_arg(1);
+            "#,
+            Value::Integer(2),
+        );
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            "print [1, 2, 3];",
+            Value::List(Rc::new(RefCell::new(vec![
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(3),
+            ]))),
+        );
+        expect_value(
+            &mut vm,
+            "print [];",
+            Value::List(Rc::new(RefCell::new(vec![]))),
+        );
+        expect_value(
+            &mut vm,
+            r#"print ["a", 1, nil,][0];"#,
+            Value::String(Rc::from("a")),
+        );
+        expect_value(&mut vm, "print [1, [2, 3]][1][0];", Value::Integer(2));
+
+        // Index assignment
+        expect_value(
+            &mut vm,
+            r#"
+            var xs = [1, 2, 3];
+            xs[1] = xs[0] + xs[2];
+            print xs[1];
+            "#,
+            Value::Integer(4),
+        );
+        expect_value(&mut vm, "var ys = [0]; print ys[0] = 5;", Value::Integer(5));
+
+        // Lists are shared by reference
+        expect_value(
+            &mut vm,
+            r#"
+            var a = [1];
+            var b = a;
+            append(b, 2);
+            print len(a);
+            "#,
+            Value::Integer(2),
+        );
+        expect_value(&mut vm, "print a == b;", Value::Boolean(true));
+        expect_value(&mut vm, "print [1] == [1];", Value::Boolean(false));
+
+        // Native list functions
+        expect_value(
+            &mut vm,
+            r#"
+            var xs = [1, 2, 3];
+            insert(xs, 0, 0);
+            insert(xs, 4, 4);
+            print xs[0] + xs[4];
+            "#,
+            Value::Integer(4),
+        );
+        expect_value(&mut vm, "print remove(xs, 1);", Value::Integer(1));
+        expect_value(&mut vm, "print pop(xs);", Value::Integer(4));
+        expect_value(&mut vm, "print len(xs);", Value::Integer(3));
+        expect_value(&mut vm, r#"print len("abc");"#, Value::Integer(3));
+
+        // Lists in loops and functions
+        expect_value(
+            &mut vm,
+            r#"
+            fun sum(list) {
+                var total = 0;
+                for (var i = 0; i < len(list); i = i + 1) {
+                    total = total + list[i];
+                }
+                return total;
+            }
+            print sum([1, 2, 3, 4]);
+            "#,
+            Value::Integer(10),
+        );
+
+        // Errors
+        expect_interpreter_result(&mut vm, "[1, 2][2];", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "[1, 2][-1];", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "[1, 2][0.5];", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, r#"[1, 2]["0"];"#, InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "var n = 1; n[0];", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "pop([]);", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "append([]);", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "insert([], 1, 1);", InterpretResult::RuntimeError);
+        expect_value(
+            &mut vm,
+            "try { insert([9, 2], 3, 0); } catch (e) { print e.message; }",
+            Value::String(Rc::from(
+                "List index out of range. Got 3 for a list of length 2",
+            )),
+        );
+        expect_interpreter_result(&mut vm, "[1, 2;", InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, "[1][0;", InterpretResult::CompileError);
+
+        // A list that contains itself is printed once
+        expect_interpreter_result(
+            &mut vm,
+            "var ys = [1]; append(ys, ys); print ys;",
+            InterpretResult::Ok,
+        );
+        expect_value(
+            &mut vm,
+            r#"print "${ys} ${[ys]}";"#,
+            Value::String(Rc::from("[1, [...]] [[1, [...]]]")),
+        );
+        expect_interpreter_result(&mut vm, "print ys + 1;", InterpretResult::RuntimeError);
+        expect_value(
+            &mut vm,
+            r#"var zs = [1]; print "${[zs, zs]}";"#,
+            Value::String(Rc::from("[[1], [1]]")),
+        );
+    }
+
+    #[test]
+    fn test_maps() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"print {"a": 1, "b": 2}["b"];"#,
+            Value::Integer(2),
+        );
+        expect_value(&mut vm, "print len({});", Value::Integer(0));
+
+        // Scalars of every kind can be keys
+        expect_value(
+            &mut vm,
+            r#"
+            var m = {1: "one", true: "yes", nil: "nothing", "k": "key",};
+            print m[1] + m[true] + m[nil] + m["k"];
+            "#,
+            Value::String(Rc::from("oneyesnothingkey")),
+        );
+        expect_value(&mut vm, "print m[1.0] == m[2 - 1];", Value::Boolean(true));
+        expect_value(
+            &mut vm,
+            r#"print {0: "zero"}[-0];"#,
+            Value::String(Rc::from("zero")),
+        );
+
+        // Setting keys
+        expect_value(
+            &mut vm,
+            r#"
+            var counts = {};
+            var words = ["a", "b", "a", "c", "a"];
+            for (var i = 0; i < len(words); i = i + 1) {
+                var word = words[i];
+                if (has(counts, word)) {
+                    counts[word] = counts[word] + 1;
+                } else {
+                    counts[word] = 1;
+                }
+            }
+            print counts["a"];
+            "#,
+            Value::Integer(3),
+        );
+        expect_value(&mut vm, "print len(counts);", Value::Integer(3));
+        expect_value(&mut vm, r#"print counts["z"] = 9;"#, Value::Integer(9));
+
+        // Keys are kept in insertion order, overwriting a key keeps its place
+        expect_value(
+            &mut vm,
+            r#"
+            var m = {"x": 1, "y": 2, "z": 3};
+            m["x"] = 10;
+            print remove(m, "y");
+            "#,
+            Value::Integer(2),
+        );
+        expect_value(
+            &mut vm,
+            "print keys(m);",
+            Value::List(Rc::new(RefCell::new(vec![
+                Value::String(Rc::from("x")),
+                Value::String(Rc::from("z")),
+            ]))),
+        );
+        expect_value(&mut vm, r#"print has(m, "y");"#, Value::Boolean(false));
+        expect_value(
+            &mut vm,
+            r#"m["y"] = 4; print keys(m)[2];"#,
+            Value::String(Rc::from("y")),
+        );
+
+        // Maps are shared by reference
+        expect_value(
+            &mut vm,
+            r#"
+            var a = {};
+            var b = a;
+            b["k"] = 1;
+            print a["k"];
+            "#,
+            Value::Integer(1),
+        );
+        expect_value(&mut vm, "print a == b;", Value::Boolean(true));
+        expect_value(&mut vm, "print {} == {};", Value::Boolean(false));
+
+        // Errors
+        expect_interpreter_result(
+            &mut vm,
+            r#"print {"a": 1}["b"];"#,
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(
+            &mut vm,
+            "var m = {}; m[[]] = 1;",
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(&mut vm, "var m = {[]: 1};", InterpretResult::RuntimeError);
+        expect_value(
+            &mut vm,
+            r#"try { var m = {}; m[[1, "a"]] = 1; } catch (e) { print e.message; }"#,
+            Value::String(Rc::from(r#"Can't use [1, "a"] as a map key."#)),
+        );
+        expect_interpreter_result(&mut vm, "var m = {0/0: 1};", InterpretResult::RuntimeError);
+        expect_interpreter_result(
+            &mut vm,
+            r#"remove({}, "a");"#,
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(&mut vm, "has([], 1);", InterpretResult::RuntimeError);
+        expect_interpreter_result(
+            &mut vm,
+            r#"var m = {"a" 1};"#,
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(
+            &mut vm,
+            r#"var m = {"a": 1;"#,
+            InterpretResult::CompileError,
+        );
+
+        // Maps that contain themselves, also through a list, are printed once
+        expect_interpreter_result(
+            &mut vm,
+            r#"var m = {}; m["self"] = m; print m;"#,
+            InterpretResult::Ok,
+        );
+        expect_value(
+            &mut vm,
+            r#"var l = [m]; m["list"] = l; print "${m} ${l}";"#,
+            Value::String(Rc::from(
+                r#"{"self": {...}, "list": [{...}]} [{"self": {...}, "list": [...]}]"#,
+            )),
+        );
+        expect_interpreter_result(&mut vm, "print m + 1;", InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"var a = 1; var b = 2; print "total: ${a + b}";"#,
+            Value::String(Rc::from("total: 3")),
+        );
+        expect_value(&mut vm, r#"print "${a}";"#, Value::String(Rc::from("1")));
+        expect_value(
+            &mut vm,
+            r#"print "${a}${b} and ${"x"}!";"#,
+            Value::String(Rc::from("12 and x!")),
+        );
+
+        // Every value is stringified with its display output
+        expect_value(
+            &mut vm,
+            r#"
+            class Point {}
+            fun f() {}
+            print "${nil} ${true} ${1.5} ${[1, "a"]} ${{"k": 2}} ${Point} ${Point()} ${f} ${clock}";
+            "#,
+            Value::String(Rc::from(
+                r#"nil true 1.5 [1, "a"] {"k": 2} <class 'Point'> <Point instance> <fun 'f'> <native fun 'clock'>"#,
+            )),
+        );
+
+        // Nested strings and braces inside the embedded expression
+        expect_value(
+            &mut vm,
+            r#"var name = "world"; print "a ${"b ${name} c"} d";"#,
+            Value::String(Rc::from("a b world c d")),
+        );
+        expect_value(
+            &mut vm,
+            r#"print "value: ${{"x": 1}["x"] + len([1, 2])}";"#,
+            Value::String(Rc::from("value: 3")),
+        );
+        expect_value(
+            &mut vm,
+            r#"
+            fun greet(n) {
+                return "Hello, ${n}!";
+            }
+            print greet("you") + " ${1 + 1}";
+            "#,
+            Value::String(Rc::from("Hello, you! 2")),
+        );
+
+        // A lone dollar sign or brace is kept as it is
+        expect_value(
+            &mut vm,
+            r#"print "$5 {x}";"#,
+            Value::String(Rc::from("$5 {x}")),
+        );
+
+        expect_interpreter_result(&mut vm, r#"print "${}";"#, InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, r#"print "${1";"#, InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, r#"print "${1}"#, InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, r#"print "${x}";"#, InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn test_escape_sequences() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"print "a\nb\tc\rd\0e";"#,
+            Value::String(Rc::from("a\nb\tc\rd\0e")),
+        );
+        expect_value(
+            &mut vm,
+            r#"print "say \"hi\" \\ ok";"#,
+            Value::String(Rc::from(r#"say "hi" \ ok"#)),
+        );
+        expect_value(
+            &mut vm,
+            r#"print "\u{1F600} \u{e9}\u{41}";"#,
+            Value::String(Rc::from("😀 éA")),
+        );
+        expect_value(&mut vm, r#"print len("\u{1F600}\n");"#, Value::Integer(2));
+
+        // Non ascii characters in the source
+        expect_value(
+            &mut vm,
+            r#"var ø = "æ"; print ø + "å";"#,
+            Value::String(Rc::from("æå")),
+        );
+
+        // Escapes in interpolated strings, an escaped dollar sign is not interpolated
+        expect_value(
+            &mut vm,
+            r#"var x = 1; print "\"${x}\"\n\${x}";"#,
+            Value::String(Rc::from("\"1\"\n${x}")),
+        );
+
+        // Invalid escapes are reported and the rest of the string is skipped
+        expect_interpreter_result(&mut vm, r#"print "\q";"#, InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, r#"print "\u{}";"#, InterpretResult::CompileError);
+        expect_interpreter_result(
+            &mut vm,
+            r#"print "\u{110000}";"#,
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(
+            &mut vm,
+            r#"print "\u{D800}";"#,
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(&mut vm, r#"print "\u41";"#, InterpretResult::CompileError);
+        expect_interpreter_result(
+            &mut vm,
+            r#"print "\u{1234567}";"#,
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(&mut vm, r#"print "abc\";"#, InterpretResult::CompileError);
+        expect_interpreter_result(
+            &mut vm,
+            r#"print "\q ${1}";"#,
+            InterpretResult::CompileError,
+        );
+    }
+
+    #[test]
+    fn test_raw_strings() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"print r"\d+\.\d* ${x} \n";"#,
+            Value::String(Rc::from(r"\d+\.\d* ${x} \n")),
+        );
+        expect_value(
+            &mut vm,
+            r##"print r#"she said "hi""#;"##,
+            Value::String(Rc::from(r#"she said "hi""#)),
+        );
+        expect_value(
+            &mut vm,
+            r###"print r##"a "# b"##;"###,
+            Value::String(Rc::from(r##"a "# b"##)),
+        );
+        expect_value(&mut vm, r#"print r"";"#, Value::String(Rc::from("")));
+        expect_value(
+            &mut vm,
+            "print r\"line 1\n  line 2\";",
+            Value::String(Rc::from("line 1\n  line 2")),
+        );
+
+        // Identifiers starting with r are still identifiers
+        expect_value(
+            &mut vm,
+            "var r = 1; var rr = r + 1; print rr;",
+            Value::Integer(2),
+        );
+
+        expect_interpreter_result(&mut vm, r#"print r"abc;"#, InterpretResult::CompileError);
+        expect_interpreter_result(
+            &mut vm,
+            r##"print r#"abc";"##,
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(&mut vm, r##"print r#abc;"##, InterpretResult::CompileError);
+    }
+
+    #[test]
+    fn test_anonymous_functions() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"
+            var add = fun (a, b) { return a + b; };
+            print add(1, 2);
+            "#,
+            Value::Integer(3),
+        );
+        expect_value(&mut vm, "print fun () { return 4; }();", Value::Integer(4));
+        expect_value(&mut vm, "fun (x) { print x; }(5);", Value::Integer(5));
+        expect_value(
+            &mut vm,
+            r#"print "${fun () {}}";"#,
+            Value::String(Rc::from("<fun 'anonymous'>")),
+        );
+        expect_value(&mut vm, "fun named() {} print named(); ", Value::Nil);
+
+        // Functions passed to other functions
+        expect_value(
+            &mut vm,
+            r#"
+            fun map(list, f) {
+                var result = [];
+                for (var i = 0; i < len(list); i = i + 1) {
+                    append(result, f(list[i]));
+                }
+                return result;
+            }
+            var doubled = map([1, 2, 3], fun (x) { return x * 2; });
+            print doubled[2];
+            "#,
+            Value::Integer(6),
+        );
+
+        // Anonymous functions capture variables like named ones
+        expect_value(
+            &mut vm,
+            r#"
+            fun counter() {
+                var count = 0;
+                return fun () {
+                    count = count + 1;
+                    return count;
+                };
+            }
+            var next = counter();
+            next();
+            print next();
+            "#,
+            Value::Integer(2),
+        );
+    }
+
+    #[test]
+    fn test_arrow_functions() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            "var double = (x) => x * 2; print double(4);",
+            Value::Integer(8),
+        );
+        expect_value(&mut vm, "print ((a, b) => a - b)(5, 3);", Value::Integer(2));
+        expect_value(&mut vm, "print (() => 7)();", Value::Integer(7));
+        expect_value(
+            &mut vm,
+            r#"
+            var f = (n) => {
+                if (n > 1) {
+                    return "big";
+                }
+                return "small";
+            };
+            print f(2) + f(0);
+            "#,
+            Value::String(Rc::from("bigsmall")),
+        );
+        expect_value(&mut vm, "print ((x) => {})(1);", Value::Nil);
+
+        // Arrows inside arrows and captured variables
+        expect_value(
+            &mut vm,
+            r#"
+            var adder = (a) => (b) => a + b;
+            print adder(2)(3);
+            "#,
+            Value::Integer(5),
+        );
+        expect_value(
+            &mut vm,
+            r#"
+            fun apply(f, x) {
+                return f(x);
+            }
+            var offset = 10;
+            print apply((x) => x + offset, 1);
+            "#,
+            Value::Integer(11),
+        );
+
+        // Plain groupings are still groupings
+        expect_value(
+            &mut vm,
+            "var x = 2; print (x) * (x + 1);",
+            Value::Integer(6),
+        );
+        expect_value(&mut vm, "print (x);", Value::Integer(2));
+
+        expect_interpreter_result(&mut vm, "var f = (1) => 2;", InterpretResult::CompileError);
+        expect_interpreter_result(
+            &mut vm,
+            "var f = (x, ) => 2;",
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(&mut vm, "var f = (x) =>;", InterpretResult::CompileError);
+        expect_interpreter_result(
+            &mut vm,
+            "var f = fun (x { };",
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(&mut vm, "break;", InterpretResult::CompileError);
+        expect_interpreter_result(
+            &mut vm,
+            "while (true) { var f = () => { break; }; }",
+            InterpretResult::CompileError,
+        );
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"try { throw "boom"; } catch (e) { print e; }"#,
+            Value::String(Rc::from("boom")),
+        );
+
+        // Built-in errors are caught as error values
+        expect_value(
+            &mut vm,
+            r#"
+            try {
+                print 1 + nil;
+            } catch (e) {
+                print e.line;
+            }
+            "#,
+            Value::Integer(3),
+        );
+        expect_value(
+            &mut vm,
+            "try { undefined_variable; } catch (e) { print e.message; }",
+            Value::String(Rc::from("Undefined variable 'undefined_variable'.")),
+        );
+        expect_value(
+            &mut vm,
+            "try { [1][5]; } catch { print \"caught\"; }",
+            Value::String(Rc::from("caught")),
+        );
+
+        // Throwing unwinds call frames and the value stack
+        expect_value(
+            &mut vm,
+            r#"
+            fun fail(n) {
+                var local = n;
+                if (n == 0) {
+                    throw {"depth": local};
+                }
+                return fail(n - 1);
+            }
+            var before = "before";
+            try {
+                var inside = 1;
+                fail(20);
+            } catch (e) {
+                var after = "after";
+                print before + after + "${e["depth"]}";
+            }
+            "#,
+            Value::String(Rc::from("beforeafter0")),
+        );
+
+        // Execution continues after the try statement
+        expect_value(
+            &mut vm,
+            r#"
+            var count = 0;
+            for (var i = 0; i < 5; i = i + 1) {
+                try {
+                    if (i % 2 == 0) {
+                        throw i;
+                    }
+                } catch (e) {
+                    count = count + 1;
+                }
+            }
+            print count;
+            "#,
+            Value::Integer(3),
+        );
+
+        // Rethrowing from a catch block reaches the outer handler
+        expect_value(
+            &mut vm,
+            r#"
+            try {
+                try { throw "a"; } catch (e) { throw e + "b"; }
+            } catch (e) {
+                print e;
+            }
+            "#,
+            Value::String(Rc::from("ab")),
+        );
+
+        // Captured variables survive the unwinding
+        expect_value(
+            &mut vm,
+            r#"
+            var saved;
+            fun capture() {
+                var secret = "kept";
+                saved = () => secret;
+                throw nil;
+            }
+            try { capture(); } catch { }
+            print saved();
+            "#,
+            Value::String(Rc::from("kept")),
+        );
+
+        expect_interpreter_result(&mut vm, "throw 1;", InterpretResult::RuntimeError);
+        expect_interpreter_result(
+            &mut vm,
+            "try { throw 1; } catch (e) { throw e; }",
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(&mut vm, "try { }", InterpretResult::CompileError);
+        expect_interpreter_result(
+            &mut vm,
+            "try { } catch (1) { }",
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(&mut vm, "throw;", InterpretResult::CompileError);
+
+        // The VM is usable after an uncaught exception
+        expect_value(&mut vm, "print 1;", Value::Integer(1));
+    }
+
+    #[test]
+    fn test_finally() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"
+            var log = "";
+            try { log = log + "t"; } finally { log = log + "f"; }
+            try { throw 1; } catch (e) { log = log + "c"; } finally { log = log + "f"; }
+            print log;
+            "#,
+            Value::String(Rc::from("tfcf")),
+        );
+
+        // The finally block runs before the exception continues to the outer handler
+        expect_value(
+            &mut vm,
+            r#"
+            var log = "";
+            try {
+                try { throw "x"; } finally { log = log + "inner "; }
+            } catch (e) {
+                log = log + "outer " + e;
+            }
+            print log;
+            "#,
+            Value::String(Rc::from("inner outer x")),
+        );
+        expect_value(
+            &mut vm,
+            r#"
+            var log = "";
+            try {
+                try { throw "x"; } catch (e) { throw "y"; } finally { log = log + "finally "; }
+            } catch (e) {
+                log = log + e;
+            }
+            print log;
+            "#,
+            Value::String(Rc::from("finally y")),
+        );
+
+        // Returning, breaking and continuing run the finally block
+        expect_value(
+            &mut vm,
+            r#"
+            var log = "";
+            fun f() {
+                try {
+                    return "returned";
+                } finally {
+                    log = log + "finally ";
+                }
+            }
+            print log + f();
+            "#,
+            Value::String(Rc::from("returned")),
+        );
+        expect_value(&mut vm, "print log;", Value::String(Rc::from("finally ")));
+        expect_value(
+            &mut vm,
+            r#"
+            var log = "";
+            for (var i = 0; i < 4; i = i + 1) {
+                try {
+                    if (i == 1) continue;
+                    if (i == 3) break;
+                    log = log + "${i}";
+                } finally {
+                    log = log + "f";
+                }
+            }
+            print log;
+            "#,
+            Value::String(Rc::from("0ff2ff")),
+        );
+        expect_value(
+            &mut vm,
+            r#"
+            fun g() {
+                try {
+                    try { return 1; } finally { log = "inner"; }
+                } finally {
+                    log = log + " outer";
+                }
+            }
+            g();
+            print log;
+            "#,
+            Value::String(Rc::from("inner outer")),
+        );
+
+        // Returning from a catch block removes its handler
+        expect_value(
+            &mut vm,
+            r#"
+            fun h() {
+                try { throw 1; } catch (e) { return e + 1; } finally { log = "h"; }
+            }
+            print h() + h();
+            "#,
+            Value::Integer(4),
+        );
+        expect_interpreter_result(&mut vm, "throw 2;", InterpretResult::RuntimeError);
+
+        expect_interpreter_result(
+            &mut vm,
+            "try { } finally { print nil + 1; }",
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(
+            &mut vm,
+            "try { } finally { var; }",
+            InterpretResult::CompileError,
+        );
+    }
+
+    #[test]
+    fn test_nested_finally() {
+        let mut vm = new_vm();
+
+        // Each level runs its finally block, which holds the next level, before leaving the loop
+        let mut nested = String::from(r#"log += "b";"#);
+        for level in 1..=10 {
+            nested = format!(
+                r#"try {{ if (i == 1) continue; if (i == 2) break; }} finally {{ log += "{}"; {} }}"#,
+                level, nested
+            );
+        }
+        expect_value(
+            &mut vm,
+            &format!(
+                r#"
+                var log = "";
+                for (var i = 0; i < 4; i++) {{ {} }}
+                print log;
+                "#,
+                nested
+            ),
+            Value::String(Rc::from("10987654321b".repeat(3))),
+        );
+
+        // Every finally block is compiled once, so the code grows with the nesting depth
+        assert!(vm.chunk.code.len() < 2000, "{}", vm.chunk.code.len());
+
+        // A return runs the finally blocks from the inside out and keeps its value
+        expect_value(
+            &mut vm,
+            r#"
+            var order = "";
+            fun f() {
+                try {
+                    try {
+                        try { return "value"; } finally { order += "1"; }
+                    } catch (e) {
+                        order += "never";
+                    } finally {
+                        order += "2";
+                    }
+                } finally {
+                    order += "3";
+                }
+            }
+            print f() + " " + order;
+            "#,
+            Value::String(Rc::from("value 123")),
+        );
+
+        // An exit inside a finally block replaces the pending one
+        expect_value(
+            &mut vm,
+            r#"
+            fun g() {
+                for (var i = 0; i < 3; i++) {
+                    try { throw "lost"; } finally { break; }
+                }
+                try { return 1; } finally { return 2; }
+            }
+            print g();
+            "#,
+            Value::Integer(2),
+        );
+    }
+
+    #[test]
+    fn test_modules() {
+        let dir = std::env::temp_dir().join(format!("blox_modules_{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        let write = |name: &str, source: &str| fs::write(dir.join(name), source).unwrap();
+        write(
+            "math.blox",
+            r#"
+            var loads = 0;
+            /// Close enough
+            var pi = 3;
+            fun double(x) { return helper(x) * 2; }
+            fun helper(x) { return x; }
+            "#,
+        );
+        write(
+            "lib/strings.blox",
+            r#"import "../math.blox"; var twice = math.double;"#,
+        );
+        write(
+            "counter.blox",
+            r#"import "math.blox"; math.loads = math.loads + 1;"#,
+        );
+        write("a.blox", r#"import "b.blox";"#);
+        write("b.blox", r#"import "a.blox";"#);
+        write("broken.blox", "var = 1;");
+        write("throws.blox", "throw \"bad\";");
+        write("my-module.blox", "var value = 7;");
+
+        let mut vm = new_vm();
+        vm.set_script_path(&dir.join("main.blox"));
+
+        expect_value(
+            &mut vm,
+            r#"import "math.blox"; print math.pi;"#,
+            Value::Integer(3),
+        );
+        expect_value(&mut vm, "print math.double(4);", Value::Integer(8));
+        expect_value(
+            &mut vm,
+            r#"print "${doc(math)}";"#,
+            Value::String(Rc::from(r#"{"pi": "Close enough"}"#)),
+        );
+        expect_value(
+            &mut vm,
+            r#"print "${math}";"#,
+            Value::String(Rc::from("<module 'math'>")),
+        );
+
+        // Paths are relative to the importing file
+        expect_value(
+            &mut vm,
+            r#"import "lib/strings.blox"; print strings.twice(5);"#,
+            Value::Integer(10),
+        );
+
+        // A module runs once and is shared by everyone importing it
+        expect_value(
+            &mut vm,
+            r#"import "math.blox"; import "counter.blox"; import counter from "counter.blox"; print math.loads;"#,
+            Value::Integer(1),
+        );
+        expect_value(
+            &mut vm,
+            r#"import util from "my-module.blox"; print util.value;"#,
+            Value::Integer(7),
+        );
+
+        // Globals of a module are only visible through the module
+        expect_interpreter_result(&mut vm, "print pi;", InterpretResult::RuntimeError);
+        expect_interpreter_result(
+            &mut vm,
+            "print math.missing;",
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(&mut vm, "math.missing();", InterpretResult::RuntimeError);
+
+        expect_interpreter_result(
+            &mut vm,
+            r#"import "a.blox";"#,
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(
+            &mut vm,
+            r#"import "missing.blox";"#,
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(
+            &mut vm,
+            r#"import "broken.blox";"#,
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(
+            &mut vm,
+            r#"import "my-module.blox";"#,
+            InterpretResult::CompileError,
+        );
+
+        // An exception in a module can be caught by the importer
+        expect_value(
+            &mut vm,
+            r#"try { import "throws.blox"; } catch (e) { print e; }"#,
+            Value::String(Rc::from("bad")),
+        );
+
+        // A cycle back to the script is found when it was started with a relative path
+        write("count.blox", "var runs = 0;");
+        write(
+            "c1.blox",
+            r#"import "count.blox"; count.runs += 1; import "c2.blox";"#,
+        );
+        write("c2.blox", r#"import "c1.blox";"#);
+        let cwd = std::env::current_dir().unwrap();
+        let up = "../".repeat(cwd.components().count() - 1);
+        let relative =
+            std::path::PathBuf::from(up).join(dir.join("c1.blox").strip_prefix("/").unwrap());
+        let mut script = new_vm();
+        script.set_script_path(&relative);
+        let source = fs::read_to_string(&relative).unwrap();
+        expect_interpreter_result(&mut script, &source, InterpretResult::RuntimeError);
+        expect_value(
+            &mut script,
+            r#"import "count.blox"; print count.runs;"#,
+            Value::Integer(1),
+        );
+
+        // Every module has its own constants, so many modules don't run out of them together
+        let globals = |prefix: &str| -> String {
+            (0..100)
+                .map(|i| format!("var {}{} = {};\n", prefix, i, i * 10))
+                .collect()
+        };
+        write("wide1.blox", &globals("a"));
+        write("wide2.blox", &globals("b"));
+        expect_value(
+            &mut vm,
+            r#"import "wide1.blox"; import "wide2.blox"; print wide1.a99 + wide2.b1;"#,
+            Value::Integer(1000),
+        );
+
+        // Within one module, names and literals are stored once
+        write("wide3.blox", &format!("{}{}", globals("a"), globals("a")));
+        expect_value(
+            &mut vm,
+            r#"import "wide3.blox"; print wide3.a50;"#,
+            Value::Integer(500),
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            "var a = 10; a += 5; a -= 3; a *= 2; a /= 4; a %= 4; print a;",
+            Value::Number(2.0),
+        );
+        expect_value(
+            &mut vm,
+            r#"var s = "a"; s += "b"; print s;"#,
+            Value::String(Rc::from("ab")),
+        );
+        expect_value(&mut vm, "var b = 1; print b += 2;", Value::Integer(3));
+        expect_value(
+            &mut vm,
+            "{ var c = 1; fun f() { c *= 3; } f(); print c; }",
+            Value::Integer(3),
+        );
+
+        // The list and index are only evaluated once
+        expect_value(
+            &mut vm,
+            r#"
+            var calls = 0;
+            var xs = [1, 2];
+            fun list() { calls += 1; return xs; }
+            list()[calls] += 10;
+            print xs[1] + calls * 100;
+            "#,
+            Value::Integer(112),
+        );
+        expect_value(
+            &mut vm,
+            r#"
+            class Point { init() { this.x = 1; } }
+            var p = Point();
+            p.x -= 3;
+            print p.x;
+            "#,
+            Value::Integer(-2),
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            "var d = 1; d + 1 += 2;",
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(
+            &mut vm,
+            "undefined_variable += 1;",
+            InterpretResult::RuntimeError,
+        );
+    }
+
+    #[test]
+    fn test_increment() {
+        let mut vm = new_vm();
+        expect_value(&mut vm, "var a = 1; print a++;", Value::Integer(1));
+        expect_value(&mut vm, "print a;", Value::Integer(2));
+        expect_value(&mut vm, "print ++a;", Value::Integer(3));
+        expect_value(&mut vm, "print a-- + --a;", Value::Integer(4));
+        expect_value(&mut vm, "print -a++;", Value::Integer(-1));
+        expect_value(
+            &mut vm,
+            "var total = 0; for (var i = 0; i < 4; i++) { total += i; } print total;",
+            Value::Integer(6),
+        );
+        expect_value(
+            &mut vm,
+            "fun f() { var n = 5; var g = fun () { return n--; }; g(); return --n; } print f();",
+            Value::Integer(3),
+        );
+
+        expect_value(
+            &mut vm,
+            "var xs = [1, 2]; var i = 0; var old = xs[i++]++; print old + xs[0] * 10 + i * 100;",
+            Value::Integer(121),
+        );
+        expect_value(&mut vm, "print ++xs[1];", Value::Integer(3));
+        expect_value(
+            &mut vm,
+            r#"
+            class Counter { init() { this.count = 0; } }
+            var c = Counter();
+            c.count++;
+            print ++c.count + c.count++;
+            "#,
+            Value::Integer(4),
+        );
+        expect_value(&mut vm, "print c.count;", Value::Integer(3));
+
+        expect_interpreter_result(&mut vm, "print --3;", InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, "++(a);", InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, "++a.b();", InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, "(a)++;", InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, "print 1 + (a)--;", InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, "a.b()++;", InterpretResult::CompileError);
+        expect_interpreter_result(&mut vm, "var s = nil; s++;", InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn test_conditional() {
+        let mut vm = new_vm();
+        expect_value(&mut vm, "print true ? 1 : 2;", Value::Integer(1));
+        expect_value(&mut vm, "print nil ? 1 : 2;", Value::Integer(2));
+        expect_value(&mut vm, "print 1 < 2 and false ? 1 : 2;", Value::Integer(2));
+
+        // Nests to the right
+        expect_value(
+            &mut vm,
+            "var n = 5; print n < 0 ? -1 : n == 0 ? 0 : 1;",
+            Value::Integer(1),
+        );
+        expect_value(
+            &mut vm,
+            "print true ? false ? 1 : 2 : 3;",
+            Value::Integer(2),
+        );
+
+        // Only the taken branch is evaluated
+        expect_value(
+            &mut vm,
+            "var calls = 0; fun f() { calls++; } var x = false ? f() : 0; print calls;",
+            Value::Integer(0),
+        );
+        expect_value(
+            &mut vm,
+            "var y; y = true ? 1 : 2; print y;",
+            Value::Integer(1),
+        );
+
+        expect_interpreter_result(&mut vm, "print true ? 1;", InterpretResult::CompileError);
+        expect_interpreter_result(
+            &mut vm,
+            "var z; true ? z : z = 1;",
+            InterpretResult::CompileError,
+        );
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut vm = new_vm();
+        expect_value(&mut vm, "print nil ?? 2;", Value::Integer(2));
+        expect_value(&mut vm, "print 1 ?? 2;", Value::Integer(1));
+        expect_value(&mut vm, "print false ?? 2;", Value::Boolean(false));
+        expect_value(&mut vm, "print nil ?? nil ?? 3;", Value::Integer(3));
+        expect_value(&mut vm, "print nil ?? false or true;", Value::Boolean(true));
+        expect_value(&mut vm, "print nil ?? 1 ? 2 : 3;", Value::Integer(2));
+
+        // The right operand is only evaluated for nil
+        expect_value(
+            &mut vm,
+            r#"
+            var calls = 0;
+            fun f() { calls++; return "default"; }
+            var options = {"name": "blox"};
+            var name = options["name"] ?? f();
+            print "${name} ${calls}";
+            "#,
+            Value::String(Rc::from("blox 0")),
+        );
+    }
+
+    #[test]
+    fn test_default_parameters() {
+        let mut vm = new_vm();
+        expect_none(
+            &mut vm,
+            "fun add(a, b = 2, c = a + b) { return a + b + c; }",
+        );
+        expect_value(&mut vm, "print add(1);", Value::Integer(6));
+        expect_value(&mut vm, "print add(1, 5);", Value::Integer(12));
+        expect_value(&mut vm, "print add(1, 5, 0);", Value::Integer(6));
+
+        // Passing nil also gives the default value
+        expect_value(&mut vm, "print add(1, nil, 1);", Value::Integer(4));
+
+        expect_value(
+            &mut vm,
+            r#"
+            class Greeter {
+                init(greeting = "Hello") { this.greeting = greeting; }
+                greet(name = "World") { return "${this.greeting}, ${name}!"; }
+            }
+            print Greeter().greet() + " " + Greeter("Hi").greet("blox");
+            "#,
+            Value::String(Rc::from("Hello, World! Hi, blox!")),
+        );
+        expect_value(
+            &mut vm,
+            "var scale = (x, by = [2][0]) => x * by; print scale(3) + scale(3, 10);",
+            Value::Integer(36),
+        );
+
+        expect_interpreter_result(&mut vm, "add();", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "add(1, 2, 3, 4);", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "fun f(a = 1, b) {}", InterpretResult::CompileError);
+    }
+
+    #[test]
+    fn test_rest_parameters() {
+        let mut vm = new_vm();
+        expect_none(
+            &mut vm,
+            "fun count(first, ...rest) { return first + len(rest); }",
+        );
+        expect_value(&mut vm, "print count(10);", Value::Integer(10));
+        expect_value(
+            &mut vm,
+            "print count(10, nil, nil, nil);",
+            Value::Integer(13),
+        );
+        expect_value(
+            &mut vm,
+            r#"
+            fun join(separator = ", ", ...parts) {
+                var text = "";
+                for (var i = 0; i < len(parts); i++) {
+                    text += (i > 0 ? separator : "") + parts[i];
+                }
+                return text;
+            }
+            print join() + join("-", "a", "b", "c");
+            "#,
+            Value::String(Rc::from("a-b-c")),
+        );
+        expect_value(
+            &mut vm,
+            "var all = (...xs) => xs; print len(all()) + len(all(1, 2));",
+            Value::Integer(2),
+        );
+
+        expect_interpreter_result(&mut vm, "count();", InterpretResult::RuntimeError);
+        expect_interpreter_result(
+            &mut vm,
+            "fun f(...rest, a) {}",
+            InterpretResult::CompileError,
+        );
+    }
+
+    #[test]
+    fn test_integers() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            "print 9007199254740993 + 1;",
+            Value::Integer(9007199254740994),
+        );
+        expect_value(&mut vm, "print 7 ~/ 2;", Value::Integer(3));
+        expect_value(&mut vm, "print -7 ~/ 2;", Value::Integer(-3));
+        expect_value(&mut vm, "print -7 % 3;", Value::Integer(-1));
+        expect_value(&mut vm, "print 7.5 ~/ 2;", Value::Number(3.0));
+        expect_value(&mut vm, "print 7 / 2;", Value::Number(3.5));
+        expect_value(&mut vm, "print 1 + 0.5;", Value::Number(1.5));
+        expect_value(&mut vm, "print 2 * 1.0;", Value::Number(2.0));
+        expect_value(
+            &mut vm,
+            r#"print "${2.0} ${2} ${1.0 / 0}";"#,
+            Value::String(Rc::from("2.0 2 inf")),
+        );
+
+        // Integers and floats with the same value are equal, also as map keys
+        expect_value(&mut vm, "print 1 == 1.0;", Value::Boolean(true));
+        expect_value(&mut vm, "print 2 > 1.5;", Value::Boolean(true));
+        expect_value(
+            &mut vm,
+            "print 9007199254740993 > 9007199254740992;",
+            Value::Boolean(true),
+        );
+        expect_value(
+            &mut vm,
+            r#"var m = {1: "one"}; print m[1.0];"#,
+            Value::String(Rc::from("one")),
+        );
+
+        // Conversions
+        expect_value(&mut vm, "print int(-2.9);", Value::Integer(-2));
+        expect_value(
+            &mut vm,
+            r#"print int("42") + int(" 1.5 ");"#,
+            Value::Integer(43),
+        );
+        expect_value(&mut vm, "print float(3);", Value::Number(3.0));
+        expect_value(
+            &mut vm,
+            r#"print num("3") + num("0.5");"#,
+            Value::Number(3.5),
+        );
+        expect_value(&mut vm, r#"print num("3");"#, Value::Integer(3));
+        expect_value(&mut vm, "var n = 1; n++; print n;", Value::Integer(2));
+
+        expect_interpreter_result(
+            &mut vm,
+            "print 9223372036854775807 + 1;",
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(
+            &mut vm,
+            "print -9223372036854775807 - 2;",
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(&mut vm, "print 1 ~/ 0;", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "print 1 % 0;", InterpretResult::RuntimeError);
+        expect_interpreter_result(
+            &mut vm,
+            "print int(1.0 / 0);",
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(&mut vm, "print [1, 2][1.0];", InterpretResult::RuntimeError);
+        expect_interpreter_result(
+            &mut vm,
+            "print 9223372036854775808;",
+            InterpretResult::CompileError,
+        );
+    }
+
+    #[test]
+    fn test_for_in() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            "var sum = 0; for (x in [1, 2, 3]) sum += x; print sum;",
+            Value::Integer(6),
+        );
+        expect_value(
+            &mut vm,
+            r#"var s = ""; for (var c in "añb") s = c + s; print s;"#,
+            Value::String(Rc::from("bña")),
+        );
+        expect_value(
+            &mut vm,
+            r#"var s = ""; for (k in {"x": 1, "y": 2}) s += k; print s;"#,
+            Value::String(Rc::from("xy")),
+        );
+        expect_value(
+            &mut vm,
+            "var sum = 0; for (i in 0..5) sum += i; print sum;",
+            Value::Integer(10),
+        );
+        expect_value(
+            &mut vm,
+            r#"var s = ""; for (i in range(10, 0, -4)) s += "${i} "; print s;"#,
+            Value::String(Rc::from("10 6 2 ")),
+        );
+        expect_value(
+            &mut vm,
+            "print len(range(0, 10, 3)) + len(5..1);",
+            Value::Integer(4),
+        );
+        expect_value(
+            &mut vm,
+            r#"var step = 3; var s = ""; for (i in 10..0 step -step) s += "${i} "; print s;"#,
+            Value::String(Rc::from("10 7 4 1 ")),
+        );
+        expect_value(&mut vm, "print len(0..10 step 2 + 1);", Value::Integer(4));
+        expect_interpreter_result(
+            &mut vm,
+            "len(range(-9223372036854775807, 9223372036854775807));",
+            InterpretResult::RuntimeError,
+        );
+        expect_value(
+            &mut vm,
+            r#"print "${1..2 + 3}";"#,
+            Value::String(Rc::from("1..5")),
+        );
+        expect_value(
+            &mut vm,
+            r#"print "${1..10 step 3} ${range(10, -2, -4)}";"#,
+            Value::String(Rc::from("1..10 step 3 10..-2 step -4")),
+        );
+
+        // break, continue and labels work like in other loops
+        expect_value(
+            &mut vm,
+            r#"
+            var pairs = 0;
+            outer: for (a in 0..10) {
+                for (b in 0..10) {
+                    if (b > a) continue outer;
+                    if (a == 4) break outer;
+                    pairs++;
+                }
+            }
+            print pairs;
+            "#,
+            Value::Integer(10),
+        );
+
+        // Every iteration has its own loop variable
+        expect_value(
+            &mut vm,
+            r#"
+            var fs = [];
+            for (i in 0..3) append(fs, () => i);
+            print fs[0]() + fs[2]();
+            "#,
+            Value::Integer(2),
+        );
+
+        expect_interpreter_result(&mut vm, "for (x in 5) {}", InterpretResult::RuntimeError);
+        expect_interpreter_result(
+            &mut vm,
+            "for (x in 0..1.5) {}",
+            InterpretResult::RuntimeError,
+        );
+        expect_interpreter_result(&mut vm, "range(0, 1, 0);", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "0..1 step 0;", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "0..1 step 0.5;", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, "for (x in [1]) }", InterpretResult::CompileError);
+    }
+
+    #[test]
+    fn test_for_in_objects() {
+        let mut vm = new_vm();
+        expect_none(
+            &mut vm,
+            r#"
+            class Countdown {
+                init(from) { this.current = from; }
+                next() {
+                    if (this.current == 0) return nil;
+                    return this.current--;
+                }
+            }
+            class Bag {
+                init() { this.items = ["a", "b"]; }
+                iterator() { return this.items; }
+            }
+            "#,
+        );
+
+        // An object with a 'next' method is iterated until it returns nil
+        expect_value(
+            &mut vm,
+            r#"var s = ""; for (n in Countdown(3)) s += n; print s;"#,
+            Value::String(Rc::from("321")),
+        );
+
+        // An object with an 'iterator' method is iterated through what it returns
+        expect_value(
+            &mut vm,
+            r#"var s = ""; for (item in Bag()) s += item; print s;"#,
+            Value::String(Rc::from("ab")),
+        );
+
+        // A function stored in a 'next' field works too
+        expect_value(
+            &mut vm,
+            r#"
+            var c = Countdown(0);
+            var stack = [1, 2];
+            c.next = fun () { return len(stack) > 0 ? pop(stack) : nil; };
+            var total = 0;
+            for (n in c) total += n;
+            print total;
+            "#,
+            Value::Integer(3),
+        );
+
+        // Exceptions thrown from 'next' leave the loop
+        expect_value(
+            &mut vm,
+            r#"
+            class Failing { next() { throw "stop"; } }
+            var result;
+            try {
+                for (x in Failing()) {}
+            } catch (e) {
+                result = e;
+            }
+            print result;
+            "#,
+            Value::String(Rc::from("stop")),
+        );
+
+        expect_interpreter_result(
+            &mut vm,
+            "class Empty {} for (x in Empty()) {}",
+            InterpretResult::RuntimeError,
+        );
+    }
+
+    #[test]
+    fn test_generators() {
+        let mut vm = new_vm();
+        expect_none(
+            &mut vm,
+            r#"
+            fun count(n) {
+                for (i in 0..n) yield i;
+                return "end";
+            }
+            fun naturals() {
+                var n = 0;
+                while (true) yield n++;
+            }
+            fun take(values, count) {
+                for (x in values) {
+                    if (count-- <= 0) return;
+                    yield x;
+                }
+            }
+            "#,
+        );
+
+        // Calling a generator function doesn't run its body
+        expect_value(
+            &mut vm,
+            "var g = count(2); print g.done;",
+            Value::Boolean(false),
+        );
+        expect_value(&mut vm, "print g.next();", Value::Integer(0));
+        expect_value(&mut vm, "print g.next();", Value::Integer(1));
+
+        // The return value comes last, after that the generator only produces nil
+        expect_value(&mut vm, "print g.next();", Value::String(Rc::from("end")));
+        expect_value(&mut vm, "print g.done;", Value::Boolean(true));
+        expect_value(&mut vm, "print g.next();", Value::Nil);
+
+        // for-in loops stop when the generator finishes, even on yielded nils
+        expect_value(
+            &mut vm,
+            r#"
+            fun maybe() { yield 1; yield nil; yield 2; }
+            var s = "";
+            for (x in maybe()) s += "${x} ";
+            print s;
+            "#,
+            Value::String(Rc::from("1 nil 2 ")),
+        );
+
+        // Generators are lazy, an endless one is fine as long as only some values are taken
+        expect_value(
+            &mut vm,
+            r#"
+            fun map(values, f) { for (x in values) yield f(x); }
+            var total = 0;
+            for (x in take(map(naturals(), (x) => x * x), 4)) total += x;
+            print total;
+            "#,
+            Value::Integer(14),
+        );
+
+        // The value passed to next is the result of the yield
+        expect_value(
+            &mut vm,
+            r#"
+            fun running_total() {
+                var total = 0;
+                while (true) total += yield total;
+            }
+            var totals = running_total();
+            totals.next(100);
+            totals.next(5);
+            print totals.next(10);
+            "#,
+            Value::Integer(15),
+        );
+
+        // Generator methods keep their instance
+        expect_value(
+            &mut vm,
+            r#"
+            class Pair {
+                init(a, b) { this.a = a; this.b = b; }
+                iterator() { yield this.a; yield this.b; }
+            }
+            var s = "";
+            for (x in Pair("x", "y")) s += x;
+            print s;
+            "#,
+            Value::String(Rc::from("xy")),
+        );
+
+        expect_interpreter_result(&mut vm, "yield 1;", InterpretResult::CompileError);
+        expect_interpreter_result(
+            &mut vm,
+            "class A { init() { yield; } }",
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(
+            &mut vm,
+            "fun again() { yield again_gen.next(); } var again_gen = again(); again_gen.next();",
+            InterpretResult::RuntimeError,
+        );
+    }
+
+    #[test]
+    fn test_generator_state() {
+        let mut vm = new_vm();
+
+        // Closures share the locals of a suspended generator
+        expect_value(
+            &mut vm,
+            r#"
+            fun counter() {
+                var c = 0;
+                yield fun () { c++; };
+                yield c;
+                yield c;
+            }
+            var g = counter();
+            var increment = g.next();
+            increment();
+            increment();
+            var first = g.next();
+            increment();
+            print "${first} ${g.next()}";
+            "#,
+            Value::String(Rc::from("2 3")),
+        );
+
+        // Try blocks stay active across a yield
+        expect_value(
+            &mut vm,
+            r#"
+            fun guarded() {
+                try {
+                    yield 1;
+                    throw "boom";
+                } catch (e) {
+                    yield "caught " + e;
+                }
+            }
+            var g = guarded();
+            g.next();
+            print g.next();
+            "#,
+            Value::String(Rc::from("caught boom")),
+        );
+
+        // An exception leaving the body finishes the generator
+        expect_value(
+            &mut vm,
+            r#"
+            fun failing() { yield 1; throw "oops"; }
+            var g = failing();
+            var error;
+            try {
+                for (x in g) {}
+            } catch (e) {
+                error = e;
+            }
+            print "${error} ${g.done}";
+            "#,
+            Value::String(Rc::from("oops true")),
+        );
+
+        // Generators run independently of each other
+        expect_value(
+            &mut vm,
+            r#"
+            fun letters() { yield "a"; yield "b"; }
+            var a = letters();
+            var b = letters();
+            print a.next() + b.next() + a.next() + b.next();
+            "#,
+            Value::String(Rc::from("aabb")),
+        );
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut vm = new_vm();
+        expect_none(
+            &mut vm,
+            r#"
+            const LIMIT = 10;
+            const OFFSET = -2.5;
+            const NAME = "blox";
+            const STARTED = clock();
+            "#,
+        );
+        expect_value(&mut vm, "print LIMIT + OFFSET;", Value::Number(7.5));
+        expect_value(&mut vm, "print NAME;", Value::String(Rc::from("blox")));
+        expect_value(
+            &mut vm,
+            "fun limit() { return LIMIT * 2; } print limit();",
+            Value::Integer(20),
+        );
+
+        // Local constants, also used from closures
+        expect_value(
+            &mut vm,
+            r#"
+            {
+                const base = 3;
+                const squared = base * base;
+                fun add(x) { return x + base + squared; }
+                print add(1);
+            }
+            "#,
+            Value::Integer(13),
+        );
+
+        // Constants can't be assigned in any way
+        for code in [
+            "LIMIT = 1;",
+            "LIMIT += 1;",
+            "LIMIT++;",
+            "--LIMIT;",
+            "STARTED = 1;",
+            "var LIMIT = 1;",
+            "fun NAME() {}",
+            "{ const a = 1; a = 2; }",
+            "{ const a = clock(); a *= 2; }",
+            "fun f() { const a = 1; return fun () { a = 2; }; }",
+            "const MISSING;",
+        ] {
+            expect_interpreter_result(&mut vm, code, InterpretResult::CompileError);
+        }
+
+        // Assignments compiled before the declaration are stopped when they run
+        expect_interpreter_result(
+            &mut vm,
+            "fun set_late() { LATE = 2; } const LATE = 1; set_late();",
+            InterpretResult::RuntimeError,
+        );
+        expect_value(&mut vm, "print LIMIT;", Value::Integer(10));
+    }
+
+    #[test]
+    fn test_type_annotations() {
+        let mut vm = new_vm();
+        expect_none(
+            &mut vm,
+            r#"
+            var count: int = 2;
+            var ratio: number = 0.5;
+            var label: string? = nil;
+            fun scale(value: number, by: int = 2): float { return value * by / 1; }
+            class Animal { init(name: string) { this.name = name; } }
+            class Dog < Animal {}
+            var pet: Animal = Dog("Rex");
+            "#,
+        );
+        expect_value(&mut vm, "print scale(count);", Value::Number(4.0));
+        expect_value(&mut vm, "print scale(ratio, 3);", Value::Number(1.5));
+        expect_value(
+            &mut vm,
+            "print label ?? pet.name;",
+            Value::String(Rc::from("Rex")),
+        );
+        expect_value(
+            &mut vm,
+            "var double = (x: int): int => x * 2; print double(count);",
+            Value::Integer(4),
+        );
+
+        // Types are inferred through locals and function signatures
+        expect_value(
+            &mut vm,
+            "{ var total = count + 1; var copy: int = total; print copy; }",
+            Value::Integer(3),
+        );
+        expect_value(
+            &mut vm,
+            "var total = count + 1; var copy: int = total; print copy;",
+            Value::Integer(3),
+        );
+
+        // Every path of a function with a return type that isn't optional returns or throws
+        expect_value(
+            &mut vm,
+            r#"
+            fun sign(x: int): string {
+                if (x < 0) return "negative"; else if (x == 0) { return "zero"; }
+                match (x) { 1 => return "one"; _ => { throw "many"; } }
+            }
+            fun first(xs: list): int {
+                for (var x in xs) return x;
+                throw "empty";
+            }
+            fun wait(): int { while (true) { try { return 1; } finally { print 2; } } }
+            fun maybe(x): int? { if (x) return 1; }
+            print sign(0) + sign(1);
+            "#,
+            Value::String(Rc::from("zeroone")),
+        );
+
+        // Mismatches are compile errors, so nothing runs
+        for code in [
+            r#"var wrong: int = "one";"#,
+            r#"count = "two";"#,
+            r#"count += "two";"#,
+            "var flag: bool = scale(1);",
+            r#"scale("one");"#,
+            "scale();",
+            "scale(1, 2, 3);",
+            "print -label;",
+            "print count + [];",
+            r#"fun name(): string { return 1; }"#,
+            "fun nothing(): int { return; }",
+            r#"var dog: Dog = Animal("Rex");"#,
+            "var unknown: Unknown = 1;",
+            r#"{ var text = "a"; var number: int = text; }"#,
+            "fun rest(...args: list) {}",
+            r#"var greeting = "hi"; var length: int = greeting;"#,
+            "fun lastly(x): int { if (x) return 1; }",
+            "fun looping(): int { while (true) { break; } }",
+            "fun caught(): int { try { return 1; } catch { print 2; } }",
+            "var arrow = (): int => { print 1; };",
+        ] {
+            expect_interpreter_result(&mut vm, code, InterpretResult::CompileError);
+        }
+        expect_value(&mut vm, "print count;", Value::Integer(2));
+
+        // Unannotated code is only checked when it runs
+        expect_interpreter_result(
+            &mut vm,
+            r#"var anything = 1; anything = "one"; print anything - 1;"#,
+            InterpretResult::RuntimeError,
+        );
+
+        // Globals assigned anywhere aren't inferred, a function can run after the assignment
+        expect_none(
+            &mut vm,
+            r#"var a = 1; fun use() { var b: string = a; } a = "x"; use();"#,
+        );
+
+        // Globals assigned another type before their declaration aren't inferred either
+        expect_value(
+            &mut vm,
+            r#"
+            fun reset() { later = "none"; }
+            var later = 1;
+            reset();
+            var text: string = later;
+            print text;
+            "#,
+            Value::String(Rc::from("none")),
+        );
+    }
+
+    #[test]
+    fn test_match() {
+        let mut vm = new_vm();
+        expect_none(
+            &mut vm,
+            r#"
+            fun describe(value) {
+                var result;
+                match (value) {
+                    1 => result = "one";
+                    "x" => {
+                        var letter = "x";
+                        result = "letter " + letter;
+                    }
+                    nil => result = "nil";
+                    -2.5 => result = "negative";
+                    true if result == nil => result = "true";
+                    _ => result = "other";
+                }
+                return result;
+            }
+            "#,
+        );
+        for (value, expected) in [
+            ("1", "one"),
+            (r#""x""#, "letter x"),
+            ("nil", "nil"),
+            ("-2.5", "negative"),
+            ("true", "true"),
+            ("false", "other"),
+            ("[]", "other"),
+        ] {
+            expect_value(
+                &mut vm,
+                &format!("print describe({});", value),
+                Value::String(Rc::from(expected)),
+            );
+        }
+
+        // The value is only evaluated once
+        expect_value(
+            &mut vm,
+            r#"
+            var calls = 0;
+            fun next() { calls++; return calls; }
+            match (next()) { 2 => print "two"; 3 => print "three"; _ => {} }
+            print calls;
+            "#,
+            Value::Integer(1),
+        );
+
+        // As an expression, inside other expressions and loops
+        expect_value(
+            &mut vm,
+            r#"
+            var sizes = "";
+            for (var i in 0..5) {
+                match (i) { 1 => continue; 4 => break; _ => {} }
+                sizes += match (i) { 0 => "zero", 2 if sizes == "zero " => "two", _ => "many" } + " ";
+            }
+            print sizes + match (match (1) { 1 => "a", _ => "b" }) { "a" => "nested", _ => "" };
+            "#,
+            Value::String(Rc::from("zero two many nested")),
+        );
+
+        for code in [
+            "match (1) { 1 => print 1; }",
+            "match (1) { _ => print 1; 2 => print 2; }",
+            "match (1) { calls => print 1; _ => print 2; }",
+            "match (1) { _ if true => print 1; }",
+            "print match (1) { 1 => 2 _ => 3 };",
+            "match 1 { _ => print 1; }",
+        ] {
+            expect_interpreter_result(&mut vm, code, InterpretResult::CompileError);
+        }
+    }
+
+    #[test]
+    fn test_binary_associativity() {
+        let mut vm = new_vm();
+        expect_value(&mut vm, "print 10 - 5 - 2;", Value::Integer(3));
+        expect_value(&mut vm, "print 10 - 3 - 2 - 1;", Value::Integer(4));
+        expect_value(&mut vm, "print 100 / 10 / 5;", Value::Number(2.0));
+        expect_value(&mut vm, "print 64 ~/ 4 ~/ 2;", Value::Integer(8));
+        expect_value(&mut vm, "print 7 % 4 * 2;", Value::Integer(6));
+        expect_value(&mut vm, "print 1 == 1 == true;", Value::Boolean(true));
+
+        // Tighter operators still bind first on either side
+        expect_value(&mut vm, "print 20 - 2 * 3 - 4;", Value::Integer(10));
+    }
+
+    #[test]
+    fn test_bitwise_operators() {
+        let mut vm = new_vm();
+        expect_value(&mut vm, "print 12 & 10;", Value::Integer(8));
+        expect_value(&mut vm, "print 12 | 10;", Value::Integer(14));
+        expect_value(&mut vm, "print 12 ^ 10;", Value::Integer(6));
+        expect_value(&mut vm, "print ~12;", Value::Integer(-13));
+        expect_value(&mut vm, "print 1 << 62;", Value::Integer(1 << 62));
+        expect_value(&mut vm, "print -16 >> 2;", Value::Integer(-4));
+
+        // Shifts bind looser than arithmetic and tighter than &, then ^ and |
+        expect_value(&mut vm, "print 1 << 2 + 1;", Value::Integer(8));
+        expect_value(&mut vm, "print 6 & 3 | 8 ^ 1;", Value::Integer(11));
+        expect_value(&mut vm, "print 5 & 4 == 4;", Value::Boolean(true));
+        expect_value(
+            &mut vm,
+            r#"
+            var checksum = 0;
+            for (var byte in [72, 105]) {
+                checksum = (checksum << 1 | checksum >> 7) & 255 ^ byte;
+            }
+            print checksum;
+            "#,
+            Value::Integer(((72 << 1) & 255) ^ 105),
+        );
+
+        for code in [
+            "print 1.0 & 1;",
+            r#"print "1" | 1;"#,
+            "print 1 ^ nil;",
+            "print ~1.5;",
+            "print 1 << 64;",
+            "print 1 >> -1;",
+        ] {
+            expect_interpreter_result(&mut vm, code, InterpretResult::RuntimeError);
+        }
+        expect_interpreter_result(
+            &mut vm,
+            "var f: float = 1.0; print f | 1;",
+            InterpretResult::CompileError,
+        );
+    }
+
+    #[test]
+    fn test_exponent() {
+        let mut vm = new_vm();
+        expect_value(&mut vm, "print 2 ** 10;", Value::Integer(1024));
+        expect_value(&mut vm, "print 2 ** 3 ** 2;", Value::Integer(512));
+        expect_value(&mut vm, "print -2 ** 2;", Value::Integer(-4));
+        expect_value(&mut vm, "print 2 ** -1;", Value::Number(0.5));
+        expect_value(&mut vm, "print 4.0 ** 0.5;", Value::Number(2.0));
+        expect_interpreter_result(&mut vm, "print 10 ** 40;", InterpretResult::RuntimeError);
+        expect_interpreter_result(&mut vm, r#"print "2" ** 2;"#, InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn test_number_literals() {
+        let mut vm = new_vm();
+        expect_value(&mut vm, "print 0xFF;", Value::Integer(255));
+        expect_value(&mut vm, "print 0Xff_00;", Value::Integer(0xff00));
+        expect_value(&mut vm, "print 0b1010;", Value::Integer(10));
+        expect_value(&mut vm, "print 0o17;", Value::Integer(15));
+        expect_value(&mut vm, "print 1_000_000;", Value::Integer(1_000_000));
+        expect_value(
+            &mut vm,
+            "print 0x7FFF_FFFF_FFFF_FFFF;",
+            Value::Integer(i64::MAX),
+        );
+        expect_value(&mut vm, "print 1e-9;", Value::Number(1e-9));
+        expect_value(&mut vm, "print 2.5E+3;", Value::Number(2500.0));
+        expect_value(&mut vm, "print 1e3;", Value::Number(1000.0));
+        expect_value(&mut vm, "print 1_0.2_5;", Value::Number(10.25));
+        expect_value(&mut vm, "print 0xF0 | 0b1111;", Value::Integer(255));
+
+        // The smallest integer is only in range negated
+        expect_value(
+            &mut vm,
+            "print -9223372036854775808;",
+            Value::Integer(i64::MIN),
+        );
+        expect_value(
+            &mut vm,
+            "print -0x8000_0000_0000_0000 == -9223372036854775807 - 1;",
+            Value::Boolean(true),
+        );
+        expect_value(
+            &mut vm,
+            "match (-9223372036854775808) { -9223372036854775808 => print 1; _ => print 2; }",
+            Value::Integer(1),
+        );
+
+        // A dot is only part of a number if digits follow it
+        expect_value(
+            &mut vm,
+            "var total = 0; for (var i in 1..0x4) total += i; print total;",
+            Value::Integer(6),
+        );
+
+        for code in [
+            "print 0b102;",
+            "print 0x;",
+            "print 0o8;",
+            "print 1e;",
+            "print 1e+;",
+            "print 1_;",
+            "print 1__0;",
+            "print 1_.5;",
+            "print 12abc;",
+            "print 9223372036854775808;",
+            "print 0x8000_0000_0000_0000;",
+            "print 1 - 9223372036854775808;",
+            "print -9223372036854775808 ** 2;",
+            "print 1e400;",
+        ] {
+            expect_interpreter_result(&mut vm, code, InterpretResult::CompileError);
+        }
+    }
+
+    #[test]
+    fn test_block_comments() {
+        let mut vm = new_vm();
+        expect_value(
+            &mut vm,
+            r#"
+            /* A block comment
+               /* can contain nested ones */
+               and spans lines */
+            print 1 /* or part of one */ + 2;
+            "#,
+            Value::Integer(3),
+        );
+        expect_value(
+            &mut vm,
+            "var text = \"/* not a comment */\"; print text;",
+            Value::String(Rc::from("/* not a comment */")),
+        );
+
+        // Lines inside comments are counted for the lines of errors
+        let res = vm.interpret(String::from("/*\n\n*/ fun f() {}\nf(1);"));
+        assert_eq!(res, InterpretResult::RuntimeError);
+        assert_eq!(vm.chunk.get_line(vm.pc - 1), 4);
+
+        expect_interpreter_result(
+            &mut vm,
+            "print 1; /* open /* nested */",
+            InterpretResult::CompileError,
+        );
+        expect_interpreter_result(&mut vm, "print 1 */ 2;", InterpretResult::CompileError);
+    }
+
+    #[test]
+    fn test_doc_comments() {
+        let mut vm = new_vm();
+        expect_none(
+            &mut vm,
+            r#"
+            /// Adds two numbers.
+            ///
+            /// Works on integers and floats.
+            fun add(a, b) { return a + b; }
+
+            ///Limit of the counter
+            const LIMIT = 10;
+
+            //// Four slashes are a plain comment
+            var plain = 1;
+
+            class Counter {
+                /// Counts one more.
+                increment() {}
+            }
+
+            /// Not attached to a declaration
+            plain = 2;
+            {
+                /// Locals only keep the doc of functions
+                fun local() {}
+                /// Dropped
+                var hidden = 1;
+            }
+            "#,
+        );
+
+        let docs: Vec<(String, String)> = vm
+            .main_module
+            .docs()
+            .iter()
+            .map(|(name, doc)| (name.to_string(), doc.to_string()))
+            .collect();
+        assert_eq!(
+            docs,
+            vec![
+                (String::from("LIMIT"), String::from("Limit of the counter")),
+                (
+                    String::from("add"),
+                    String::from("Adds two numbers.\n\nWorks on integers and floats.")
+                ),
+            ]
+        );
+
+        // Functions keep their own doc comment
+        expect_value(
+            &mut vm,
+            "print doc(add);",
+            Value::String(Rc::from(
+                "Adds two numbers.\n\nWorks on integers and floats.",
+            )),
+        );
+        expect_value(
+            &mut vm,
+            "print doc(Counter().increment);",
+            Value::String(Rc::from("Counts one more.")),
+        );
+        expect_value(&mut vm, "print doc(plain) ?? doc(len);", Value::Nil);
+
+        // Declaring a global again without a doc comment removes it
+        expect_none(&mut vm, "fun add(a, b) { return a + b; }");
+        assert_eq!(vm.main_module.docs().len(), 1);
+
+        // Variables and constants holding a function give it their doc comment
+        expect_value(
+            &mut vm,
+            r#"
+            /// Doubles a number.
+            var twice = (x) => x * 2;
+            /// Halves a number.
+            const half = fun(x) { return x / 2; };
+            /// Only the function that is the value
+            var wrapped = [fun() {}];
+            print "${doc(twice)} ${doc(half)} ${doc(wrapped[0])}";
+            "#,
+            Value::String(Rc::from("Doubles a number. Halves a number. nil")),
+        );
+    }
+
+    #[test]
+    fn test_enums() {
+        let mut vm = new_vm();
+        expect_none(&mut vm, "enum Color { Red, Green, Blue, }");
+        expect_value(
+            &mut vm,
+            "print Color.Red == Color.Red;",
+            Value::Boolean(true),
+        );
+        expect_value(
+            &mut vm,
+            "print Color.Red != Color.Blue;",
+            Value::Boolean(true),
+        );
+        expect_value(&mut vm, "print Color.Green.index;", Value::Integer(1));
+        expect_value(
+            &mut vm,
+            r#"print "${Color} ${Color.Blue} ${Color.Blue.name}";"#,
+            Value::String(Rc::from("<enum 'Color'> Color.Blue Blue")),
+        );
+
+        // Variants are iterated in order and can be map keys
+        expect_value(
+            &mut vm,
+            r#"
+            var names = {};
+            for (var color in Color) names[color] = color.name;
+            print "${len(Color)} ${names[Color.Green]} ${names}";
+            "#,
+            Value::String(Rc::from(
+                r#"3 Green {Color.Red: "Red", Color.Green: "Green", Color.Blue: "Blue"}"#,
+            )),
+        );
+
+        // A match on a known enum needs no default arm if it covers every variant
+        expect_none(
+            &mut vm,
+            r#"
+            fun temperature(color: Color) {
+                return match (color) {
+                    Color.Red => "warm",
+                    Color.Green => "neutral",
+                    Color.Blue => "cool",
+                };
+            }
+            "#,
+        );
+        expect_value(
+            &mut vm,
+            "print temperature(Color.Blue);",
+            Value::String(Rc::from("cool")),
+        );
+        expect_value(
+            &mut vm,
+            r#"
+            {
+                enum Size { Small, Large }
+                var size = Size.Large;
+                match (size) { Size.Small => print "small"; Size.Large => print "large"; }
+            }
+            "#,
+            Value::String(Rc::from("large")),
+        );
+        expect_value(
+            &mut vm,
+            r#"
+            var current = Color.Green;
+            match (current) { Color.Red => print "stop"; Color.Green => print "go"; Color.Blue => {} }
+            "#,
+            Value::String(Rc::from("go")),
+        );
+
+        // A value the compiler couldn't check doesn't match any arm
+        expect_interpreter_result(
+            &mut vm,
+            "fun unknown(x) { return x; } temperature(unknown(1));",
+            InterpretResult::RuntimeError,
+        );
+
+        // Enums declared separately are never equal
+        expect_value(
+            &mut vm,
+            "enum Other { Red } print Other.Red == Color.Red;",
+            Value::Boolean(false),
+        );
+
+        for code in [
+            "print Color.Purple;",
+            "Color = nil;",
+            "enum Twice { A, A }",
+            "enum Missing { A, B } fun f(m: Missing) { match (m) { Missing.A => print 1; } }",
+            "fun f(c: Color) { match (c) { Color.Red if true => {} Color.Green => {} Color.Blue => {} } }",
+            "fun f(c: Color) { match (c) { Other.Red => {} _ => {} } }",
+            "var c: Color = 1;",
+            "var c = Color.Red; match (c) { Color.Red => print 1; }",
+            "enum Empty {}",
+        ] {
+            expect_interpreter_result(&mut vm, code, InterpretResult::CompileError);
+        }
     }
 }
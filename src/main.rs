@@ -1,4 +1,4 @@
-use std::{fs, io::Write};
+use std::{fs, io::Write, path::Path};
 
 mod blox;
 
@@ -23,6 +23,8 @@ fn main() {
         .help("Prints disassembly per instruction")
         .arg("--frame_info")
         .help("Prints frame information per instruction")
+        .arg("--virtual_sleep")
+        .help("Skips the pause of sleep statements")
         .arg("--help")
         .help("Prints this message!");
 
@@ -48,6 +50,9 @@ fn main() {
     if parser.get("--frame_info").is_some() {
         settings.frame_info = true;
     }
+    if parser.get("--virtual_sleep").is_some() {
+        settings.virtual_sleep = true;
+    }
 
     let non_bound_args = parser.get_non_bound();
 
@@ -64,6 +69,7 @@ fn main() {
             Ok(source) => {
                 // Create the VM
                 let mut vm = blox::vm::VM::new(settings);
+                vm.set_script_path(Path::new(path));
 
                 // Interpret the source
                 vm.interpret(source);